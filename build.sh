@@ -3,4 +3,5 @@ set -e
 cd "`dirname $0`"
 source flags.sh
 cargo build --all --target wasm32-unknown-unknown --release
-cp target/wasm32-unknown-unknown/release/*.wasm ./res/contract.wasm
\ No newline at end of file
+cp target/wasm32-unknown-unknown/release/lending_project.wasm ./res/contract.wasm
+cp target/wasm32-unknown-unknown/release/mock_amm.wasm ./res/mock_amm.wasm
@@ -0,0 +1,306 @@
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, Vector};
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, log, near_bindgen, AccountId, Balance, PanicOnDefault,
+    Promise, PromiseOrValue, PromiseResult,
+};
+near_sdk::setup_alloc!();
+
+pub const ERR_NO_POOL: &str = "ERR_NO_POOL";
+pub const ERR_WRONG_TOKEN: &str = "ERR_WRONG_TOKEN";
+pub const ERR_NOT_ENOUGH_DEPOSIT: &str = "ERR_NOT_ENOUGH_DEPOSIT";
+pub const ERR_SLIPPAGE: &str = "ERR68_SLIPPAGE";
+
+#[ext_contract(ft_contract)]
+trait TFT {
+    fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>);
+}
+
+#[ext_contract(self_contract)]
+trait TSelf {
+    fn exchange_callback_post_withdraw(
+        &mut self,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    );
+}
+
+// Same fields as the swap action of Ref Finance
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+// amounts[0] of tokens[0] always swap for amounts[1] of tokens[1], and the other way around
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FixedRatePool {
+    pub tokens: Vec<AccountId>,
+    pub amounts: Vec<Balance>,
+}
+
+impl FixedRatePool {
+    pub fn get_return(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        let index_in = self
+            .tokens
+            .iter()
+            .position(|token| token == token_in)
+            .expect(ERR_WRONG_TOKEN);
+        let index_out = self
+            .tokens
+            .iter()
+            .position(|token| token == token_out)
+            .expect(ERR_WRONG_TOKEN);
+        assert_ne!(index_in, index_out, "{}", ERR_WRONG_TOKEN);
+        amount_in * self.amounts[index_out] / self.amounts[index_in]
+    }
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockAmm {
+    pub pools: Vector<FixedRatePool>,
+    // Internal balances by "account_id:token_id", like the deposits of Ref Finance
+    pub deposits: LookupMap<String, Balance>,
+}
+
+#[near_bindgen]
+impl MockAmm {
+    #[init]
+    pub fn new() -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        // Prefixes differ from the lending contract so both can share the mocked storage in tests
+        Self {
+            pools: Vector::new(b"amm_pools".to_vec()),
+            deposits: LookupMap::new(b"amm_deposits".to_vec()),
+        }
+    }
+
+    // The contract must hold enough of both tokens to pay out the swaps
+    pub fn add_pool(&mut self, tokens: Vec<ValidAccountId>, amounts: Vec<U128>) -> u64 {
+        assert!(
+            tokens.len() == 2 && amounts.len() == 2,
+            "{}",
+            ERR_WRONG_TOKEN
+        );
+        self.pools.push(&FixedRatePool {
+            tokens: tokens.into_iter().map(|token| token.into()).collect(),
+            amounts: amounts.into_iter().map(|amount| amount.into()).collect(),
+        });
+        self.pools.len() - 1
+    }
+
+    // Swap deposited tokens, the output stays in the deposits of the caller
+    #[payable]
+    pub fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<ValidAccountId>) -> U128 {
+        let _ = referral_id;
+        let account_id = env::predecessor_account_id();
+        let mut amount_out = 0;
+        for action in actions.iter() {
+            let pool = self.pools.get(action.pool_id).expect(ERR_NO_POOL);
+            let amount_in = action.amount_in.map_or(amount_out, Balance::from);
+            amount_out = pool.get_return(&action.token_in, amount_in, &action.token_out);
+            assert!(
+                amount_out >= action.min_amount_out.into(),
+                "{}",
+                ERR_SLIPPAGE
+            );
+            self.internal_withdraw(&account_id, &action.token_in, amount_in);
+            self.internal_deposit(&account_id, &action.token_out, amount_out);
+            log!(
+                "Swapped {} {} for {} {}",
+                amount_in,
+                action.token_in,
+                amount_out,
+                action.token_out
+            );
+        }
+        U128::from(amount_out)
+    }
+
+    #[payable]
+    pub fn withdraw(
+        &mut self,
+        token_id: ValidAccountId,
+        amount: U128,
+        unregister: Option<bool>,
+    ) -> Promise {
+        assert_one_yocto();
+        let _ = unregister;
+        let account_id = env::predecessor_account_id();
+        self.internal_withdraw(&account_id, token_id.as_ref(), amount.into());
+        ft_contract::ft_transfer(
+            ValidAccountId::try_from(account_id.clone()).unwrap(),
+            amount,
+            None,
+            token_id.as_ref(),
+            1,
+            10_000_000_000_000,
+        )
+        .then(self_contract::exchange_callback_post_withdraw(
+            token_id.into(),
+            account_id,
+            amount,
+            &env::current_account_id(),
+            0,
+            10_000_000_000_000,
+        ))
+    }
+
+    // Like Ref Finance, a failed transfer is credited back to the deposits
+    #[private]
+    pub fn exchange_callback_post_withdraw(
+        &mut self,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    ) {
+        if let PromiseResult::Failed = env::promise_result(0) {
+            self.internal_deposit(&sender_id, &token_id, amount.into());
+        }
+    }
+
+    pub fn get_deposit(&self, account_id: ValidAccountId, token_id: ValidAccountId) -> U128 {
+        U128::from(
+            self.deposits
+                .get(&deposit_key(account_id.as_ref(), token_id.as_ref()))
+                .unwrap_or(0),
+        )
+    }
+
+    pub fn get_return(
+        &self,
+        pool_id: u64,
+        token_in: ValidAccountId,
+        amount_in: U128,
+        token_out: ValidAccountId,
+    ) -> U128 {
+        let pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        U128::from(pool.get_return(token_in.as_ref(), amount_in.into(), token_out.as_ref()))
+    }
+}
+
+impl MockAmm {
+    fn internal_deposit(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let key = deposit_key(account_id, token_id);
+        let balance = self.deposits.get(&key).unwrap_or(0);
+        self.deposits.insert(&key, &(balance + amount));
+    }
+
+    fn internal_withdraw(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let key = deposit_key(account_id, token_id);
+        let balance = self.deposits.get(&key).unwrap_or(0);
+        assert!(balance >= amount, "{}", ERR_NOT_ENOUGH_DEPOSIT);
+        self.deposits.insert(&key, &(balance - amount));
+    }
+}
+
+fn deposit_key(account_id: &AccountId, token_id: &AccountId) -> String {
+    format!("{}:{}", account_id, token_id)
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for MockAmm {
+    // Tokens sent with an empty message are added to the deposits of the sender
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        assert!(msg.is_empty(), "Instant swaps are not supported");
+        self.internal_deposit(
+            sender_id.as_ref(),
+            &env::predecessor_account_id(),
+            amount.into(),
+        );
+        PromiseOrValue::Value(U128::from(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+    use near_sdk::MockedBlockchain;
+
+    fn account(account_id: &str) -> ValidAccountId {
+        ValidAccountId::try_from(account_id).unwrap()
+    }
+
+    fn setup() -> MockAmm {
+        testing_env!(VMContextBuilder::new().build());
+        let mut amm = MockAmm::new();
+        // 1 wNEAR for 5 USDC
+        amm.add_pool(
+            vec![account("wnear"), account("usdc")],
+            vec![U128::from(10u128.pow(24)), U128::from(5_000_000)],
+        );
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(account("wnear"))
+            .build());
+        amm.ft_on_transfer(
+            account("lending"),
+            U128::from(2 * 10u128.pow(24)),
+            "".to_string(),
+        );
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(account("lending"))
+            .build());
+        amm
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut amm = setup();
+        let amount_out = amm.swap(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: "wnear".to_string(),
+                amount_in: Some(U128::from(2 * 10u128.pow(24))),
+                token_out: "usdc".to_string(),
+                min_amount_out: U128::from(10_000_000),
+            }],
+            None,
+        );
+        assert_eq!(amount_out, U128::from(10_000_000));
+        assert_eq!(
+            amm.get_deposit(account("lending"), account("wnear")),
+            U128::from(0)
+        );
+        assert_eq!(
+            amm.get_deposit(account("lending"), account("usdc")),
+            U128::from(10_000_000)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR68_SLIPPAGE")]
+    fn test_swap_slippage() {
+        let mut amm = setup();
+        amm.swap(
+            vec![SwapAction {
+                pool_id: 0,
+                token_in: "wnear".to_string(),
+                amount_in: Some(U128::from(2 * 10u128.pow(24))),
+                token_out: "usdc".to_string(),
+                min_amount_out: U128::from(10_000_001),
+            }],
+            None,
+        );
+    }
+}
@@ -0,0 +1,487 @@
+use crate::utils::{
+    ERR_BELOW_MIN_BORROW, ERR_BORROW_CAP_EXCEEDED, ERR_DUPLICATE_ACTION, ERR_NO_ACTIONS,
+    ERR_REPAY_NEEDS_TRANSFER,
+};
+use crate::*;
+
+// Action run by execute, positions are checked once after all actions are applied
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Action {
+    Borrow { pool_id: u64, amount: U128 },
+    Withdraw { pool_id: u64, amount: U128 },
+    // Only in an Execute transfer, paid from the transferred tokens (all of them without amount)
+    Repay { pool_id: u64, amount: Option<U128> },
+    Claim { pool_id: u64 },
+    EnableCollateral { pool_id: u64 },
+    DisableCollateral { pool_id: u64 },
+}
+
+impl Action {
+    pub fn pool_id(&self) -> u64 {
+        match self {
+            Action::Borrow { pool_id, .. }
+            | Action::Withdraw { pool_id, .. }
+            | Action::Repay { pool_id, .. }
+            | Action::Claim { pool_id }
+            | Action::EnableCollateral { pool_id }
+            | Action::DisableCollateral { pool_id } => *pool_id,
+        }
+    }
+
+    fn action_type(&self) -> Option<ActionType> {
+        match self {
+            Action::Borrow { .. } => Some(ActionType::Borrow),
+            Action::Withdraw { .. } => Some(ActionType::Withdraw),
+            Action::Repay { .. } => Some(ActionType::Repay),
+            Action::Claim { .. } => Some(ActionType::Claim),
+            Action::EnableCollateral { .. } | Action::DisableCollateral { .. } => None,
+        }
+    }
+}
+
+#[near_bindgen]
+impl LendingContract {
+    // Run several actions with one price fetch and a single borrow limit check at the end
+    #[payable]
+    pub fn execute(&mut self, actions: Vec<Action>) -> Promise {
+        assert_one_yocto();
+        self.internal_execute(env::predecessor_account_id(), actions)
+    }
+
+    // Unused amount of an Execute transfer, whatever the result of its actions
+    #[private]
+    pub fn resolve_execute(&mut self, refund: U128) -> U128 {
+        refund
+    }
+
+    #[private]
+    pub fn execute_callback(
+        &mut self,
+        account_id: AccountId,
+        actions: Vec<Action>,
+        loans: Vec<Loan>,
+        deposits: Vec<LenderInfo>,
+    ) {
+        let (pool_tokens, borrow_tokens) = self.get_action_tokens(&actions);
+        let tokens = LendingContract::get_price_tokens(&loans, &deposits, &pool_tokens);
+        let prices = LendingContract::process_prices(&tokens);
+        for i in 0..borrow_tokens.len() {
+            LendingContract::assert_storage_registered(env::promise_result(
+                (tokens.len() + i) as u64,
+            ));
+        }
+
+        // Apply the actions to the positions before checking the borrow limit
+        let mut loans = loans;
+        let mut deposits = deposits;
+        let mut borrowed: HashMap<u64, Balance> = HashMap::new();
+        for action in actions.iter() {
+            let pool = self.pools.get(action.pool_id()).expect(ERR_NO_POOL);
+            match action {
+                Action::Borrow { amount, .. } => {
+                    let total_borrowed = borrowed.entry(pool.pool_id).or_insert(0);
+                    *total_borrowed += Balance::from(*amount);
+                    assert!(
+                        *total_borrowed <= pool.pool_supply,
+                        "Dont enough token to borrow from pool"
+                    );
+                    if let Some(remaining_borrow_capacity) = pool.remaining_borrow_capacity() {
+                        assert!(
+                            *total_borrowed <= remaining_borrow_capacity,
+                            "{}",
+                            ERR_BORROW_CAP_EXCEEDED
+                        );
+                    }
+                    assert!(
+                        pool.get_debt(&account_id) + *total_borrowed >= pool.min_borrow,
+                        "{}",
+                        ERR_BELOW_MIN_BORROW
+                    );
+                    pool.get_loan_term(&account_id, None);
+                    loans.push(pool.new_loan(&account_id, (*amount).into()));
+                }
+                Action::Withdraw { amount, .. } => {
+                    let amount = pool.withdraw_amount(&account_id, (*amount).into());
+                    let deposit = LendingContract::find_deposit(&mut deposits, &pool);
+                    deposit.share -= amount + pool.amount_claimable(&account_id);
+                }
+                Action::Claim { .. } => {
+                    let deposit = LendingContract::find_deposit(&mut deposits, &pool);
+                    deposit.share -= pool.amount_claimable(&account_id);
+                }
+                Action::EnableCollateral { .. } => {
+                    LendingContract::find_deposit(&mut deposits, &pool).is_collateral = true;
+                }
+                Action::DisableCollateral { .. } => {
+                    LendingContract::find_deposit(&mut deposits, &pool).is_collateral = false;
+                }
+                Action::Repay { .. } => env::panic(ERR_REPAY_NEEDS_TRANSFER.as_bytes()),
+            }
+        }
+        self.assert_borrow_limit(&account_id, &loans, &deposits, &prices);
+
+        // Each loan takes its part of the debt ceiling of an isolated collateral before it is sent
+        for action in actions.into_iter() {
+            let mut pool = self.pools.get(action.pool_id()).expect(ERR_NO_POOL);
+            match action {
+                Action::Borrow { amount, .. } => {
+                    log!(
+                        "{} borrow {} token from pool {}",
+                        account_id,
+                        Balance::from(amount),
+                        pool.pool_id
+                    );
+                    let mut loan = pool.new_loan(&account_id, amount.into());
+                    loan.rate_mode = pool.get_rate_mode(&account_id, None);
+                    self.assert_debt_ceiling(
+                        &deposits,
+                        self.get_isolated_debt(&pool.lending_token, loan.amount),
+                    );
+                    pool.reserve_loan(loan.amount);
+                    self.pools.replace(pool.pool_id, &pool);
+                    self.add_isolated_debt(&deposits, &pool.lending_token, loan.amount);
+                    self.transfer_loan(&pool, account_id.clone(), loan);
+                }
+                Action::Withdraw { amount, .. } => {
+                    let amount = pool.withdraw_amount(&account_id, amount.into());
+                    log!(
+                        "{} withdraw {} token with interest {}",
+                        account_id,
+                        amount,
+                        pool.amount_claimable(&account_id)
+                    );
+                    self.transfer_withdrawal(&pool, account_id.clone(), amount);
+                }
+                Action::Claim { .. } => {
+                    log!(
+                        "{} claim {} token",
+                        account_id,
+                        pool.amount_claimable(&account_id)
+                    );
+                    self.transfer_claim(&pool, account_id.clone());
+                }
+                Action::EnableCollateral { .. } | Action::DisableCollateral { .. } => {
+                    let is_collateral = matches!(action, Action::EnableCollateral { .. });
+                    log!(
+                        "{} use deposit in pool {} as collateral: {}",
+                        account_id,
+                        pool.pool_id,
+                        is_collateral
+                    );
+                    pool.set_collateral(&account_id, is_collateral);
+                    self.pools.replace(pool.pool_id, &pool);
+                }
+                Action::Repay { .. } => {}
+            }
+        }
+    }
+}
+
+impl LendingContract {
+    // Fetch the prices of every position and action, and check the storage of borrowers
+    pub(crate) fn internal_execute(
+        &mut self,
+        account_id: AccountId,
+        actions: Vec<Action>,
+    ) -> Promise {
+        assert!(!actions.is_empty(), "{}", ERR_NO_ACTIONS);
+        let mut transfer_pools: Vec<u64> = vec![];
+        let mut borrow_pools: Vec<u64> = vec![];
+        for action in actions.iter() {
+            let pool = self.pools.get(action.pool_id()).expect(ERR_NO_POOL);
+            if let Some(action_type) = action.action_type() {
+                self.assert_not_paused(&pool, action_type);
+            }
+            match action {
+                Action::Repay { .. } => env::panic(ERR_REPAY_NEEDS_TRANSFER.as_bytes()),
+                // A borrower has one loan transfer pending per pool
+                Action::Borrow { .. } => {
+                    assert!(
+                        !borrow_pools.contains(&pool.pool_id),
+                        "{}",
+                        ERR_DUPLICATE_ACTION
+                    );
+                    self.assert_no_pending_borrow(&account_id, pool.pool_id);
+                    borrow_pools.push(pool.pool_id);
+                }
+                // Withdraw and claim both pay out the interest of the pool
+                Action::Withdraw { .. } | Action::Claim { .. } => {
+                    assert!(
+                        !transfer_pools.contains(&pool.pool_id),
+                        "{}",
+                        ERR_DUPLICATE_ACTION
+                    );
+                    transfer_pools.push(pool.pool_id);
+                }
+                _ => {}
+            }
+        }
+        let loans = self.get_all_loans(&account_id);
+        let deposits = self.get_all_deposits(&account_id);
+        let (pool_tokens, borrow_tokens) = self.get_action_tokens(&actions);
+        let tokens = LendingContract::get_price_tokens(&loans, &deposits, &pool_tokens);
+        let gas = 20_000_000_000_000 + 30_000_000_000_000 * actions.len() as u64;
+        borrow_tokens
+            .iter()
+            .fold(self.fetch_prices(&tokens), |promises, token| {
+                promises.and(ft_contract::storage_balance_of(
+                    ValidAccountId::try_from(account_id.clone()).unwrap(),
+                    token,
+                    0,
+                    5_000_000_000_000,
+                ))
+            })
+            .then(self_contract::execute_callback(
+                account_id,
+                actions,
+                loans,
+                deposits,
+                &env::current_account_id(),
+                0,
+                gas,
+            ))
+    }
+
+    // Lending tokens of the pools used by the actions, and of the pools borrowed from
+    fn get_action_tokens(&self, actions: &[Action]) -> (Vec<AccountId>, Vec<AccountId>) {
+        let mut pool_tokens = vec![];
+        let mut borrow_tokens = vec![];
+        for action in actions.iter() {
+            let pool = self.pools.get(action.pool_id()).expect(ERR_NO_POOL);
+            if let Action::Borrow { .. } = action {
+                borrow_tokens.push(pool.lending_token.clone());
+            }
+            pool_tokens.push(pool.lending_token);
+        }
+        (pool_tokens, borrow_tokens)
+    }
+
+    pub(crate) fn find_deposit<'a>(
+        deposits: &'a mut [LenderInfo],
+        pool: &LendingPool,
+    ) -> &'a mut LenderInfo {
+        deposits
+            .iter_mut()
+            .find(|deposit| deposit.lending_token == pool.lending_token)
+            .expect("ERR_NO_LENDER")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{self, account, add_pool, price, set_context, storage};
+    use crate::*;
+
+    // alice owes 100 USDC of pool 0 against 10 wNEAR deposited in pool 1
+    fn new_contract() -> LendingContract {
+        let mut contract = test_utils::new_contract();
+        let mut usdc = add_pool(&mut contract, "usdc", 6);
+        usdc.deposit("lender".to_string(), 1_000_000_000);
+        usdc.borrow(&"alice".to_string(), 100_000_000, None);
+        contract.pools.replace(0, &usdc);
+        let mut wnear = add_pool(&mut contract, "wnear", 24);
+        wnear.deposit("alice".to_string(), 10 * 10u128.pow(24));
+        contract.pools.replace(1, &wnear);
+        contract
+    }
+
+    fn execute_transfer(
+        contract: &mut LendingContract,
+        amount: Balance,
+        actions: &str,
+    ) -> PromiseOrValue<U128> {
+        set_context("usdc", vec![]);
+        contract.ft_on_transfer(
+            account("alice"),
+            U128::from(amount),
+            format!(
+                "{{\"transfer_type\": \"Execute\", \"token\": \"usdc\", \"pool_id\": 0, \"actions\": {}}}",
+                actions
+            ),
+        )
+    }
+
+    fn refund(result: PromiseOrValue<U128>) -> Balance {
+        match result {
+            PromiseOrValue::Value(refund) => refund.into(),
+            PromiseOrValue::Promise(_) => panic!("expected a value"),
+        }
+    }
+
+    // The repay is paid first and the rest of the transfer is deposited
+    #[test]
+    fn test_execute_transfer() {
+        let mut contract = new_contract();
+        let result = execute_transfer(
+            &mut contract,
+            150_000_000,
+            "[{\"Repay\": {\"pool_id\": 0, \"amount\": \"100000000\"}}]",
+        );
+        assert_eq!(refund(result), 0);
+        let usdc = contract.pools.get(0).unwrap();
+        assert_eq!(usdc.get_debt(&"alice".to_string()), 0);
+        assert_eq!(usdc.get_deposit(&"alice".to_string()), 50_000_000);
+    }
+
+    // Paused deposits do not block a repay, what is left of it is refunded
+    #[test]
+    fn test_execute_transfer_deposit_paused() {
+        let mut contract = new_contract();
+        contract.paused_actions.push(ActionType::Deposit);
+        let result = execute_transfer(
+            &mut contract,
+            150_000_000,
+            "[{\"Repay\": {\"pool_id\": 0, \"amount\": \"100000000\"}}]",
+        );
+        assert_eq!(refund(result), 50_000_000);
+        let usdc = contract.pools.get(0).unwrap();
+        assert_eq!(usdc.get_debt(&"alice".to_string()), 0);
+        assert_eq!(usdc.get_deposit(&"alice".to_string()), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ACTION_PAUSED")]
+    fn test_execute_transfer_deposit_only_paused() {
+        let mut contract = new_contract();
+        contract.paused_actions.push(ActionType::Deposit);
+        execute_transfer(&mut contract, 50_000_000, "[]");
+    }
+
+    // The other actions run after the transfer, the refund does not depend on their result
+    #[test]
+    fn test_execute_transfer_actions() {
+        let mut contract = new_contract();
+        let result = execute_transfer(
+            &mut contract,
+            100_000_000,
+            "[{\"Repay\": {\"pool_id\": 0, \"amount\": null}}, {\"DisableCollateral\": {\"pool_id\": 1}}]",
+        );
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert_eq!(contract.resolve_execute(U128::from(0)), U128::from(0));
+    }
+
+    #[test]
+    fn test_execute_callback() {
+        let mut contract = new_contract();
+        let alice = "alice".to_string();
+        let mut usdc = contract.pools.get(0).unwrap();
+        usdc.repay(alice.clone(), 100_000_000);
+        contract.pools.replace(0, &usdc);
+        let loans = contract.get_all_loans(&alice);
+        let deposits = contract.get_all_deposits(&alice);
+
+        // 20 USDC fits under half of 10 wNEAR at $5
+        set_context("lending", vec![price("5"), price("1"), storage(true)]);
+        contract.execute_callback(
+            alice.clone(),
+            vec![Action::Borrow {
+                pool_id: 0,
+                amount: U128::from(20_000_000),
+            }],
+            loans,
+            deposits.clone(),
+        );
+
+        set_context("lending", vec![price("5")]);
+        contract.execute_callback(
+            alice.clone(),
+            vec![Action::DisableCollateral { pool_id: 1 }],
+            vec![],
+            deposits,
+        );
+        assert!(
+            !contract
+                .pools
+                .get(1)
+                .unwrap()
+                .lenders
+                .get(&alice)
+                .unwrap()
+                .is_collateral
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BORROW_VALUE_LIMITED")]
+    fn test_execute_borrow_limit() {
+        let mut contract = new_contract();
+        let alice = "alice".to_string();
+        let loans = contract.get_all_loans(&alice);
+        let deposits = contract.get_all_deposits(&alice);
+        set_context("lending", vec![price("1"), price("5"), storage(true)]);
+        contract.execute_callback(
+            alice,
+            vec![
+                Action::Borrow {
+                    pool_id: 0,
+                    amount: U128::from(20_000_000),
+                },
+                Action::DisableCollateral { pool_id: 1 },
+            ],
+            loans,
+            deposits,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_REGISTERED")]
+    fn test_execute_borrow_not_registered() {
+        let mut contract = new_contract();
+        let alice = "alice".to_string();
+        let loans = contract.get_all_loans(&alice);
+        let deposits = contract.get_all_deposits(&alice);
+        set_context("lending", vec![price("1"), price("5"), storage(false)]);
+        contract.execute_callback(
+            alice,
+            vec![Action::Borrow {
+                pool_id: 0,
+                amount: U128::from(1_000_000),
+            }],
+            loans,
+            deposits,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DUPLICATE_ACTION")]
+    fn test_execute_duplicate_withdraw() {
+        let mut contract = new_contract();
+        set_context("alice", vec![]);
+        contract.execute(vec![
+            Action::Withdraw {
+                pool_id: 1,
+                amount: U128::from(1),
+            },
+            Action::Claim { pool_id: 1 },
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DUPLICATE_ACTION")]
+    fn test_execute_duplicate_borrow() {
+        let mut contract = new_contract();
+        set_context("alice", vec![]);
+        contract.execute(vec![
+            Action::Borrow {
+                pool_id: 0,
+                amount: U128::from(1),
+            },
+            Action::Borrow {
+                pool_id: 0,
+                amount: U128::from(1),
+            },
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_REPAY_NEEDS_TRANSFER")]
+    fn test_execute_repay() {
+        let mut contract = new_contract();
+        set_context("alice", vec![]);
+        contract.execute(vec![Action::Repay {
+            pool_id: 0,
+            amount: None,
+        }]);
+    }
+}
@@ -0,0 +1,277 @@
+use crate::utils::{
+    SwapAction, ERR_NO_DEX, ERR_POSITION_WORSE, ERR_SAME_POOL, ERR_WRONG_SWAP_PURPOSE,
+};
+use crate::*;
+
+#[near_bindgen]
+impl LendingContract {
+    // Move a deposit to another pool without closing the loans: withdraw amount with its
+    // interest, swap it on the dex and deposit the output, with one borrow limit check.
+    // The swap must return at least the oracle value minus the maximum slippage
+    #[payable]
+    pub fn swap_collateral(
+        &mut self,
+        from_pool_id: u64,
+        to_pool_id: u64,
+        amount: U128,
+        min_amount_out: Option<U128>,
+    ) -> Promise {
+        assert_one_yocto();
+        assert!(self.dex.is_some(), "{}", ERR_NO_DEX);
+        assert_ne!(from_pool_id, to_pool_id, "{}", ERR_SAME_POOL);
+        let from_pool = self.pools.get(from_pool_id).expect(ERR_NO_POOL);
+        let to_pool = self.pools.get(to_pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(&from_pool, ActionType::Withdraw);
+        self.assert_not_paused(&to_pool, ActionType::Deposit);
+        self.get_dex_pool_id(&from_pool.lending_token, &to_pool.lending_token);
+        let account_id = env::predecessor_account_id();
+        from_pool.withdraw_amount(&account_id, amount.into());
+        let loans = self.get_all_loans(&account_id);
+        let deposits = self.get_all_deposits(&account_id);
+        let tokens = LendingContract::get_price_tokens(
+            &loans,
+            &deposits,
+            &[from_pool.lending_token, to_pool.lending_token],
+        );
+        self.fetch_prices(&tokens)
+            .then(self_contract::swap_collateral_callback(
+                account_id,
+                amount,
+                min_amount_out,
+                SwapArgs {
+                    purpose: SwapPurpose::CollateralSwap {
+                        from_pool_id,
+                        to_pool_id,
+                    },
+                    loans,
+                    deposits,
+                },
+                &env::current_account_id(),
+                0,
+                200_000_000_000_000,
+            ))
+    }
+
+    #[private]
+    pub fn swap_collateral_callback(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        min_amount_out: Option<U128>,
+        args: SwapArgs,
+    ) -> Promise {
+        let SwapArgs {
+            purpose,
+            loans,
+            deposits,
+        } = args;
+        let (from_pool_id, to_pool_id) = match purpose {
+            SwapPurpose::CollateralSwap {
+                from_pool_id,
+                to_pool_id,
+            } => (from_pool_id, to_pool_id),
+            _ => env::panic(ERR_WRONG_SWAP_PURPOSE.as_bytes()),
+        };
+        let mut from_pool = self.pools.get(from_pool_id).expect(ERR_NO_POOL);
+        let to_pool = self.pools.get(to_pool_id).expect(ERR_NO_POOL);
+        let tokens = LendingContract::get_price_tokens(
+            &loans,
+            &deposits,
+            &[
+                from_pool.lending_token.clone(),
+                to_pool.lending_token.clone(),
+            ],
+        );
+        let prices = LendingContract::process_prices(&tokens);
+
+        // The interest of the deposit is swapped with it
+        let amount = from_pool.withdraw_amount(&account_id, amount.into());
+        let interest = from_pool.amount_claimable(&account_id);
+        let amount_in = amount + interest;
+        let min_amount_out = self.get_min_amount_out(
+            &from_pool.lending_token,
+            &to_pool.lending_token,
+            amount_in,
+            &prices,
+            min_amount_out,
+        );
+        if let Some(remaining_supply_capacity) = to_pool.remaining_supply_capacity() {
+            assert!(
+                min_amount_out <= remaining_supply_capacity,
+                "{}",
+                ERR_SUPPLY_CAP_EXCEEDED
+            );
+        }
+        assert!(
+            to_pool.get_deposit(&account_id) + min_amount_out >= to_pool.min_deposit,
+            "{}",
+            ERR_BELOW_MIN_DEPOSIT
+        );
+
+        // The position is checked with the smallest output the swap accepts
+        let mut deposits = deposits;
+        LendingContract::find_deposit(&mut deposits, &from_pool).share -= amount_in;
+        if !deposits
+            .iter()
+            .any(|deposit| deposit.lending_token == to_pool.lending_token)
+        {
+            deposits.push(LenderInfo {
+                lending_token: to_pool.lending_token.clone(),
+                share: 0,
+                reward_debt: 0,
+                acc_reward: 0,
+                is_collateral: true,
+                auto_compound: false,
+            });
+        }
+        LendingContract::find_deposit(&mut deposits, &to_pool).share += min_amount_out;
+        self.assert_borrow_limit(&account_id, &loans, &deposits, &prices);
+
+        log!(
+            "{} swap {} token with interest {} from pool {} for at least {} token of pool {}",
+            account_id,
+            amount,
+            interest,
+            from_pool_id,
+            min_amount_out,
+            to_pool_id
+        );
+        from_pool.withdraw(account_id.clone(), amount, interest);
+        self.pools.replace(from_pool_id, &from_pool);
+        let dex_pool_id = self.get_dex_pool_id(&from_pool.lending_token, &to_pool.lending_token);
+        self.internal_swap(
+            account_id,
+            SwapAction {
+                pool_id: dex_pool_id,
+                token_in: from_pool.lending_token,
+                amount_in: Some(U128::from(amount_in)),
+                token_out: to_pool.lending_token,
+                min_amount_out: U128::from(min_amount_out),
+            },
+            purpose,
+        )
+    }
+
+    // Repay a loan by selling part of a deposit on the dex, without liquidation penalty.
+    // amount is withdrawn with its interest and the swap must return at least min_amount_out,
+    // any output above the debt is sent back
+    #[payable]
+    pub fn repay_with_collateral(
+        &mut self,
+        collateral_pool_id: u64,
+        repay_pool_id: u64,
+        amount: U128,
+        min_amount_out: U128,
+    ) -> Promise {
+        assert_one_yocto();
+        assert!(self.dex.is_some(), "{}", ERR_NO_DEX);
+        assert_ne!(collateral_pool_id, repay_pool_id, "{}", ERR_SAME_POOL);
+        let collateral_pool = self.pools.get(collateral_pool_id).expect(ERR_NO_POOL);
+        let repay_pool = self.pools.get(repay_pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(&collateral_pool, ActionType::Withdraw);
+        self.assert_not_paused(&repay_pool, ActionType::Repay);
+        self.get_dex_pool_id(&collateral_pool.lending_token, &repay_pool.lending_token);
+        let account_id = env::predecessor_account_id();
+        assert!(repay_pool.get_debt(&account_id) > 0, "{}", ERR_NO_BORROWER);
+        collateral_pool.withdraw_amount(&account_id, amount.into());
+        let loans = self.get_all_loans(&account_id);
+        let deposits = self.get_all_deposits(&account_id);
+        let tokens = LendingContract::get_price_tokens(&loans, &deposits, &[]);
+        self.fetch_prices(&tokens)
+            .then(self_contract::repay_with_collateral_callback(
+                account_id,
+                amount,
+                min_amount_out,
+                SwapArgs {
+                    purpose: SwapPurpose::Repay {
+                        collateral_pool_id,
+                        repay_pool_id,
+                    },
+                    loans,
+                    deposits,
+                },
+                &env::current_account_id(),
+                0,
+                200_000_000_000_000,
+            ))
+    }
+
+    #[private]
+    pub fn repay_with_collateral_callback(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        min_amount_out: U128,
+        args: SwapArgs,
+    ) -> Promise {
+        let SwapArgs {
+            purpose,
+            loans,
+            deposits,
+        } = args;
+        let (collateral_pool_id, repay_pool_id) = match purpose {
+            SwapPurpose::Repay {
+                collateral_pool_id,
+                repay_pool_id,
+            } => (collateral_pool_id, repay_pool_id),
+            _ => env::panic(ERR_WRONG_SWAP_PURPOSE.as_bytes()),
+        };
+        let mut collateral_pool = self.pools.get(collateral_pool_id).expect(ERR_NO_POOL);
+        let repay_pool = self.pools.get(repay_pool_id).expect(ERR_NO_POOL);
+        let tokens = LendingContract::get_price_tokens(&loans, &deposits, &[]);
+        let prices = LendingContract::process_prices(&tokens);
+
+        let amount = collateral_pool.withdraw_amount(&account_id, amount.into());
+        let interest = collateral_pool.amount_claimable(&account_id);
+        let amount_in = amount + interest;
+        let min_amount_out = Balance::from(min_amount_out);
+
+        // Selling collateral below its value lowers the borrow limit more than the loan,
+        // so the position must end within the limit or at least closer to it
+        let (loan_value, deposit_value) =
+            self.get_position_value(&account_id, &loans, &deposits, &prices);
+        let mut loans = loans;
+        let mut deposits = deposits;
+        LendingContract::find_deposit(&mut deposits, &collateral_pool).share -= amount_in;
+        if let Some(loan) = loans
+            .iter_mut()
+            .find(|loan| loan.lending_token == repay_pool.lending_token)
+        {
+            loan.amount -= min_amount_out.min(loan.amount);
+        }
+        let (new_loan_value, new_deposit_value) =
+            self.get_position_value(&account_id, &loans, &deposits, &prices);
+        assert!(
+            new_loan_value <= new_deposit_value
+                || U256::from(new_loan_value) * U256::from(deposit_value)
+                    <= U256::from(loan_value) * U256::from(new_deposit_value),
+            "{}",
+            ERR_POSITION_WORSE
+        );
+
+        log!(
+            "{} sell {} token with interest {} from pool {} to repay pool {}, at least {}",
+            account_id,
+            amount,
+            interest,
+            collateral_pool_id,
+            repay_pool_id,
+            min_amount_out
+        );
+        collateral_pool.withdraw(account_id.clone(), amount, interest);
+        self.pools.replace(collateral_pool_id, &collateral_pool);
+        let dex_pool_id =
+            self.get_dex_pool_id(&collateral_pool.lending_token, &repay_pool.lending_token);
+        self.internal_swap(
+            account_id,
+            SwapAction {
+                pool_id: dex_pool_id,
+                token_in: collateral_pool.lending_token,
+                amount_in: Some(U128::from(amount_in)),
+                token_out: repay_pool.lending_token,
+                min_amount_out: U128::from(min_amount_out),
+            },
+            purpose,
+        )
+    }
+}
@@ -0,0 +1,139 @@
+use crate::*;
+
+#[near_bindgen]
+impl LendingContract {
+    // Let anyone add the claimable interest of the deposit to its share
+    #[payable]
+    pub fn set_auto_compound(&mut self, pool_id: u64, auto_compound: bool) {
+        assert_one_yocto();
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        let lender_id = env::predecessor_account_id();
+        pool.set_auto_compound(&lender_id, auto_compound);
+        self.pools.replace(pool_id, &pool);
+        log!(
+            "{} set auto compound to {} in pool {}",
+            lender_id,
+            auto_compound,
+            pool_id
+        );
+    }
+
+    // Compound the interest of the given lenders that turned on auto compound, without any
+    // transfer. The caller pages through the lenders to keep the gas bounded. Returns the
+    // interest compounded
+    pub fn compound(&mut self, pool_id: u64, lender_ids: Vec<AccountId>) -> U128 {
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(&pool, ActionType::Claim);
+        pool.update_pool();
+        let lender_ids: Vec<AccountId> = lender_ids
+            .into_iter()
+            .filter(|lender_id| {
+                pool.lenders
+                    .get(lender_id)
+                    .is_some_and(|lender| lender.auto_compound)
+            })
+            .collect();
+        let total_interest: Balance = lender_ids
+            .iter()
+            .map(|lender_id| pool.compound(lender_id))
+            .sum();
+        self.pools.replace(pool_id, &pool);
+        log!(
+            "Compounded {} Yocto {} of interest for {} lenders in pool {}",
+            total_interest,
+            pool.lending_token,
+            lender_ids.len(),
+            pool_id
+        );
+        U128::from(total_interest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lending_pool::tests::new_lending_pool;
+    use crate::test_utils::{add_pool, new_contract, set_context};
+    use crate::utils::ONE_DAY;
+    use crate::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+    use near_sdk::MockedBlockchain;
+
+    #[test]
+    fn test_compound() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut pool = new_lending_pool(0);
+        let lender = "lender".to_string();
+        pool.deposit(lender.clone(), 1_000_000_000);
+        pool.set_auto_compound(&lender, true);
+        pool.borrow(&"alice".to_string(), 500_000_000, None);
+
+        // 20% a year on 500_000_000 for a year
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(ONE_DAY * 365)
+            .build());
+        pool.update_pool();
+        assert_eq!(pool.compound(&lender), 100_000_000);
+        assert_eq!(pool.lenders.get(&lender).unwrap().share, 1_100_000_000);
+        assert_eq!(pool.total_share, 1_100_000_000);
+        assert_eq!(pool.amount_claimable(&lender), 0);
+        assert_eq!(pool.pool_supply, 500_000_000);
+
+        // Repaying brings the compounded interest into pool_supply
+        pool.repay("alice".to_string(), 600_000_000);
+        assert_eq!(pool.pool_supply, 1_100_000_000);
+    }
+
+    #[test]
+    fn test_compound_supply_cap() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut pool = new_lending_pool(0);
+        let lender = "lender".to_string();
+        pool.deposit(lender.clone(), 1_000_000_000);
+        pool.borrow(&"alice".to_string(), 500_000_000, None);
+        pool.supply_cap = Some(1_040_000_000);
+
+        // Only the interest up to the supply cap is compounded, the rest stays claimable
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(ONE_DAY * 365)
+            .build());
+        pool.update_pool();
+        assert_eq!(pool.compound(&lender), 40_000_000);
+        assert_eq!(pool.get_deposit(&lender), 1_040_000_000);
+        assert_eq!(pool.amount_claimable(&lender), 60_000_000);
+        assert_eq!(pool.remaining_supply_capacity(), Some(0));
+    }
+
+    // Only the listed lenders that turned on auto compound are compounded
+    #[test]
+    fn test_compound_lender_ids() {
+        let mut contract = new_contract();
+        let mut pool = add_pool(&mut contract, "usdc", 6);
+        let (alice, bob, carol) = ("alice".to_string(), "bob".to_string(), "carol".to_string());
+        pool.deposit(alice.clone(), 1_000_000_000);
+        pool.deposit(bob.clone(), 1_000_000_000);
+        pool.deposit(carol.clone(), 1_000_000_000);
+        pool.borrow(&"dave".to_string(), 1_500_000_000, None);
+        contract.pools.replace(0, &pool);
+        set_context("alice", vec![]);
+        contract.set_auto_compound(0, true);
+        assert_eq!(
+            contract.get_lender_ids(0, 1, 2),
+            vec![bob.clone(), carol.clone()]
+        );
+
+        // 300_000_000 of interest shared by three lenders
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(ONE_DAY * 365)
+            .build());
+        assert_eq!(
+            contract.compound(0, vec![alice.clone(), bob.clone()]),
+            U128::from(100_000_000)
+        );
+        let pool = contract.pools.get(0).unwrap();
+        assert_eq!(pool.get_deposit(&alice), 1_100_000_000);
+        assert_eq!(pool.get_deposit(&bob), 1_000_000_000);
+        assert_eq!(pool.amount_claimable(&bob), 100_000_000);
+        assert_eq!(pool.amount_claimable(&carol), 100_000_000);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::utils::ERR_DELEGATION_EXCEEDED;
+use crate::*;
+
+#[near_bindgen]
+impl LendingContract {
+    // Let delegatee borrow up to amount from a pool against the caller's collateral,
+    // the loan is the caller's. An amount of 0 revokes the delegation
+    #[payable]
+    pub fn approve_delegation(&mut self, delegatee: ValidAccountId, pool_id: u64, amount: U128) {
+        assert_one_yocto();
+        self.pools.get(pool_id).expect(ERR_NO_POOL);
+        let key = LendingContract::delegation_key(
+            &env::predecessor_account_id(),
+            delegatee.as_ref(),
+            pool_id,
+        );
+        if Balance::from(amount) > 0 {
+            self.delegations.insert(&key, &amount.into());
+        } else {
+            self.delegations.remove(&key);
+        }
+        log!(
+            "{} approved {} to borrow {} token from pool {}",
+            env::predecessor_account_id(),
+            delegatee,
+            Balance::from(amount),
+            pool_id
+        );
+    }
+
+    // Borrow against the collateral of the delegator, the tokens are sent to the caller
+    #[payable]
+    pub fn borrow_on_behalf(
+        &mut self,
+        delegator: ValidAccountId,
+        pool_id: u64,
+        amount: U128,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_delegation(
+            delegator.as_ref(),
+            &env::predecessor_account_id(),
+            pool_id,
+            amount.into(),
+        );
+        self.internal_borrow(
+            delegator.into(),
+            env::predecessor_account_id(),
+            pool_id,
+            amount,
+            None,
+            None,
+        )
+    }
+}
+
+impl LendingContract {
+    pub(crate) fn delegation_key(
+        delegator: &AccountId,
+        delegatee: &AccountId,
+        pool_id: u64,
+    ) -> String {
+        format!("{}:{}:{}", delegator, delegatee, pool_id)
+    }
+
+    pub(crate) fn assert_delegation(
+        &self,
+        delegator: &AccountId,
+        delegatee: &AccountId,
+        pool_id: u64,
+        amount: Balance,
+    ) {
+        let key = LendingContract::delegation_key(delegator, delegatee, pool_id);
+        let allowance = self.delegations.get(&key).unwrap_or(0);
+        assert!(amount <= allowance, "{}", ERR_DELEGATION_EXCEEDED);
+    }
+
+    // The allowance shrinks with each loan sent to the delegatee. It is used before the
+    // transfer, so loans in flight together cannot exceed it
+    pub(crate) fn use_delegation(
+        &mut self,
+        delegator: &AccountId,
+        delegatee: &AccountId,
+        pool_id: u64,
+        amount: Balance,
+    ) {
+        let key = LendingContract::delegation_key(delegator, delegatee, pool_id);
+        let allowance = self.delegations.get(&key).unwrap_or(0);
+        assert!(amount <= allowance, "{}", ERR_DELEGATION_EXCEEDED);
+        if allowance > amount {
+            self.delegations.insert(&key, &(allowance - amount));
+        } else {
+            self.delegations.remove(&key);
+        }
+        log!(
+            "{} borrowed {} token from pool {} on behalf of {}, remaining allowance {}",
+            delegatee,
+            amount,
+            pool_id,
+            delegator,
+            allowance - amount
+        );
+    }
+
+    // Give back the allowance of a loan whose transfer failed, unless the delegation was
+    // revoked since
+    pub(crate) fn restore_delegation(
+        &mut self,
+        delegator: &AccountId,
+        delegatee: &AccountId,
+        pool_id: u64,
+        amount: Balance,
+    ) {
+        let key = LendingContract::delegation_key(delegator, delegatee, pool_id);
+        if let Some(allowance) = self.delegations.get(&key) {
+            self.delegations.insert(&key, &(allowance + amount));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{add_pool, new_contract, set_context};
+
+    #[test]
+    fn test_use_delegation() {
+        let mut contract = new_contract();
+        let delegator = "institution".to_string();
+        let delegatee = "trader".to_string();
+        let key = LendingContract::delegation_key(&delegator, &delegatee, 0);
+        contract.delegations.insert(&key, &1_000);
+
+        contract.use_delegation(&delegator, &delegatee, 0, 400);
+        assert_eq!(contract.delegations.get(&key), Some(600));
+        contract.use_delegation(&delegator, &delegatee, 0, 600);
+        assert_eq!(contract.delegations.get(&key), None);
+    }
+
+    // Loans in flight use the allowance, so together they cannot exceed it
+    #[test]
+    #[should_panic(expected = "ERR_DELEGATION_EXCEEDED")]
+    fn test_delegation_exceeded() {
+        let mut contract = new_contract();
+        let (delegator, delegatee) = ("institution".to_string(), "trader".to_string());
+        let key = LendingContract::delegation_key(&delegator, &delegatee, 0);
+        contract.delegations.insert(&key, &1_000);
+        contract.use_delegation(&delegator, &delegatee, 0, 600);
+        contract.use_delegation(&delegator, &delegatee, 0, 600);
+    }
+
+    // The allowance of a loan whose transfer failed is given back
+    #[test]
+    fn test_delegation_transfer_failed() {
+        let mut contract = new_contract();
+        let mut pool = add_pool(&mut contract, "usdc", 6);
+        pool.deposit("lender".to_string(), 1_000_000_000);
+        contract.pools.replace(0, &pool);
+        let (delegator, delegatee) = ("institution".to_string(), "trader".to_string());
+        let key = LendingContract::delegation_key(&delegator, &delegatee, 0);
+        contract.delegations.insert(&key, &1_000);
+        let loan = pool.new_loan(&delegator, 400);
+        for (result, allowance) in [
+            (PromiseResult::Failed, Some(1_000)),
+            (PromiseResult::Successful(vec![]), Some(600)),
+        ] {
+            contract.use_delegation(&delegator, &delegatee, 0, 400);
+            set_context("lending", vec![result]);
+            contract.update_borrower(0, loan.clone(), U128::from(0), delegatee.clone());
+            assert_eq!(contract.delegations.get(&key), allowance);
+        }
+        assert_eq!(contract.pools.get(0).unwrap().get_debt(&delegator), 400);
+
+        // Revoked while the transfer was pending
+        contract.use_delegation(&delegator, &delegatee, 0, 400);
+        contract.delegations.remove(&key);
+        set_context("lending", vec![PromiseResult::Failed]);
+        contract.update_borrower(0, loan, U128::from(0), delegatee);
+        assert_eq!(contract.delegations.get(&key), None);
+    }
+}
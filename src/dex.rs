@@ -0,0 +1,581 @@
+use crate::utils::{
+    dex_contract, SwapAction, ERR_NO_DEX, ERR_NO_DEX_POOL, ERR_NO_DEX_WITHDRAWAL,
+    GAS_FOR_DEX_DEPOSIT, GAS_FOR_DEX_SWAP, GAS_FOR_DEX_WITHDRAW, GAS_FOR_SWAP_CALLBACK,
+    MAX_SWAP_SLIPPAGE, SLIPPAGE_DIVISOR,
+};
+use crate::*;
+
+// What the output of a swap is used for, and what to undo when the swap fails
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum SwapPurpose {
+    // Tokens borrowed from borrow_pool_id are deposited as collateral in collateral_pool_id
+    Leverage {
+        borrow_pool_id: u64,
+        collateral_pool_id: u64,
+    },
+    // Deposit withdrawn from from_pool_id is deposited in to_pool_id
+    CollateralSwap {
+        from_pool_id: u64,
+        to_pool_id: u64,
+    },
+    // Deposit withdrawn from collateral_pool_id repays the loan of repay_pool_id
+    Repay {
+        collateral_pool_id: u64,
+        repay_pool_id: u64,
+    },
+}
+
+// Arguments of the callbacks that check a position before a swap: what the swap is for, and the
+// loans and deposits whose prices were fetched
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapArgs {
+    pub purpose: SwapPurpose,
+    pub loans: Vec<Loan>,
+    pub deposits: Vec<LenderInfo>,
+}
+
+// Tokens withdrawn from the exchange after a swap: the output when swapped, otherwise the input
+// to undo the action. A failed withdrawal is kept until it is retried
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DexWithdrawal {
+    pub account_id: AccountId,
+    pub token: AccountId,
+    pub amount: U128,
+    pub purpose: SwapPurpose,
+    pub swapped: bool,
+}
+
+#[near_bindgen]
+impl LendingContract {
+    // Owner sets the dex pool used to swap between two tokens, None removes it
+    pub fn set_dex_pool(
+        &mut self,
+        token_a: ValidAccountId,
+        token_b: ValidAccountId,
+        dex_pool_id: Option<u64>,
+    ) {
+        self.assert_owner();
+        let key = LendingContract::dex_pool_key(token_a.as_ref(), token_b.as_ref());
+        let old_dex_pool_id = match dex_pool_id {
+            Some(dex_pool_id) => self.dex_pool_ids.insert(&key, &dex_pool_id),
+            None => self.dex_pool_ids.remove(&key),
+        };
+        log!(
+            "Dex pool of {} updated from {:?} to {:?}",
+            key,
+            old_dex_pool_id,
+            dex_pool_id
+        );
+    }
+
+    // Swap the tokens once they are in the exchange. The part the exchange refunded is back in
+    // the contract and is undone right away, a partial deposit is withdrawn to be undone
+    #[private]
+    pub fn on_dex_deposit(
+        &mut self,
+        account_id: AccountId,
+        action: SwapAction,
+        purpose: SwapPurpose,
+    ) {
+        let amount_in = Balance::from(action.amount_in.expect("ERR_NO_AMOUNT_IN"));
+        let used = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                serde_json::from_slice::<U128>(&result).map_or(0, Balance::from)
+            }
+            _ => 0,
+        };
+        if used == amount_in {
+            let dex = self.dex.clone().expect(ERR_NO_DEX);
+            dex_contract::swap(vec![action.clone()], None, &dex, 1, GAS_FOR_DEX_SWAP).then(
+                self_contract::on_dex_swap(
+                    account_id,
+                    action,
+                    purpose,
+                    &env::current_account_id(),
+                    0,
+                    GAS_FOR_DEX_WITHDRAW + GAS_FOR_SWAP_CALLBACK * 2,
+                ),
+            );
+            return;
+        }
+        log!(
+            "Deposit of {} {} in dex for {} only used {}: {:?}",
+            amount_in,
+            action.token_in,
+            account_id,
+            used,
+            purpose
+        );
+        self.undo_swap(account_id.clone(), amount_in - used, purpose.clone());
+        if used > 0 {
+            self.internal_dex_withdraw(
+                DexWithdrawal {
+                    account_id,
+                    token: action.token_in,
+                    amount: U128::from(used),
+                    purpose,
+                    swapped: false,
+                },
+                None,
+            );
+        }
+    }
+
+    // Withdraw the output of a successful swap, or undo the action when the swap failed
+    #[private]
+    pub fn on_dex_swap(&mut self, account_id: AccountId, action: SwapAction, purpose: SwapPurpose) {
+        let SwapAction {
+            token_in,
+            amount_in,
+            token_out,
+            ..
+        } = action;
+        let amount_in = amount_in.expect("ERR_NO_AMOUNT_IN");
+        let amount_out = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                serde_json::from_slice::<U128>(&result).map_or(0, Balance::from)
+            }
+            _ => 0,
+        };
+        if amount_out == 0 {
+            log!(
+                "Swap of {} {} for {} failed: {:?}",
+                Balance::from(amount_in),
+                token_in,
+                account_id,
+                purpose
+            );
+            // The action is undone once the input is back from the exchange
+            self.internal_dex_withdraw(
+                DexWithdrawal {
+                    account_id,
+                    token: token_in,
+                    amount: amount_in,
+                    purpose,
+                    swapped: false,
+                },
+                None,
+            );
+            return;
+        }
+        log!(
+            "Swapped {} {} to {} {} for {}",
+            Balance::from(amount_in),
+            token_in,
+            amount_out,
+            token_out,
+            account_id
+        );
+        self.internal_dex_withdraw(
+            DexWithdrawal {
+                account_id,
+                token: token_out,
+                amount: U128::from(amount_out),
+                purpose,
+                swapped: true,
+            },
+            None,
+        );
+    }
+
+    // Finish the swap once the tokens are back in the contract. A failed withdrawal leaves them
+    // in the deposit of the contract in the exchange, and is kept to be retried
+    #[private]
+    pub fn on_dex_withdraw(&mut self, withdrawal: DexWithdrawal, withdrawal_id: Option<u64>) {
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            let amount = withdrawal.amount.into();
+            if withdrawal.swapped {
+                self.complete_swap(withdrawal.account_id, amount, withdrawal.purpose);
+            } else {
+                self.undo_swap(withdrawal.account_id, amount, withdrawal.purpose);
+            }
+            return;
+        }
+        let withdrawal_id = withdrawal_id.unwrap_or_else(|| {
+            self.dex_withdrawal_count += 1;
+            self.dex_withdrawal_count - 1
+        });
+        log!(
+            "Withdrawal {} of {} {} from dex failed for {}: {:?}",
+            withdrawal_id,
+            Balance::from(withdrawal.amount),
+            withdrawal.token,
+            withdrawal.account_id,
+            withdrawal.purpose
+        );
+        self.dex_withdrawals.insert(&withdrawal_id, &withdrawal);
+    }
+
+    // Anyone can retry a failed withdrawal, the tokens only go to the account of the swap
+    #[payable]
+    pub fn retry_dex_withdraw(&mut self, withdrawal_id: u64) -> Promise {
+        assert_one_yocto();
+        // Removed while the retry is pending so that it cannot be withdrawn twice
+        let withdrawal = self
+            .dex_withdrawals
+            .remove(&withdrawal_id)
+            .expect(ERR_NO_DEX_WITHDRAWAL);
+        log!("Retry withdrawal {} from dex", withdrawal_id);
+        self.internal_dex_withdraw(withdrawal, Some(withdrawal_id))
+    }
+}
+
+impl LendingContract {
+    // Deposit the tokens in the exchange, swap them in one pool and withdraw the output
+    pub(crate) fn internal_swap(
+        &self,
+        account_id: AccountId,
+        action: SwapAction,
+        purpose: SwapPurpose,
+    ) -> Promise {
+        let dex = self.dex.clone().expect(ERR_NO_DEX);
+        let amount_in = action.amount_in.expect("ERR_NO_AMOUNT_IN");
+        ft_contract::ft_transfer_call(
+            ValidAccountId::try_from(dex).unwrap(),
+            amount_in,
+            None,
+            "".to_string(),
+            &action.token_in.clone(),
+            1,
+            GAS_FOR_DEX_DEPOSIT,
+        )
+        .then(self_contract::on_dex_deposit(
+            account_id,
+            action,
+            purpose,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_DEX_SWAP + GAS_FOR_DEX_WITHDRAW + GAS_FOR_SWAP_CALLBACK * 3,
+        ))
+    }
+
+    fn internal_dex_withdraw(
+        &self,
+        withdrawal: DexWithdrawal,
+        withdrawal_id: Option<u64>,
+    ) -> Promise {
+        dex_contract::withdraw(
+            ValidAccountId::try_from(withdrawal.token.clone()).unwrap(),
+            withdrawal.amount,
+            None,
+            &self.dex.clone().expect(ERR_NO_DEX),
+            1,
+            GAS_FOR_DEX_WITHDRAW,
+        )
+        .then(self_contract::on_dex_withdraw(
+            withdrawal,
+            withdrawal_id,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_SWAP_CALLBACK,
+        ))
+    }
+
+    pub(crate) fn get_dex_pool_id(&self, token_a: &AccountId, token_b: &AccountId) -> u64 {
+        self.dex_pool_ids
+            .get(&LendingContract::dex_pool_key(token_a, token_b))
+            .expect(ERR_NO_DEX_POOL)
+    }
+
+    // Pools swap both ways, so the key does not depend on the order of the tokens
+    pub(crate) fn dex_pool_key(token_a: &AccountId, token_b: &AccountId) -> String {
+        if token_a < token_b {
+            format!("{}:{}", token_a, token_b)
+        } else {
+            format!("{}:{}", token_b, token_a)
+        }
+    }
+
+    // Amount of token_out worth the same as amount of token_in at the oracle prices
+    pub(crate) fn convert_amount(
+        &self,
+        token_in: &AccountId,
+        token_out: &AccountId,
+        amount: Balance,
+        prices: &HashMap<AccountId, Balance>,
+    ) -> Balance {
+        let decimals_in = self.token_decimals.get(token_in).unwrap() as u32;
+        let decimals_out = self.token_decimals.get(token_out).unwrap() as u32;
+        (U256::from(amount) * U256::from(prices[token_in]) * U256::from(10u128.pow(decimals_out))
+            / U256::from(prices[token_out])
+            / U256::from(10u128.pow(decimals_in)))
+        .as_u128()
+    }
+
+    // Slippage protection: the swap must return at least the oracle value minus the maximum slippage
+    pub(crate) fn get_min_amount_out(
+        &self,
+        token_in: &AccountId,
+        token_out: &AccountId,
+        amount_in: Balance,
+        prices: &HashMap<AccountId, Balance>,
+        min_amount_out: Option<U128>,
+    ) -> Balance {
+        let oracle_amount_out = self.convert_amount(token_in, token_out, amount_in, prices)
+            * (SLIPPAGE_DIVISOR - MAX_SWAP_SLIPPAGE)
+            / SLIPPAGE_DIVISOR;
+        oracle_amount_out.max(min_amount_out.map_or(0, Balance::from))
+    }
+
+    fn complete_swap(&mut self, account_id: AccountId, amount_out: Balance, purpose: SwapPurpose) {
+        match purpose {
+            SwapPurpose::Leverage {
+                collateral_pool_id, ..
+            } => {
+                let mut pool = self.pools.get(collateral_pool_id).expect(ERR_NO_POOL);
+                self.credit_deposit(&mut pool, account_id, amount_out);
+                self.pools.replace(collateral_pool_id, &pool);
+            }
+            SwapPurpose::CollateralSwap { to_pool_id, .. } => {
+                let mut pool = self.pools.get(to_pool_id).expect(ERR_NO_POOL);
+                self.credit_deposit(&mut pool, account_id, amount_out);
+                self.pools.replace(to_pool_id, &pool);
+            }
+            // Any output above the debt is sent to the account
+            SwapPurpose::Repay { repay_pool_id, .. } => {
+                let mut pool = self.pools.get(repay_pool_id).expect(ERR_NO_POOL);
+                let refund = self.internal_repay(&mut pool, account_id.clone(), amount_out);
+                log!(
+                    "{} repayed {} Yocto {} to pool {} with collateral",
+                    account_id,
+                    amount_out - refund,
+                    pool.lending_token,
+                    repay_pool_id
+                );
+                self.refund(&pool, account_id, refund);
+                self.pools.replace(repay_pool_id, &pool);
+            }
+        }
+    }
+
+    fn undo_swap(&mut self, account_id: AccountId, amount_in: Balance, purpose: SwapPurpose) {
+        match purpose {
+            SwapPurpose::Leverage { borrow_pool_id, .. } => {
+                let mut pool = self.pools.get(borrow_pool_id).expect(ERR_NO_POOL);
+                let refund = self.internal_repay(&mut pool, account_id.clone(), amount_in);
+                log!(
+                    "{} repayed {} Yocto {} to pool {}",
+                    account_id,
+                    amount_in - refund,
+                    pool.lending_token,
+                    borrow_pool_id
+                );
+                self.refund(&pool, account_id, refund);
+                self.pools.replace(borrow_pool_id, &pool);
+            }
+            // The withdrawn tokens go back to the deposit
+            SwapPurpose::CollateralSwap {
+                from_pool_id: collateral_pool_id,
+                ..
+            }
+            | SwapPurpose::Repay {
+                collateral_pool_id, ..
+            } => {
+                let mut pool = self.pools.get(collateral_pool_id).expect(ERR_NO_POOL);
+                self.credit_deposit(&mut pool, account_id, amount_in);
+                self.pools.replace(collateral_pool_id, &pool);
+            }
+        }
+    }
+
+    // Deposit swapped tokens without the checks of a new deposit, which were done before the swap.
+    // The part above the supply cap is sent to the account
+    fn credit_deposit(&self, pool: &mut LendingPool, account_id: AccountId, amount: Balance) {
+        let accepted = pool
+            .remaining_supply_capacity()
+            .map_or(amount, |capacity| amount.min(capacity));
+        log!(
+            "{} deposited {} Yocto {} to pool {}",
+            account_id,
+            accepted,
+            pool.lending_token,
+            pool.pool_id
+        );
+        pool.deposit(account_id.clone(), accepted);
+        self.refund(pool, account_id, amount - accepted);
+    }
+
+    fn refund(&self, pool: &LendingPool, account_id: AccountId, amount: Balance) {
+        if amount > 0 {
+            ft_contract::ft_transfer(
+                ValidAccountId::try_from(account_id).unwrap(),
+                U128::from(amount),
+                None,
+                &pool.lending_token,
+                1,
+                10_000_000_000_000,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, account, add_pool, set_context};
+
+    fn new_contract() -> LendingContract {
+        let mut contract = test_utils::new_contract();
+        contract.token_decimals.insert(&"wnear".to_string(), &24);
+        contract.token_decimals.insert(&"usdc".to_string(), &6);
+        contract
+    }
+
+    #[test]
+    fn test_convert_amount() {
+        let contract = new_contract();
+        let prices: HashMap<AccountId, Balance> =
+            vec![("wnear".to_string(), 50_000), ("usdc".to_string(), 10_000)]
+                .into_iter()
+                .collect();
+        // 2 wNEAR at $5 is worth 10 USDC
+        let usdc = contract.convert_amount(
+            &"wnear".to_string(),
+            &"usdc".to_string(),
+            2 * 10u128.pow(24),
+            &prices,
+        );
+        assert_eq!(usdc, 10_000_000);
+        let wnear =
+            contract.convert_amount(&"usdc".to_string(), &"wnear".to_string(), usdc, &prices);
+        assert_eq!(wnear, 2 * 10u128.pow(24));
+
+        // The oracle value minus 2% slippage, unless the caller asks for more
+        assert_eq!(
+            contract.get_min_amount_out(
+                &"wnear".to_string(),
+                &"usdc".to_string(),
+                2 * 10u128.pow(24),
+                &prices,
+                None
+            ),
+            9_800_000
+        );
+        assert_eq!(
+            contract.get_min_amount_out(
+                &"wnear".to_string(),
+                &"usdc".to_string(),
+                2 * 10u128.pow(24),
+                &prices,
+                Some(U128::from(9_900_000))
+            ),
+            9_900_000
+        );
+    }
+
+    #[test]
+    fn test_dex_pool() {
+        let mut contract = new_contract();
+        set_context("owner", vec![]);
+        contract.set_dex_pool(account("wnear"), account("usdc"), Some(3));
+        // Pools swap both ways
+        assert_eq!(
+            contract.get_dex_pool_id(&"usdc".to_string(), &"wnear".to_string()),
+            3
+        );
+        contract.set_dex_pool(account("usdc"), account("wnear"), None);
+        assert_eq!(
+            contract.get_dex_pool(account("wnear"), account("usdc")),
+            None
+        );
+    }
+
+    // A failed withdrawal of the swap input is kept and the deposit is only credited back once
+    // a retry succeeds
+    #[test]
+    fn test_retry_dex_withdraw() {
+        let mut contract = new_contract();
+        contract.dex = Some("amm".to_string());
+        add_pool(&mut contract, "wnear", 24);
+        add_pool(&mut contract, "usdc", 6);
+        let withdrawal = DexWithdrawal {
+            account_id: "alice".to_string(),
+            token: "wnear".to_string(),
+            amount: U128::from(1_000),
+            purpose: SwapPurpose::CollateralSwap {
+                from_pool_id: 0,
+                to_pool_id: 1,
+            },
+            swapped: false,
+        };
+        set_context("lending", vec![PromiseResult::Failed]);
+        contract.on_dex_withdraw(withdrawal.clone(), None);
+        assert_eq!(contract.get_dex_withdrawal(0), Some(withdrawal.clone()));
+        assert_eq!(
+            contract
+                .pools
+                .get(0)
+                .unwrap()
+                .get_deposit(&"alice".to_string()),
+            0
+        );
+
+        // The withdrawal is taken out while the retry is pending, and kept if it fails again
+        contract.retry_dex_withdraw(0);
+        assert_eq!(contract.get_dex_withdrawal(0), None);
+        contract.on_dex_withdraw(withdrawal.clone(), Some(0));
+        assert_eq!(contract.get_dex_withdrawal(0), Some(withdrawal.clone()));
+        assert_eq!(contract.dex_withdrawal_count, 1);
+
+        contract.retry_dex_withdraw(0);
+        set_context("lending", vec![PromiseResult::Successful(vec![])]);
+        contract.on_dex_withdraw(withdrawal, Some(0));
+        assert_eq!(contract.get_dex_withdrawal(0), None);
+        assert_eq!(
+            contract
+                .pools
+                .get(0)
+                .unwrap()
+                .get_deposit(&"alice".to_string()),
+            1_000
+        );
+    }
+
+    // Tokens the exchange did not take are credited back without a swap
+    #[test]
+    fn test_dex_deposit_refunded() {
+        let mut contract = new_contract();
+        contract.dex = Some("amm".to_string());
+        add_pool(&mut contract, "wnear", 24);
+        add_pool(&mut contract, "usdc", 6);
+        let action = || SwapAction {
+            pool_id: 0,
+            token_in: "wnear".to_string(),
+            amount_in: Some(U128::from(1_000)),
+            token_out: "usdc".to_string(),
+            min_amount_out: U128::from(0),
+        };
+        let purpose = SwapPurpose::CollateralSwap {
+            from_pool_id: 0,
+            to_pool_id: 1,
+        };
+        set_context("lending", vec![PromiseResult::Failed]);
+        contract.on_dex_deposit("alice".to_string(), action(), purpose.clone());
+        set_context(
+            "lending",
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&U128::from(400)).unwrap(),
+            )],
+        );
+        contract.on_dex_deposit("alice".to_string(), action(), purpose);
+        assert_eq!(
+            contract
+                .pools
+                .get(0)
+                .unwrap()
+                .get_deposit(&"alice".to_string()),
+            1_600
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_DEX_WITHDRAWAL")]
+    fn test_retry_unknown_dex_withdraw() {
+        let mut contract = new_contract();
+        set_context("lending", vec![PromiseResult::Successful(vec![])]);
+        contract.retry_dex_withdraw(0);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::utils::ERR_NO_EMODE_CATEGORY;
+use crate::*;
+
+// Correlated assets, like stablecoins or NEAR and its staking derivatives, lent against each other
+// with a higher borrow limit than the collateral factor of their pools
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct EModeCategory {
+    pub label: String,
+    // Borrow limit /100 of collateral in the category
+    pub collateral_factor: u128,
+    // Liquidation threshold /100 of collateral in the category
+    pub liquidation_threshold: u128,
+}
+
+#[near_bindgen]
+impl LendingContract {
+    // Opt into an efficiency mode category, None leaves it. The boosted borrow limit only applies
+    // while all the loans of the account are in the category, the position is checked when the
+    // account has loans
+    #[payable]
+    pub fn set_emode(&mut self, category_id: Option<u64>) -> PromiseOrValue<()> {
+        assert_one_yocto();
+        if let Some(category_id) = category_id {
+            self.emode_categories
+                .get(&category_id)
+                .expect(ERR_NO_EMODE_CATEGORY);
+        }
+        let account_id = env::predecessor_account_id();
+        let loans = self.get_all_loans(&account_id);
+        if loans.is_empty() {
+            self.internal_set_emode(&account_id, category_id);
+            return PromiseOrValue::Value(());
+        }
+        let deposits = self.get_all_deposits(&account_id);
+        let tokens = LendingContract::get_price_tokens(&loans, &deposits, &[]);
+        PromiseOrValue::Promise(
+            self.fetch_prices(&tokens)
+                .then(self_contract::set_emode_callback(
+                    account_id,
+                    category_id,
+                    loans,
+                    deposits,
+                    &env::current_account_id(),
+                    0,
+                    50_000_000_000_000,
+                )),
+        )
+    }
+
+    #[private]
+    pub fn set_emode_callback(
+        &mut self,
+        account_id: AccountId,
+        category_id: Option<u64>,
+        loans: Vec<Loan>,
+        deposits: Vec<LenderInfo>,
+    ) {
+        let tokens = LendingContract::get_price_tokens(&loans, &deposits, &[]);
+        let prices = LendingContract::process_prices(&tokens);
+        self.internal_set_emode(&account_id, category_id);
+        self.assert_borrow_limit(&account_id, &loans, &deposits, &prices);
+    }
+}
+
+impl LendingContract {
+    fn internal_set_emode(&mut self, account_id: &AccountId, category_id: Option<u64>) {
+        match category_id {
+            Some(category_id) => self.account_emodes.insert(account_id, &category_id),
+            None => self.account_emodes.remove(account_id),
+        };
+        log!("{} set efficiency mode to {:?}", account_id, category_id);
+    }
+
+    // Category of the account when all its loans are in it
+    pub(crate) fn get_emode_category(
+        &self,
+        account_id: &AccountId,
+        loans: &[Loan],
+    ) -> Option<(u64, EModeCategory)> {
+        let category_id = self.account_emodes.get(account_id)?;
+        let category = self.emode_categories.get(&category_id)?;
+        loans
+            .iter()
+            .all(|loan| {
+                self.get_pool_by_token(&loan.lending_token).emode_category == Some(category_id)
+            })
+            .then_some((category_id, category))
+    }
+
+    // Collateral factor /100 of a deposit, boosted when it is in the category of the account
+    pub(crate) fn get_collateral_factor(
+        &self,
+        pool: &LendingPool,
+        emode_category: &Option<(u64, EModeCategory)>,
+    ) -> u128 {
+        match emode_category {
+            Some((category_id, category)) if pool.emode_category == Some(*category_id) => {
+                category.collateral_factor
+            }
+            _ => pool.collateral_factor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{add_pool, new_contract};
+
+    #[test]
+    fn test_emode() {
+        let mut contract = new_contract();
+        contract.emode_categories.insert(
+            &1,
+            &EModeCategory {
+                label: "Stablecoins".to_string(),
+                collateral_factor: 97,
+                liquidation_threshold: 98,
+            },
+        );
+        for token in ["usdc", "usdt"] {
+            let mut pool = add_pool(&mut contract, token, 6);
+            pool.emode_category = Some(1);
+            contract.pools.replace(pool.pool_id, &pool);
+        }
+        add_pool(&mut contract, "wnear", 24);
+        let alice = "alice".to_string();
+        contract.account_emodes.insert(&alice, &1);
+        let prices: HashMap<AccountId, Balance> = vec![
+            ("usdc".to_string(), 10_000),
+            ("usdt".to_string(), 10_000),
+            ("wnear".to_string(), 50_000),
+        ]
+        .into_iter()
+        .collect();
+        let deposits = vec![LenderInfo {
+            lending_token: "usdc".to_string(),
+            share: 1_000_000_000,
+            reward_debt: 0,
+            acc_reward: 0,
+            is_collateral: true,
+            auto_compound: false,
+        }];
+        let usdt = contract.pools.get(1).unwrap();
+        let mut loans = vec![usdt.new_loan(&alice, 900_000_000)];
+
+        // $1,000 of USDC backs $970 of USDT in the stablecoin category
+        assert_eq!(
+            contract.get_position_value(&alice, &loans, &deposits, &prices),
+            (900, 970)
+        );
+        contract.assert_borrow_limit(&alice, &loans, &deposits, &prices);
+
+        // A loan outside the category falls back to the collateral factor of the pool
+        let wnear = contract.pools.get(2).unwrap();
+        loans.push(wnear.new_loan(&alice, 10u128.pow(23)));
+        assert_eq!(contract.get_emode_category(&alice, &loans), None);
+        assert_eq!(
+            contract.get_position_value(&alice, &loans, &deposits, &prices),
+            (900, 500)
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use crate::utils::{
+    ERR_FLASH_LOAN_IN_PROGRESS, ERR_NO_FLASH_LOAN, GAS_FOR_FLASH_LOAN_CALLBACK, GAS_FOR_PRICE,
+};
+use crate::*;
+
+#[near_bindgen]
+impl LendingContract {
+    // Lend tokens to a receiver contract through ft_transfer_call. The receiver must send
+    // back amount + fee with a FlashLoanRepay transfer before its ft_on_transfer resolves,
+    // anything not repaid becomes a loan of the caller, so the caller's collateral has to
+    // cover amount + fee and its origination fee as a loan before the tokens are sent
+    #[payable]
+    pub fn flash_loan(
+        &mut self,
+        pool_id: u64,
+        amount: U128,
+        receiver: ValidAccountId,
+        msg: String,
+    ) -> Promise {
+        assert_one_yocto();
+        let pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(&pool, ActionType::FlashLoan);
+        assert!(pool.flash_loan.is_none(), "{}", ERR_FLASH_LOAN_IN_PROGRESS);
+        let initiator = env::predecessor_account_id();
+        let flash_loan = FlashLoan {
+            initiator: initiator.clone(),
+            receiver: receiver.into(),
+            amount: amount.into(),
+            fee: pool.get_flash_loan_fee(amount.into()),
+            repaid: 0,
+        };
+        let loans = self.get_all_loans(&initiator);
+        let deposits = self.get_all_deposits(&initiator);
+        let tokens = LendingContract::get_price_tokens(
+            &loans,
+            &deposits,
+            std::slice::from_ref(&pool.lending_token),
+        );
+        let gas_for_prices = GAS_FOR_PRICE * tokens.len() as u64;
+        self.fetch_prices(&tokens)
+            .then(self_contract::flash_loan_callback(
+                pool_id,
+                flash_loan,
+                msg,
+                loans,
+                deposits,
+                &env::current_account_id(),
+                0,
+                env::prepaid_gas() - env::used_gas() - gas_for_prices - GAS_FOR_FLASH_LOAN_CALLBACK,
+            ))
+    }
+
+    #[private]
+    pub fn flash_loan_callback(
+        &mut self,
+        pool_id: u64,
+        flash_loan: FlashLoan,
+        msg: String,
+        loans: Vec<Loan>,
+        deposits: Vec<LenderInfo>,
+    ) -> Promise {
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        let tokens = LendingContract::get_price_tokens(
+            &loans,
+            &deposits,
+            std::slice::from_ref(&pool.lending_token),
+        );
+        let prices = LendingContract::process_prices(&tokens);
+        let mut loans = loans;
+        loans.push(pool.new_loan(
+            &flash_loan.initiator,
+            pool.get_flash_loan_debt(flash_loan.amount),
+        ));
+        self.assert_borrow_limit(&flash_loan.initiator, &loans, &deposits, &prices);
+        let fee = pool.start_flash_loan(
+            flash_loan.initiator.clone(),
+            flash_loan.receiver.clone(),
+            flash_loan.amount,
+        );
+        log!(
+            "{} flash borrowed {} token from pool {} to {} with fee {}",
+            flash_loan.initiator,
+            flash_loan.amount,
+            pool_id,
+            flash_loan.receiver,
+            fee
+        );
+        self.pools.replace(pool_id, &pool);
+        ft_contract::ft_transfer_call(
+            ValidAccountId::try_from(flash_loan.receiver).unwrap(),
+            U128::from(flash_loan.amount),
+            None,
+            msg,
+            &pool.lending_token,
+            1,
+            env::prepaid_gas() - env::used_gas() - GAS_FOR_FLASH_LOAN_CALLBACK * 2,
+        )
+        .then(self_contract::check_flash_loan(
+            pool_id,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_FLASH_LOAN_CALLBACK,
+        ))
+    }
+
+    // Settle the flash loan once the receiver's ft_on_transfer is resolved
+    #[private]
+    pub fn check_flash_loan(&mut self, pool_id: u64) {
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        let amount = pool.flash_loan.as_ref().expect(ERR_NO_FLASH_LOAN).amount;
+        // ft_transfer_call returns the amount used by the receiver, the rest was refunded
+        let unused = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                let used = serde_json::from_slice::<U128>(&result).map_or(0, Balance::from);
+                amount - used.min(amount)
+            }
+            _ => amount,
+        };
+        let shortfall = pool.finish_flash_loan(unused);
+        if shortfall > 0 {
+            log!(
+                "Flash loan of pool {} not repaid, {} recorded as loan",
+                pool_id,
+                shortfall
+            );
+        }
+        self.pools.replace(pool_id, &pool);
+    }
+}
@@ -0,0 +1,658 @@
+use crate::utils::{
+    BORROW_RATE_DIVISOR, ERR_DUST_ABOVE_MIN_BORROW, ERR_INVALID_COLLATERAL_FACTOR,
+    ERR_INVALID_EMODE_CATEGORY, ERR_NO_EMODE_CATEGORY, ERR_NO_PROPOSAL,
+    ERR_TIMELOCK_DELAY_TOO_SHORT, ERR_TIMELOCK_NOT_EXPIRED, INTEREST_DIVISOR, LIQUIDATE_THRESHOLD,
+    MIN_TIMELOCK_DELAY,
+};
+use crate::*;
+
+// Risk parameter changes that only take effect after the timelock delay
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum ProposalKind {
+    InterestRate {
+        pool_id: u64,
+        interest_rate: u64,
+    },
+    CollateralFactor {
+        pool_id: u64,
+        collateral_factor: u128,
+    },
+    TokenDecimals {
+        pool_id: u64,
+        decimals: u8,
+    },
+    Oracle {
+        oracle: AccountId,
+    },
+    TimelockDelay {
+        delay: U64,
+    },
+    Dex {
+        dex: AccountId,
+    },
+    StableRate {
+        pool_id: u64,
+        stable_rate_premium: u64,
+        rebalance_utilization: u64,
+    },
+    TermLoan {
+        pool_id: u64,
+        grace_period: U64,
+        early_repayment_penalty: u64,
+    },
+    // A debt ceiling makes the pool isolated, None lists it as regular collateral
+    Isolation {
+        pool_id: u64,
+        debt_ceiling: Option<Balance>,
+        borrowable_in_isolation: bool,
+    },
+    // None removes the category, pools keep it until they are moved out by PoolEMode
+    EMode {
+        category_id: u64,
+        category: Option<EModeCategory>,
+    },
+    PoolEMode {
+        pool_id: u64,
+        category_id: Option<u64>,
+    },
+    ReserveFactor {
+        pool_id: u64,
+        reserve_factor: u64,
+    },
+    ReferralFactor {
+        pool_id: u64,
+        referral_factor: u64,
+    },
+    ProtocolFees {
+        pool_id: u64,
+        origination_fee: u64,
+        liquidation_fee: u64,
+    },
+    SafetyModule {
+        pool_id: u64,
+        safety_module_factor: u64,
+        max_slash_rate: u64,
+        stake_cooldown: U64,
+    },
+    // Slash up to amount of the stake, capped when executed by max_slash_rate and the bad debt
+    // lenders lost
+    Slash {
+        pool_id: u64,
+        amount: Balance,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Proposal {
+    pub proposal_id: u64,
+    pub kind: ProposalKind,
+    pub proposer: AccountId,
+    pub execution_time: U64,
+}
+
+#[near_bindgen]
+impl LendingContract {
+    // Owner schedules a parameter change, execution_time defaults to now + timelock delay
+    pub fn propose_change(&mut self, kind: ProposalKind, execution_time: Option<U64>) -> u64 {
+        self.assert_owner();
+        self.validate_proposal(&kind);
+        let earliest_time = env::block_timestamp() + self.timelock_delay;
+        let execution_time = execution_time.map_or(earliest_time, Timestamp::from);
+        assert!(
+            execution_time >= earliest_time,
+            "Execution time must be at least {}",
+            earliest_time
+        );
+        let proposal = Proposal {
+            proposal_id: self.proposal_count,
+            kind,
+            proposer: env::predecessor_account_id(),
+            execution_time: U64::from(execution_time),
+        };
+        log!(
+            "Proposal {} scheduled at {}: {:?}",
+            proposal.proposal_id,
+            execution_time,
+            proposal.kind
+        );
+        self.proposals.insert(&proposal.proposal_id, &proposal);
+        self.proposal_count += 1;
+        proposal.proposal_id
+    }
+
+    // Anyone can execute a proposal once its execution time has passed
+    pub fn execute_proposal(&mut self, proposal_id: u64) {
+        let proposal = self.proposals.get(&proposal_id).expect(ERR_NO_PROPOSAL);
+        assert!(
+            env::block_timestamp() >= proposal.execution_time.0,
+            "{}",
+            ERR_TIMELOCK_NOT_EXPIRED
+        );
+        self.validate_proposal(&proposal.kind);
+        self.proposals.remove(&proposal_id);
+        log!("Proposal {} executed: {:?}", proposal_id, proposal.kind);
+        match proposal.kind {
+            ProposalKind::InterestRate {
+                pool_id,
+                interest_rate,
+            } => {
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                pool.update_param(
+                    "interest_rate",
+                    |pool| &mut pool.interest_rate,
+                    interest_rate,
+                );
+                self.pools.replace(pool_id, &pool);
+            }
+            ProposalKind::CollateralFactor {
+                pool_id,
+                collateral_factor,
+            } => {
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                pool.update_param(
+                    "collateral_factor",
+                    |pool| &mut pool.collateral_factor,
+                    collateral_factor,
+                );
+                self.pools.replace(pool_id, &pool);
+            }
+            ProposalKind::TokenDecimals { pool_id, decimals } => {
+                let pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                let old_decimals = self.token_decimals.insert(&pool.lending_token, &decimals);
+                log!(
+                    "Pool {} decimals updated from {:?} to {}",
+                    pool_id,
+                    old_decimals,
+                    decimals
+                );
+            }
+            ProposalKind::Oracle { oracle } => {
+                log!("Oracle updated from {} to {}", self.oracle, oracle);
+                self.oracle = oracle;
+            }
+            ProposalKind::TimelockDelay { delay } => {
+                log!(
+                    "Timelock delay updated from {} to {}",
+                    self.timelock_delay,
+                    delay.0
+                );
+                self.timelock_delay = delay.into();
+            }
+            ProposalKind::StableRate {
+                pool_id,
+                stable_rate_premium,
+                rebalance_utilization,
+            } => {
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                pool.update_param(
+                    "stable_rate_premium",
+                    |pool| &mut pool.stable_rate_premium,
+                    stable_rate_premium,
+                );
+                pool.update_param(
+                    "rebalance_utilization",
+                    |pool| &mut pool.rebalance_utilization,
+                    rebalance_utilization,
+                );
+                self.pools.replace(pool_id, &pool);
+            }
+            ProposalKind::TermLoan {
+                pool_id,
+                grace_period,
+                early_repayment_penalty,
+            } => {
+                // Existing term loans keep the parameters they were taken with
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                pool.update_param(
+                    "grace_period",
+                    |pool| &mut pool.grace_period,
+                    grace_period.into(),
+                );
+                pool.update_param(
+                    "early_repayment_penalty",
+                    |pool| &mut pool.early_repayment_penalty,
+                    early_repayment_penalty,
+                );
+                self.pools.replace(pool_id, &pool);
+            }
+            ProposalKind::Isolation {
+                pool_id,
+                debt_ceiling,
+                borrowable_in_isolation,
+            } => {
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                pool.update_param("debt_ceiling", |pool| &mut pool.debt_ceiling, debt_ceiling);
+                pool.update_param(
+                    "borrowable_in_isolation",
+                    |pool| &mut pool.borrowable_in_isolation,
+                    borrowable_in_isolation,
+                );
+                self.pools.replace(pool_id, &pool);
+            }
+            ProposalKind::EMode {
+                category_id,
+                category,
+            } => {
+                let old_category = match &category {
+                    Some(category) => self.emode_categories.insert(&category_id, category),
+                    None => self.emode_categories.remove(&category_id),
+                };
+                log!(
+                    "Efficiency mode category {} updated from {:?} to {:?}",
+                    category_id,
+                    old_category,
+                    category
+                );
+            }
+            ProposalKind::PoolEMode {
+                pool_id,
+                category_id,
+            } => {
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                pool.update_param(
+                    "emode_category",
+                    |pool| &mut pool.emode_category,
+                    category_id,
+                );
+                self.pools.replace(pool_id, &pool);
+            }
+            ProposalKind::ReserveFactor {
+                pool_id,
+                reserve_factor,
+            } => {
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                pool.update_param(
+                    "reserve_factor",
+                    |pool| &mut pool.reserve_factor,
+                    reserve_factor,
+                );
+                self.pools.replace(pool_id, &pool);
+            }
+            ProposalKind::ReferralFactor {
+                pool_id,
+                referral_factor,
+            } => {
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                pool.update_param(
+                    "referral_factor",
+                    |pool| &mut pool.referral_factor,
+                    referral_factor,
+                );
+                self.pools.replace(pool_id, &pool);
+            }
+            ProposalKind::ProtocolFees {
+                pool_id,
+                origination_fee,
+                liquidation_fee,
+            } => {
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                pool.update_param(
+                    "origination_fee",
+                    |pool| &mut pool.origination_fee,
+                    origination_fee,
+                );
+                pool.update_param(
+                    "liquidation_fee",
+                    |pool| &mut pool.liquidation_fee,
+                    liquidation_fee,
+                );
+                self.pools.replace(pool_id, &pool);
+            }
+            ProposalKind::SafetyModule {
+                pool_id,
+                safety_module_factor,
+                max_slash_rate,
+                stake_cooldown,
+            } => {
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                pool.update_param(
+                    "safety_module_factor",
+                    |pool| &mut pool.safety_module_factor,
+                    safety_module_factor,
+                );
+                pool.update_param(
+                    "max_slash_rate",
+                    |pool| &mut pool.max_slash_rate,
+                    max_slash_rate,
+                );
+                pool.update_param(
+                    "stake_cooldown",
+                    |pool| &mut pool.stake_cooldown,
+                    stake_cooldown.into(),
+                );
+                self.pools.replace(pool_id, &pool);
+            }
+            ProposalKind::Slash { pool_id, amount } => {
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                let slashed = pool.slash(amount);
+                self.pools.replace(pool_id, &pool);
+                log!(
+                    "Slashed {} Yocto {} of the stake in pool {}, {} bad debt left uncovered",
+                    slashed,
+                    pool.lending_token,
+                    pool_id,
+                    pool.uncovered_bad_debt
+                );
+            }
+            ProposalKind::Dex { dex } => {
+                log!("Dex updated from {:?} to {}", self.dex, dex);
+                self.dex = Some(dex);
+            }
+        }
+    }
+
+    // Owner limits how much can be supplied to and borrowed from a pool, None removes the cap.
+    // Caps and limits only restrict new positions, so they apply without the timelock
+    pub fn set_pool_caps(
+        &mut self,
+        pool_id: u64,
+        supply_cap: Option<U128>,
+        borrow_cap: Option<U128>,
+    ) {
+        self.assert_owner();
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        pool.update_param(
+            "supply_cap",
+            |pool| &mut pool.supply_cap,
+            supply_cap.map(Balance::from),
+        );
+        pool.update_param(
+            "borrow_cap",
+            |pool| &mut pool.borrow_cap,
+            borrow_cap.map(Balance::from),
+        );
+        self.pools.replace(pool_id, &pool);
+    }
+
+    // Owner sets the smallest loan and deposit a pool accepts, and the debt below which a
+    // partially repaid loan is closed out, which cannot be above the smallest loan
+    pub fn set_pool_limits(
+        &mut self,
+        pool_id: u64,
+        min_borrow: U128,
+        min_deposit: U128,
+        dust_threshold: U128,
+    ) {
+        self.assert_owner();
+        assert!(
+            dust_threshold.0 <= min_borrow.0,
+            "{}",
+            ERR_DUST_ABOVE_MIN_BORROW
+        );
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        pool.update_param("min_borrow", |pool| &mut pool.min_borrow, min_borrow.into());
+        pool.update_param(
+            "min_deposit",
+            |pool| &mut pool.min_deposit,
+            min_deposit.into(),
+        );
+        pool.update_param(
+            "dust_threshold",
+            |pool| &mut pool.dust_threshold,
+            dust_threshold.into(),
+        );
+        self.pools.replace(pool_id, &pool);
+    }
+
+    // Owner sets the flash loan fee of a pool, /10000 of the borrowed amount
+    pub fn set_flash_loan_fee(&mut self, pool_id: u64, flash_loan_fee: u64) {
+        self.assert_owner();
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        pool.update_param(
+            "flash_loan_fee",
+            |pool| &mut pool.flash_loan_fee,
+            flash_loan_fee,
+        );
+        self.pools.replace(pool_id, &pool);
+    }
+
+    // Guardian can drop a scheduled proposal before it is executed
+    pub fn cancel_proposal(&mut self, proposal_id: u64) {
+        self.assert_guardian();
+        let proposal = self.proposals.remove(&proposal_id).expect(ERR_NO_PROPOSAL);
+        log!("Proposal {} cancelled: {:?}", proposal_id, proposal.kind);
+    }
+
+    fn validate_proposal(&self, kind: &ProposalKind) {
+        match kind {
+            ProposalKind::InterestRate { pool_id, .. }
+            | ProposalKind::TokenDecimals { pool_id, .. } => {
+                self.pools.get(*pool_id).expect(ERR_NO_POOL);
+            }
+            ProposalKind::CollateralFactor {
+                pool_id,
+                collateral_factor,
+            } => {
+                self.pools.get(*pool_id).expect(ERR_NO_POOL);
+                // Borrowing limit has to stay below the liquidation threshold
+                assert!(
+                    *collateral_factor < LIQUIDATE_THRESHOLD,
+                    "{}",
+                    ERR_INVALID_COLLATERAL_FACTOR
+                );
+            }
+            ProposalKind::Oracle { oracle } => {
+                assert!(
+                    env::is_valid_account_id(oracle.as_bytes()),
+                    "Invalid oracle account"
+                );
+            }
+            ProposalKind::StableRate {
+                pool_id,
+                rebalance_utilization,
+                ..
+            } => {
+                self.pools.get(*pool_id).expect(ERR_NO_POOL);
+                assert!(
+                    *rebalance_utilization as u128 <= INTEREST_DIVISOR,
+                    "Invalid rebalance utilization"
+                );
+            }
+            ProposalKind::TermLoan {
+                pool_id,
+                early_repayment_penalty,
+                ..
+            } => {
+                self.pools.get(*pool_id).expect(ERR_NO_POOL);
+                assert!(
+                    *early_repayment_penalty as u128 <= INTEREST_DIVISOR,
+                    "Invalid early repayment penalty"
+                );
+            }
+            ProposalKind::Isolation {
+                pool_id,
+                debt_ceiling,
+                borrowable_in_isolation,
+            } => {
+                self.pools.get(*pool_id).expect(ERR_NO_POOL);
+                assert!(
+                    debt_ceiling.is_none() || !borrowable_in_isolation,
+                    "Isolated pools cannot be borrowed in isolation"
+                );
+            }
+            ProposalKind::EMode { category, .. } => {
+                // Borrowing limit has to stay below the liquidation threshold
+                if let Some(category) = category {
+                    assert!(
+                        category.collateral_factor < category.liquidation_threshold
+                            && category.liquidation_threshold < BORROW_RATE_DIVISOR,
+                        "{}",
+                        ERR_INVALID_EMODE_CATEGORY
+                    );
+                }
+            }
+            ProposalKind::PoolEMode {
+                pool_id,
+                category_id,
+            } => {
+                self.pools.get(*pool_id).expect(ERR_NO_POOL);
+                if let Some(category_id) = category_id {
+                    self.emode_categories
+                        .get(category_id)
+                        .expect(ERR_NO_EMODE_CATEGORY);
+                }
+            }
+            ProposalKind::ReserveFactor {
+                pool_id,
+                reserve_factor,
+            } => {
+                self.pools.get(*pool_id).expect(ERR_NO_POOL);
+                assert!(
+                    *reserve_factor as u128 <= INTEREST_DIVISOR,
+                    "Invalid reserve factor"
+                );
+            }
+            ProposalKind::ReferralFactor {
+                pool_id,
+                referral_factor,
+            } => {
+                self.pools.get(*pool_id).expect(ERR_NO_POOL);
+                assert!(
+                    *referral_factor as u128 <= INTEREST_DIVISOR,
+                    "Invalid referral factor"
+                );
+            }
+            ProposalKind::ProtocolFees {
+                pool_id,
+                origination_fee,
+                liquidation_fee,
+            } => {
+                self.pools.get(*pool_id).expect(ERR_NO_POOL);
+                assert!(
+                    (*origination_fee as u128) < INTEREST_DIVISOR
+                        && *liquidation_fee as u128 <= INTEREST_DIVISOR,
+                    "Invalid protocol fees"
+                );
+            }
+            ProposalKind::SafetyModule {
+                pool_id,
+                safety_module_factor,
+                max_slash_rate,
+                ..
+            } => {
+                self.pools.get(*pool_id).expect(ERR_NO_POOL);
+                assert!(
+                    *safety_module_factor as u128 <= INTEREST_DIVISOR
+                        && *max_slash_rate as u128 <= INTEREST_DIVISOR,
+                    "Invalid safety module parameters"
+                );
+            }
+            ProposalKind::Slash { pool_id, .. } => {
+                self.pools.get(*pool_id).expect(ERR_NO_POOL);
+            }
+            ProposalKind::Dex { dex } => {
+                assert!(
+                    env::is_valid_account_id(dex.as_bytes()),
+                    "Invalid dex account"
+                );
+            }
+            ProposalKind::TimelockDelay { delay } => {
+                assert!(
+                    delay.0 >= MIN_TIMELOCK_DELAY,
+                    "{}",
+                    ERR_TIMELOCK_DELAY_TOO_SHORT
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{add_pool, context, new_contract, set_context};
+    use crate::utils::TIMELOCK_DELAY;
+    use near_sdk::testing_env;
+    use near_sdk::MockedBlockchain;
+
+    // Pool 0 lends usdc at a 20% interest rate
+    fn new_governed_contract() -> LendingContract {
+        let mut contract = new_contract();
+        add_pool(&mut contract, "usdc", 6);
+        contract
+    }
+
+    fn propose_interest_rate(contract: &mut LendingContract) -> u64 {
+        contract.propose_change(
+            ProposalKind::InterestRate {
+                pool_id: 0,
+                interest_rate: 3_000,
+            },
+            None,
+        )
+    }
+
+    #[test]
+    fn test_timelock() {
+        let mut contract = new_governed_contract();
+        let proposal_id = propose_interest_rate(&mut contract);
+        assert_eq!(
+            contract.get_proposal(proposal_id).execution_time,
+            U64::from(TIMELOCK_DELAY)
+        );
+
+        // Anyone executes the proposal once the delay has passed
+        testing_env!(context("alice").block_timestamp(TIMELOCK_DELAY).build());
+        contract.execute_proposal(proposal_id);
+        assert_eq!(contract.pools.get(0).unwrap().interest_rate, 3_000);
+        assert!(contract.proposals.get(&proposal_id).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TIMELOCK_NOT_EXPIRED")]
+    fn test_execute_before_delay() {
+        let mut contract = new_governed_contract();
+        let proposal_id = propose_interest_rate(&mut contract);
+        testing_env!(context("alice").block_timestamp(TIMELOCK_DELAY - 1).build());
+        contract.execute_proposal(proposal_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Execution time must be at least")]
+    fn test_execution_time_before_delay() {
+        let mut contract = new_governed_contract();
+        contract.propose_change(
+            ProposalKind::InterestRate {
+                pool_id: 0,
+                interest_rate: 3_000,
+            },
+            Some(U64::from(TIMELOCK_DELAY - 1)),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TIMELOCK_DELAY_TOO_SHORT")]
+    fn test_timelock_delay_too_short() {
+        let mut contract = new_governed_contract();
+        contract.propose_change(
+            ProposalKind::TimelockDelay {
+                delay: U64::from(MIN_TIMELOCK_DELAY - 1),
+            },
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_PROPOSAL")]
+    fn test_cancel_proposal() {
+        let mut contract = new_governed_contract();
+        let proposal_id = propose_interest_rate(&mut contract);
+        contract.cancel_proposal(proposal_id);
+        assert_eq!(contract.pools.get(0).unwrap().interest_rate, 2_000);
+        testing_env!(context("alice").block_timestamp(TIMELOCK_DELAY).build());
+        contract.execute_proposal(proposal_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_GUARDIAN")]
+    fn test_cancel_proposal_not_guardian() {
+        let mut contract = new_governed_contract();
+        let proposal_id = propose_interest_rate(&mut contract);
+        set_context("alice", vec![]);
+        contract.cancel_proposal(proposal_id);
+    }
+}
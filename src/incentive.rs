@@ -0,0 +1,283 @@
+use crate::utils::{ERR_INCENTIVE_NOT_FUNDED, ERR_INVALID_INCENTIVE, ERR_NO_INCENTIVE_REWARD};
+use crate::*;
+
+// Liquidity mining: a pool emits a NEP-141 reward token to its lenders or borrowers between the
+// start and end of a schedule. The reward tokens are sent with a FundIncentive transfer, and a
+// schedule takes the tokens it emits from these funds when it is added
+#[near_bindgen]
+impl LendingContract {
+    pub fn add_incentive(
+        &mut self,
+        pool_id: u64,
+        reward_token: ValidAccountId,
+        side: IncentiveSide,
+        start: Timestamp,
+        end: Timestamp,
+        rate: U128,
+    ) -> u64 {
+        self.assert_owner();
+        assert!(
+            start < end && end > env::block_timestamp(),
+            "{}",
+            ERR_INVALID_INCENTIVE
+        );
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        let incentive = Incentive {
+            reward_token: reward_token.into(),
+            side,
+            start,
+            end,
+            rate: rate.into(),
+            reward_per_share: 0,
+            last_update: 0,
+        };
+        let emission = incentive.get_remaining_emission();
+        let funds = self
+            .incentive_funds
+            .get(&incentive.reward_token)
+            .unwrap_or(0);
+        assert!(funds >= emission, "{}", ERR_INCENTIVE_NOT_FUNDED);
+        self.incentive_funds
+            .insert(&incentive.reward_token, &(funds - emission));
+        let incentive_id = pool.add_incentive(incentive);
+        self.pools.replace(pool_id, &pool);
+        log!(
+            "Incentive {} of pool {}: {} Yocto {} per second to {:?} from {} to {}",
+            incentive_id,
+            pool_id,
+            Balance::from(rate),
+            pool.incentives[incentive_id as usize].reward_token,
+            side,
+            start,
+            end
+        );
+        incentive_id
+    }
+
+    // Transfer the rewards of every incentive of the pool. They are removed before the transfers
+    // so that they cannot be claimed twice, and given back if a transfer fails
+    #[payable]
+    pub fn claim_incentives(&mut self, pool_id: u64) -> Promise {
+        assert_one_yocto();
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(&pool, ActionType::Claim);
+        let account_id = env::predecessor_account_id();
+        let rewards: Vec<(usize, Balance)> = pool
+            .get_incentive_rewards(&account_id)
+            .into_iter()
+            .enumerate()
+            .filter(|(_, amount)| *amount > 0)
+            .collect();
+        for (incentive_id, amount) in rewards.iter() {
+            pool.claim_incentive(&account_id, *incentive_id as u64, *amount);
+        }
+        self.pools.replace(pool_id, &pool);
+        rewards
+            .into_iter()
+            .map(|(incentive_id, amount)| {
+                log!(
+                    "{} claim {} Yocto {}",
+                    account_id,
+                    amount,
+                    pool.incentives[incentive_id].reward_token
+                );
+                ft_contract::ft_transfer(
+                    ValidAccountId::try_from(account_id.clone()).unwrap(),
+                    U128::from(amount),
+                    None,
+                    &pool.incentives[incentive_id].reward_token,
+                    1,
+                    10_000_000_000_000,
+                )
+                .then(self_contract::check_claim_incentive_success(
+                    pool_id,
+                    incentive_id as u64,
+                    account_id.clone(),
+                    U128::from(amount),
+                    &env::current_account_id(),
+                    0,
+                    10_000_000_000_000,
+                ))
+            })
+            .reduce(|claims, claim| claims.and(claim))
+            .expect(ERR_NO_INCENTIVE_REWARD)
+    }
+
+    // Give back the rewards when the transfer of an incentive claim failed
+    #[private]
+    pub fn check_claim_incentive_success(
+        &mut self,
+        pool_id: u64,
+        incentive_id: u64,
+        account_id: AccountId,
+        amount: U128,
+    ) {
+        if let PromiseResult::Successful(_) = env::promise_result(env::promise_results_count() - 1)
+        {
+            return;
+        }
+        log!(
+            "Claim of {} Yocto of incentive {} failed for {}",
+            Balance::from(amount),
+            incentive_id,
+            account_id
+        );
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        pool.restore_incentive(&account_id, incentive_id, amount.into());
+        self.pools.replace(pool_id, &pool);
+    }
+
+    // Reward tokens funded and not yet given to a schedule
+    pub fn get_incentive_funds(&self, reward_token: ValidAccountId) -> U128 {
+        U128::from(self.incentive_funds.get(reward_token.as_ref()).unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lending_pool::tests::new_lending_pool;
+    use crate::test_utils::{account, add_pool, context, new_contract, set_context_with};
+    use crate::utils::ONE_SECOND;
+    use crate::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+    use near_sdk::MockedBlockchain;
+
+    fn set_time(seconds: u64) {
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(seconds * ONE_SECOND)
+            .build());
+    }
+
+    fn new_incentive(side: IncentiveSide) -> Incentive {
+        Incentive {
+            reward_token: "reward".to_string(),
+            side,
+            start: 100 * ONE_SECOND,
+            end: 200 * ONE_SECOND,
+            rate: 1_000,
+            reward_per_share: 0,
+            last_update: 0,
+        }
+    }
+
+    #[test]
+    fn test_incentives() {
+        set_time(0);
+        let mut pool = new_lending_pool(0);
+        let (alice, bob) = ("alice".to_string(), "bob".to_string());
+        assert_eq!(pool.add_incentive(new_incentive(IncentiveSide::Supply)), 0);
+        assert_eq!(pool.add_incentive(new_incentive(IncentiveSide::Borrow)), 1);
+        pool.deposit(alice.clone(), 1_000_000);
+        pool.borrow(&bob, 500_000, None);
+
+        // Nothing is emitted before the start
+        set_time(150);
+        assert_eq!(pool.get_incentive_rewards(&alice), vec![50_000, 0]);
+        assert_eq!(pool.get_incentive_rewards(&bob), vec![0, 50_000]);
+
+        // Bob's deposit shares the supply rewards from now on
+        pool.deposit(bob.clone(), 1_000_000);
+        set_time(250);
+        assert_eq!(pool.get_incentive_rewards(&alice), vec![75_000, 0]);
+        assert_eq!(pool.get_incentive_rewards(&bob), vec![25_000, 100_000]);
+
+        pool.claim_incentive(&bob, 1, 100_000);
+        assert_eq!(pool.get_incentive_rewards(&bob), vec![25_000, 0]);
+    }
+
+    fn set_context(
+        seconds: u64,
+        predecessor_account_id: &str,
+        promise_results: Vec<PromiseResult>,
+    ) {
+        set_context_with(
+            context(predecessor_account_id).block_timestamp(seconds * ONE_SECOND),
+            promise_results,
+        );
+    }
+
+    fn fund(contract: &mut LendingContract, amount: Balance) {
+        set_context(0, "reward", vec![]);
+        contract.ft_on_transfer(
+            account("owner"),
+            U128::from(amount),
+            "{\"transfer_type\": \"FundIncentive\", \"token\": \"reward\", \"pool_id\": 0}"
+                .to_string(),
+        );
+    }
+
+    fn add_incentive(contract: &mut LendingContract) -> u64 {
+        set_context(0, "owner", vec![]);
+        contract.add_incentive(
+            0,
+            account("reward"),
+            IncentiveSide::Supply,
+            100 * ONE_SECOND,
+            200 * ONE_SECOND,
+            U128::from(1_000),
+        )
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INCENTIVE_NOT_FUNDED")]
+    fn test_incentive_not_funded() {
+        let mut contract = new_contract();
+        add_pool(&mut contract, "usdc", 6);
+        fund(&mut contract, 99_999);
+        add_incentive(&mut contract);
+    }
+
+    // The rewards are removed before the transfer and given back if it fails
+    #[test]
+    fn test_claim_incentives() {
+        let mut contract = new_contract();
+        let mut pool = add_pool(&mut contract, "usdc", 6);
+        pool.deposit("alice".to_string(), 1_000_000);
+        contract.pools.replace(0, &pool);
+        fund(&mut contract, 150_000);
+        assert_eq!(add_incentive(&mut contract), 0);
+        assert_eq!(
+            contract.get_incentive_funds(account("reward")),
+            U128::from(50_000)
+        );
+
+        set_context(150, "alice", vec![]);
+        contract.claim_incentives(0);
+        let alice = "alice".to_string();
+        assert_eq!(
+            contract.pools.get(0).unwrap().get_incentive_rewards(&alice),
+            vec![0]
+        );
+
+        set_context(150, "lending", vec![PromiseResult::Failed]);
+        contract.check_claim_incentive_success(0, 0, alice.clone(), U128::from(50_000));
+        assert_eq!(
+            contract.pools.get(0).unwrap().get_incentive_rewards(&alice),
+            vec![50_000]
+        );
+
+        set_context(150, "alice", vec![]);
+        contract.claim_incentives(0);
+        set_context(150, "lending", vec![PromiseResult::Successful(vec![])]);
+        contract.check_claim_incentive_success(0, 0, alice.clone(), U128::from(50_000));
+        assert_eq!(
+            contract.pools.get(0).unwrap().get_incentive_rewards(&alice),
+            vec![0]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_INCENTIVE_REWARD")]
+    fn test_claim_incentives_twice() {
+        let mut contract = new_contract();
+        let mut pool = add_pool(&mut contract, "usdc", 6);
+        pool.deposit("alice".to_string(), 1_000_000);
+        contract.pools.replace(0, &pool);
+        fund(&mut contract, 100_000);
+        add_incentive(&mut contract);
+        set_context(150, "alice", vec![]);
+        contract.claim_incentives(0);
+        contract.claim_incentives(0);
+    }
+}
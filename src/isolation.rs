@@ -0,0 +1,190 @@
+use crate::utils::{
+    DEBT_CEILING_DECIMALS, ERR_DEBT_CEILING_EXCEEDED, ERR_ISOLATION_MODE,
+    ERR_NOT_BORROWABLE_IN_ISOLATION,
+};
+use crate::*;
+
+impl LendingContract {
+    // Collateral in an isolated pool cannot be used with other collateral and only backs loans
+    // of pools borrowable in isolation, returns the isolated pool of an account in isolation mode
+    pub(crate) fn assert_isolation(
+        &self,
+        loans: &[Loan],
+        deposits: &[LenderInfo],
+    ) -> Option<LendingPool> {
+        let mut collateral_pools = deposits
+            .iter()
+            .filter(|deposit| deposit.is_collateral)
+            .map(|deposit| self.get_pool_by_token(&deposit.lending_token));
+        let collateral_count = collateral_pools.clone().count();
+        let isolated_pool = collateral_pools.find(|pool| pool.debt_ceiling.is_some())?;
+        assert_eq!(collateral_count, 1, "{}", ERR_ISOLATION_MODE);
+        assert!(
+            loans.iter().all(|loan| {
+                self.get_pool_by_token(&loan.lending_token)
+                    .borrowable_in_isolation
+            }),
+            "{}",
+            ERR_NOT_BORROWABLE_IN_ISOLATION
+        );
+        Some(isolated_pool)
+    }
+
+    // New isolated debt of an account in isolation mode must fit under the debt ceiling of its
+    // collateral, checked before the loans are sent
+    pub(crate) fn assert_debt_ceiling(&self, deposits: &[LenderInfo], isolated_debt: Balance) {
+        if let Some(isolated_pool) = self.get_isolated_pool(deposits) {
+            assert!(
+                isolated_pool.isolated_debt + isolated_debt <= isolated_pool.debt_ceiling.unwrap(),
+                "{}",
+                ERR_DEBT_CEILING_EXCEEDED
+            );
+        }
+    }
+
+    // New loans of an account in isolation mode count towards the debt ceiling of its collateral
+    // before they are sent
+    pub(crate) fn add_isolated_debt(
+        &mut self,
+        deposits: &[LenderInfo],
+        lending_token: &AccountId,
+        amount: Balance,
+    ) {
+        if let Some(mut isolated_pool) = self.get_isolated_pool(deposits) {
+            isolated_pool.isolated_debt += self.get_isolated_debt(lending_token, amount);
+            self.pools.replace(isolated_pool.pool_id, &isolated_pool);
+        }
+    }
+
+    // Repay a loan, the principal repaid frees room under the debt ceiling of an isolated collateral
+    pub(crate) fn internal_repay(
+        &mut self,
+        pool: &mut LendingPool,
+        borrower_id: AccountId,
+        amount: Balance,
+    ) -> Balance {
+        let amount_borrowed = pool.amount_borrowed;
+        let refund = pool.repay(borrower_id.clone(), amount);
+        let principal_paid = amount_borrowed - pool.amount_borrowed;
+        let deposits = self.get_all_deposits(&borrower_id);
+        self.remove_isolated_debt(&deposits, &pool.lending_token, principal_paid);
+        refund
+    }
+
+    // Principal that is no longer owed leaves the debt of an isolated collateral
+    pub(crate) fn remove_isolated_debt(
+        &mut self,
+        deposits: &[LenderInfo],
+        lending_token: &AccountId,
+        principal: Balance,
+    ) {
+        if let Some(mut isolated_pool) = self.get_isolated_pool(deposits) {
+            let isolated_debt = self.get_isolated_debt(lending_token, principal);
+            isolated_pool.isolated_debt = isolated_pool.isolated_debt.saturating_sub(isolated_debt);
+            self.pools.replace(isolated_pool.pool_id, &isolated_pool);
+        }
+    }
+
+    fn get_isolated_pool(&self, deposits: &[LenderInfo]) -> Option<LendingPool> {
+        deposits
+            .iter()
+            .filter(|deposit| deposit.is_collateral)
+            .map(|deposit| self.get_pool_by_token(&deposit.lending_token))
+            .find(|pool| pool.debt_ceiling.is_some())
+    }
+
+    // Loans borrowable in isolation are stable assets, counted at 1 with DEBT_CEILING_DECIMALS
+    pub(crate) fn get_isolated_debt(&self, lending_token: &AccountId, amount: Balance) -> Balance {
+        let decimals = self.token_decimals.get(lending_token).unwrap() as u32;
+        (U256::from(amount) * U256::from(10u128.pow(DEBT_CEILING_DECIMALS))
+            / U256::from(10u128.pow(decimals)))
+        .as_u128()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, add_pool, set_context};
+
+    // usdc can be borrowed against meme, which has a $1,000 debt ceiling
+    fn new_contract() -> LendingContract {
+        let mut contract = test_utils::new_contract();
+        let mut usdc = add_pool(&mut contract, "usdc", 6);
+        usdc.borrowable_in_isolation = true;
+        usdc.deposit("lender".to_string(), 10_000_000_000);
+        contract.pools.replace(usdc.pool_id, &usdc);
+        let mut meme = add_pool(&mut contract, "meme", 18);
+        meme.debt_ceiling = Some(100_000);
+        meme.deposit("alice".to_string(), 10u128.pow(24));
+        contract.pools.replace(meme.pool_id, &meme);
+        add_pool(&mut contract, "wnear", 24);
+        contract
+    }
+
+    #[test]
+    fn test_isolation() {
+        let mut contract = new_contract();
+        let alice = "alice".to_string();
+        let mut usdc = contract.pools.get(0).unwrap();
+        usdc.borrow(&alice, 600_000_000, None);
+        contract.pools.replace(0, &usdc);
+        let loans = contract.get_all_loans(&alice);
+        let deposits = contract.get_all_deposits(&alice);
+        assert_eq!(
+            contract
+                .assert_isolation(&loans, &deposits)
+                .unwrap()
+                .pool_id,
+            1
+        );
+        contract.add_isolated_debt(&deposits, &usdc.lending_token, 600_000_000);
+        assert_eq!(contract.pools.get(1).unwrap().isolated_debt, 60_000);
+
+        // Repaid principal frees room under the ceiling
+        contract.internal_repay(&mut usdc, alice, 100_000_000);
+        assert_eq!(contract.pools.get(1).unwrap().isolated_debt, 50_000);
+    }
+
+    // The loan counts towards the debt ceiling before its transfer and is released if it fails
+    #[test]
+    fn test_isolated_debt_transfer_failed() {
+        let mut contract = new_contract();
+        let alice = "alice".to_string();
+        let loan = contract.pools.get(0).unwrap().new_loan(&alice, 600_000_000);
+        let deposits = contract.get_all_deposits(&alice);
+        for (result, isolated_debt) in [
+            (PromiseResult::Failed, 0),
+            (PromiseResult::Successful(vec![]), 60_000),
+        ] {
+            contract.add_isolated_debt(&deposits, &loan.lending_token, loan.amount);
+            set_context("lending", vec![result]);
+            contract.update_borrower(0, loan.clone(), U128::from(0), alice.clone());
+            assert_eq!(contract.pools.get(1).unwrap().isolated_debt, isolated_debt);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DEBT_CEILING_EXCEEDED")]
+    fn test_debt_ceiling_exceeded() {
+        let mut contract = new_contract();
+        let deposits = contract.get_all_deposits(&"alice".to_string());
+        contract.add_isolated_debt(&deposits, &"usdc".to_string(), 600_000_000);
+        contract.assert_debt_ceiling(&deposits, 40_000);
+        contract.assert_debt_ceiling(
+            &deposits,
+            contract.get_isolated_debt(&"usdc".to_string(), 400_010_000),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ISOLATION_MODE")]
+    fn test_isolated_with_other_collateral() {
+        let mut contract = new_contract();
+        let mut wnear = contract.pools.get(2).unwrap();
+        wnear.deposit("alice".to_string(), 10u128.pow(24));
+        contract.pools.replace(2, &wnear);
+        let deposits = contract.get_all_deposits(&"alice".to_string());
+        contract.assert_isolation(&[], &deposits);
+    }
+}
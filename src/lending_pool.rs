@@ -1,8 +1,11 @@
 use crate::utils::{
-    Share, ERR_NO_BORROWER, INTEREST_DIVISOR, LIQUIDATE_THRESHOLD, LIQUIDATOR_INCENTIVE,
-    MAX_LIQUIDATE_RATE, ONE_DAY, SHARE_DIVISOR,
+    ActionType, Share, ERR_COOLDOWN_NOT_FINISHED, ERR_FLASH_LOAN_IN_PROGRESS, ERR_INVALID_TERM,
+    ERR_NOT_ENOUGH_SHARE, ERR_NO_BORROWER, ERR_NO_FLASH_LOAN, ERR_NO_STAKE, ERR_RATE_MODE_MISMATCH,
+    ERR_REBALANCE_NOT_ALLOWED, ERR_STAKE_COLLATERAL, ERR_TERM_LOAN, INTEREST_DIVISOR,
+    LIQUIDATOR_INCENTIVE, ONE_DAY, ONE_SECOND, SHARE_DIVISOR,
 };
 use crate::*;
+use std::fmt::Debug;
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
@@ -17,6 +20,83 @@ pub struct LendingPool {
     pub total_share: Share,
     pub reward_per_share: Balance,
     pub lastest_reward_time: Timestamp,
+    // Sum of interest_rate /10000 over the nanoseconds until lastest_reward_time, a variable loan
+    // owes the interest of the increase since it was taken
+    pub borrow_index: u128,
+    pub paused_actions: Vec<ActionType>,
+    pub collateral_factor: u128,
+    pub supply_cap: Option<Balance>,
+    pub borrow_cap: Option<Balance>,
+    pub min_borrow: Balance,
+    pub min_deposit: Balance,
+    pub dust_threshold: Balance,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub flash_loan_fee: u64,
+    pub flash_loan: Option<FlashLoan>,
+    // Principal of the loans in stable rate mode, the rest of amount_borrowed is variable
+    pub stable_borrowed: Balance,
+    // New stable loans are locked at interest_rate + stable_rate_premium
+    pub stable_rate_premium: u64,
+    // Utilization /10000 above which stable loans can be rebalanced to the current stable rate
+    pub rebalance_utilization: u64,
+    // Time after maturity before a term loan can be liquidated
+    pub grace_period: Timestamp,
+    // Penalty /10000 on the principal of a term loan repaid before maturity
+    pub early_repayment_penalty: u64,
+    // Isolated pools have a debt ceiling /10^DEBT_CEILING_DECIMALS for the loans backed by their
+    // collateral, which cannot be used with other collateral
+    pub debt_ceiling: Option<Balance>,
+    // Loans backed by the collateral of an isolated pool, same unit as debt_ceiling
+    pub isolated_debt: Balance,
+    // Stable assets that can be borrowed against isolated collateral
+    pub borrowable_in_isolation: bool,
+    // Efficiency mode category of the lending token
+    pub emode_category: Option<u64>,
+    // Share /10000 of the interest set aside to cover bad debt
+    pub reserve_factor: u64,
+    pub reserve: Balance,
+    // Debt written off after the collateral of its borrower was gone
+    pub bad_debt: Balance,
+    // Lender shares staked in the safety module, slashed to make up the losses of lenders
+    pub stakers: UnorderedMap<AccountId, StakeInfo>,
+    // Lending tokens staked, split between the stake shares of the stakers
+    pub total_staked: Balance,
+    pub total_stake_share: Share,
+    pub stake_reward_per_share: Balance,
+    // Share /10000 of the reserve income paid to stakers
+    pub safety_module_factor: u64,
+    // Share /10000 of the stake a single slash can take
+    pub max_slash_rate: u64,
+    // Time between an unstake request and the unstake
+    pub stake_cooldown: Timestamp,
+    // Bad debt lost by lenders that slashing has not made up yet
+    pub uncovered_bad_debt: Balance,
+    // Value /SHARE_DIVISOR of a lender share in lending tokens, lowered when bad debt is
+    // written off against lenders
+    pub share_price: Balance,
+    // Emission schedules of reward tokens for suppliers and borrowers
+    pub incentives: Vec<Incentive>,
+    // Rewards of each account, by incentive id
+    pub incentive_rewards: UnorderedMap<AccountId, Vec<IncentiveReward>>,
+    // Share /10000 of the interest of referred loans, after the reserve part, paid to referrers
+    pub referral_factor: u64,
+    pub referral_rewards: UnorderedMap<AccountId, Balance>,
+    // Fee /10000 of the borrowed amount, kept from the tokens sent to the borrower
+    pub origination_fee: u64,
+    // Share /10000 of the liquidator bonus taken as a protocol fee
+    pub liquidation_fee: u64,
+    // Protocol fees, outside pool_supply, that the owner can withdraw
+    pub treasury: Balance,
+}
+
+// Variable loans pay the pool interest rate, stable loans the rate locked when they were taken
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum RateMode {
+    Variable,
+    Stable,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -26,7 +106,40 @@ pub struct Loan {
     pub lending_token: AccountId,
     pub borrower: AccountId,
     pub loan_start_time: Timestamp,
+    // Borrowed amount with capitalized interest, interest accrues on it
+    pub amount: Balance,
+    // Part of amount that was lent out, the rest is capitalized interest
+    pub principal: Balance,
+    pub rate_mode: RateMode,
+    // borrow_index of the pool at loan_start_time
+    pub borrow_index: u128,
+    // Interest rate /10000 of a stable loan
+    pub stable_rate: u64,
+    pub term: Option<LoanTerm>,
+    // Earns referral_factor of the interest of the loan after the reserve part
+    pub referrer: Option<AccountId>,
+}
+
+// Term loans are stable loans with a maturity, the pool parameters are locked when they are taken
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LoanTerm {
+    pub maturity: Timestamp,
+    pub grace_period: Timestamp,
+    pub early_repayment_penalty: u64,
+}
+
+// Flash loan in progress, at most one per pool
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FlashLoan {
+    pub initiator: AccountId,
+    pub receiver: AccountId,
     pub amount: Balance,
+    pub fee: Balance,
+    pub repaid: Balance,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -37,102 +150,635 @@ pub struct LenderInfo {
     pub share: Share,
     pub reward_debt: Balance,
     pub acc_reward: Balance,
+    // Deposit counts towards the borrow limit
+    pub is_collateral: bool,
+    // Claimable interest can be added to share by anyone with compound
+    pub auto_compound: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StakeInfo {
+    // Stake share, a slash lowers what every stake share is worth
+    pub share: Share,
+    pub reward_debt: Balance,
+    pub acc_reward: Balance,
+    // Part of share that can be unstaked once the cooldown ends, it can still be slashed
+    pub cooldown_share: Share,
+    pub cooldown_end: Timestamp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum IncentiveSide {
+    // Shared by lenders in proportion to their share
+    Supply,
+    // Shared by borrowers in proportion to their principal
+    Borrow,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Incentive {
+    pub reward_token: AccountId,
+    pub side: IncentiveSide,
+    pub start: Timestamp,
+    pub end: Timestamp,
+    // Reward tokens emitted per second between start and end
+    pub rate: Balance,
+    pub reward_per_share: Balance,
+    pub last_update: Timestamp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct IncentiveReward {
+    pub reward_debt: Balance,
+    pub acc_reward: Balance,
+}
+
+impl Incentive {
+    // Reward tokens emitted from now to the end of the schedule
+    pub fn get_remaining_emission(&self) -> Balance {
+        let from = env::block_timestamp().max(self.start);
+        (U256::from(self.rate) * U256::from(self.end.saturating_sub(from)) / U256::from(ONE_SECOND))
+            .as_u128()
+    }
+
+    // Reward per share including the emission since the last update, total is the share or the
+    // principal of the side
+    fn get_reward_per_share(&self, total: Balance) -> Balance {
+        let from = self.last_update.max(self.start);
+        let to = env::block_timestamp().min(self.end);
+        if to <= from || total == 0 {
+            return self.reward_per_share;
+        }
+        self.reward_per_share
+            + (U256::from(self.rate) * U256::from(to - from) * U256::from(SHARE_DIVISOR)
+                / U256::from(ONE_SECOND)
+                / U256::from(total))
+            .as_u128()
+    }
 }
 
 impl LendingPool {
     pub fn update_pool(&mut self) {
+        self.borrow_index = self.get_borrow_index();
         if self.total_share == 0 {
             self.lastest_reward_time = env::block_timestamp();
             return;
         }
-        let pending_reward = self.borrowers.values().fold(0, |acc, borrower| {
-            acc + self.get_pending_interest(&borrower)
-        });
-        self.reward_per_share += (U256::from(pending_reward) * U256::from(SHARE_DIVISOR)
+        let pending_interest = self.get_pending_total_interest();
+        let reserve = self.get_reserve_part(pending_interest);
+        let stake_reward = self.get_stake_reward_part(reserve);
+        self.reserve += reserve - stake_reward;
+        let mut referral_reward = 0;
+        for (referrer_id, reward) in self.get_pending_referral_rewards() {
+            let acc_reward = self.referral_rewards.get(&referrer_id).unwrap_or(0);
+            self.referral_rewards
+                .insert(&referrer_id, &(acc_reward + reward));
+            referral_reward += reward;
+        }
+        if stake_reward > 0 {
+            self.stake_reward_per_share += (U256::from(stake_reward) * U256::from(SHARE_DIVISOR)
+                / U256::from(self.total_stake_share))
+            .as_u128();
+        }
+        self.reward_per_share += (U256::from(pending_interest - reserve - referral_reward)
+            * U256::from(SHARE_DIVISOR)
             / U256::from(self.total_share))
         .as_u128();
         self.lastest_reward_time = env::block_timestamp();
     }
 
+    // Pending interest is settled under the old value, so the borrow index keeps the past
+    // interest rate
+    pub fn update_param<T: Debug>(
+        &mut self,
+        name: &str,
+        param: fn(&mut LendingPool) -> &mut T,
+        value: T,
+    ) {
+        self.update_pool();
+        let pool_id = self.pool_id;
+        let param = param(self);
+        let old_value = std::mem::replace(param, value);
+        log!(
+            "Pool {} {} updated from {:?} to {:?}",
+            pool_id,
+            name,
+            old_value,
+            param
+        );
+    }
+
     pub fn deposit(&mut self, lender_id: AccountId, amount: Balance) {
         self.update_pool();
+        self.settle_incentives(&lender_id);
         let lending_token = self.lending_token.clone();
         let mut lender = self.lenders.get(&lender_id).unwrap_or(LenderInfo {
             lending_token,
             share: 0,
             reward_debt: 0,
             acc_reward: 0,
+            is_collateral: true,
+            auto_compound: false,
         });
         if lender.share > 0 {
             let pending = self.reward_per_share * lender.share / SHARE_DIVISOR - lender.reward_debt;
             lender.acc_reward += pending;
         }
-        lender.share += amount;
+        let share = self.amount_to_share(amount);
+        lender.share += share;
         lender.reward_debt = self.reward_per_share * lender.share / SHARE_DIVISOR;
         self.lenders.insert(&lender_id, &lender);
         self.pool_supply += amount;
-        self.total_share += amount;
+        self.total_share += share;
+        self.checkpoint_incentives(&lender_id);
+    }
+
+    pub fn new_loan(&self, borrower_id: &AccountId, amount: Balance) -> Loan {
+        Loan {
+            lending_token: self.lending_token.clone(),
+            borrower: borrower_id.clone(),
+            loan_start_time: env::block_timestamp(),
+            amount,
+            principal: amount,
+            rate_mode: RateMode::Variable,
+            borrow_index: self.get_borrow_index(),
+            stable_rate: 0,
+            term: None,
+            referrer: None,
+        }
+    }
+
+    // Term loans are opened as new loans and cannot be extended
+    pub fn get_loan_term(
+        &self,
+        borrower_id: &AccountId,
+        term: Option<Timestamp>,
+    ) -> Option<LoanTerm> {
+        self.assert_loan_term(borrower_id, term.is_some());
+        term.map(|term| {
+            assert!(term > 0, "{}", ERR_INVALID_TERM);
+            LoanTerm {
+                maturity: env::block_timestamp() + term,
+                grace_period: self.grace_period,
+                early_repayment_penalty: self.early_repayment_penalty,
+            }
+        })
+    }
+
+    pub fn assert_loan_term(&self, borrower_id: &AccountId, is_term: bool) {
+        if let Some(loan) = self.borrowers.get(borrower_id) {
+            assert!(loan.term.is_none() && !is_term, "{}", ERR_TERM_LOAN);
+        }
+    }
+
+    // Take a loan out of the pool supply, the rate mode must match an existing loan
+    pub fn borrow(
+        &mut self,
+        borrower_id: &AccountId,
+        amount: Balance,
+        rate_mode: Option<RateMode>,
+    ) {
+        let rate_mode = self.get_rate_mode(borrower_id, rate_mode);
+        self.reserve_loan(amount);
+        self.add_loan(borrower_id, amount, Some(rate_mode));
     }
 
-    pub fn borrow(&mut self, borrower_id: &AccountId, amount: Balance) {
+    // The tokens of a loan leave the pool supply before they are sent
+    pub fn reserve_loan(&mut self, amount: Balance) {
         assert!(
-            amount <= self.pool_supply - self.amount_borrowed,
+            amount <= self.pool_supply,
             "Dont enough token to borrow from pool"
         );
+        self.pool_supply -= amount;
+    }
+
+    // Record reserved tokens as a loan, it cannot fail once the tokens are sent: an existing
+    // loan keeps its mode and grows by the amount
+    pub fn add_loan(
+        &mut self,
+        borrower_id: &AccountId,
+        amount: Balance,
+        rate_mode: Option<RateMode>,
+    ) {
         self.update_pool();
-        let lending_token = self.lending_token.clone();
-        let mut borrower = self.borrowers.get(&borrower_id).unwrap_or(Loan {
-            lending_token,
-            amount: 0,
-            borrower: borrower_id.clone(),
-            loan_start_time: env::block_timestamp(),
+        self.settle_incentives(borrower_id);
+        let mut borrower = self.borrowers.get(&borrower_id).unwrap_or_else(|| {
+            let mut loan = self.new_loan(borrower_id, 0);
+            loan.rate_mode = rate_mode.unwrap_or(RateMode::Variable);
+            loan
         });
         let mut interest = 0;
         if borrower.amount > 0 {
             interest = self.get_interest(&borrower);
-            borrower.loan_start_time = env::block_timestamp();
+            self.restart_interest(&mut borrower);
+        }
+        if borrower.rate_mode == RateMode::Stable {
+            // The new amount is locked at the current stable rate, averaged with the existing debt
+            let debt = borrower.amount + interest;
+            borrower.stable_rate = if debt + amount == 0 {
+                self.get_stable_rate()
+            } else {
+                ((U256::from(debt) * U256::from(borrower.stable_rate)
+                    + U256::from(amount) * U256::from(self.get_stable_rate()))
+                    / U256::from(debt + amount))
+                .as_u64()
+            };
+            self.stable_borrowed += amount;
         }
         borrower.amount += amount + interest;
+        borrower.principal += amount;
         self.amount_borrowed += amount;
-        self.pool_supply -= amount;
         self.borrowers.insert(&borrower_id, &borrower);
+        self.checkpoint_incentives(borrower_id);
+    }
+
+    // The rate of a term loan is fixed at the stable rate until it is repaid, its tokens are
+    // reserved like those of other loans
+    pub fn borrow_term(&mut self, borrower_id: &AccountId, amount: Balance, term: LoanTerm) {
+        self.add_loan(borrower_id, amount, Some(RateMode::Stable));
+        let mut loan = self.borrowers.get(borrower_id).unwrap();
+        // The loan was checked to be new before its transfer, recording it must not fail after
+        if loan.rate_mode == RateMode::Stable {
+            loan.term.get_or_insert(term);
+        }
+        self.borrowers.insert(borrower_id, &loan);
+    }
+
+    // A borrower has a single loan per pool, so borrowing more keeps the mode of the loan
+    pub fn get_rate_mode(&self, borrower_id: &AccountId, rate_mode: Option<RateMode>) -> RateMode {
+        match self.borrowers.get(borrower_id) {
+            Some(loan) => {
+                assert_eq!(
+                    rate_mode.unwrap_or(loan.rate_mode),
+                    loan.rate_mode,
+                    "{}",
+                    ERR_RATE_MODE_MISMATCH
+                );
+                loan.rate_mode
+            }
+            None => rate_mode.unwrap_or(RateMode::Variable),
+        }
+    }
+
+    // Switch a loan between variable and stable rate, a stable loan is locked at the current stable rate
+    pub fn swap_rate_mode(&mut self, borrower_id: &AccountId) -> RateMode {
+        self.update_pool();
+        let mut loan = self
+            .borrowers
+            .get(borrower_id)
+            .expect("You have not borrowed anything yet");
+        assert!(loan.term.is_none(), "{}", ERR_TERM_LOAN);
+        loan.amount += self.get_interest(&loan);
+        self.restart_interest(&mut loan);
+        match loan.rate_mode {
+            RateMode::Variable => {
+                loan.rate_mode = RateMode::Stable;
+                loan.stable_rate = self.get_stable_rate();
+                self.stable_borrowed += loan.principal;
+            }
+            RateMode::Stable => {
+                loan.rate_mode = RateMode::Variable;
+                loan.stable_rate = 0;
+                self.stable_borrowed -= loan.principal;
+            }
+        }
+        self.borrowers.insert(borrower_id, &loan);
+        loan.rate_mode
+    }
+
+    // When utilization is too high, stable loans below the current stable rate are moved up to it
+    pub fn rebalance_stable_rate(&mut self, borrower_id: &AccountId) -> u64 {
+        let mut loan = self.borrowers.get(borrower_id).expect(ERR_NO_BORROWER);
+        let stable_rate = self.get_stable_rate();
+        assert!(
+            self.utilization() >= self.rebalance_utilization
+                && loan.rate_mode == RateMode::Stable
+                && loan.term.is_none()
+                && loan.stable_rate < stable_rate,
+            "{}",
+            ERR_REBALANCE_NOT_ALLOWED
+        );
+        self.update_pool();
+        loan.amount += self.get_interest(&loan);
+        self.restart_interest(&mut loan);
+        loan.stable_rate = stable_rate;
+        self.borrowers.insert(borrower_id, &loan);
+        stable_rate
     }
 
+    // Repayments pay the accrued interest first, then the principal with the early repayment
+    // penalty of a term loan. amount_borrowed only tracks principal, interest and penalty go
+    // to pool_supply for lenders
     pub fn repay(&mut self, borrower_id: AccountId, amount: Balance) -> Balance {
         self.update_pool();
+        self.settle_incentives(&borrower_id);
         let mut borrower = self
             .borrowers
             .get(&borrower_id)
             .expect("You have not borrowed anything yet");
-        let interest = self.get_interest(&borrower);
-        assert!(
-            amount >= interest,
-            "Amount repay must be greater than interest"
-        );
-        if amount >= (borrower.amount + interest) {
-            self.pool_supply += borrower.amount + interest;
+        let debt = borrower.amount + self.get_interest(&borrower);
+        let penalty_rate = self.get_early_repayment_penalty(&borrower);
+        let penalty = self.get_penalty(borrower.principal, penalty_rate);
+        if amount >= debt + penalty {
+            self.pool_supply += debt + penalty;
+            self.add_reward(penalty);
+            self.remove_principal(&borrower, borrower.principal);
+            self.borrowers.remove(&borrower_id);
+            self.checkpoint_incentives(&borrower_id);
+            return amount - debt - penalty;
+        }
+        let interest_paid = amount.min(debt - borrower.principal);
+        // The rest pays principal + penalty on it
+        let principal_paid = (U256::from(amount - interest_paid) * U256::from(INTEREST_DIVISOR)
+            / U256::from(INTEREST_DIVISOR + penalty_rate as u128))
+        .as_u128();
+        let penalty = amount - interest_paid - principal_paid;
+        // Interest left unpaid stays in the loan
+        borrower.amount = debt - interest_paid - principal_paid;
+        borrower.principal -= principal_paid;
+        self.restart_interest(&mut borrower);
+        self.pool_supply += amount;
+        self.add_reward(penalty);
+        self.remove_principal(&borrower, principal_paid);
+        if borrower.amount < self.dust_threshold && self.cover_dust(borrower.amount) {
+            // Remaining debt is not worth keeping the position open
+            log!(
+                "Loan of {} closed out with {} dust covered by the protocol",
+                borrower_id,
+                borrower.amount
+            );
+            self.remove_principal(&borrower, borrower.principal);
             self.borrowers.remove(&borrower_id);
-            self.amount_borrowed -= amount - (borrower.amount + interest);
-            amount - (borrower.amount + interest)
         } else {
-            borrower.amount -= amount - interest;
-            borrower.loan_start_time = env::block_timestamp();
-            self.pool_supply += amount;
-            self.amount_borrowed -= amount;
             self.borrowers.insert(&borrower_id, &borrower);
-            0
         }
+        self.checkpoint_incentives(&borrower_id);
+        0
+    }
+
+    // The reserve, then the treasury pays the dust of a closed out loan so lenders do not lose
+    // it. Returns false when they cannot cover all of it, the loan then stays open
+    fn cover_dust(&mut self, dust: Balance) -> bool {
+        if dust > self.reserve + self.treasury {
+            return false;
+        }
+        let from_reserve = dust.min(self.reserve);
+        self.reserve -= from_reserve;
+        // Treasury tokens are outside pool_supply
+        self.treasury -= dust - from_reserve;
+        self.pool_supply += dust - from_reserve;
+        true
+    }
+
+    // Penalty rate /10000 of repaying the loan now
+    pub fn get_early_repayment_penalty(&self, loan: &Loan) -> u64 {
+        match &loan.term {
+            Some(term) if env::block_timestamp() < term.maturity => term.early_repayment_penalty,
+            _ => 0,
+        }
+    }
+
+    fn get_penalty(&self, principal: Balance, penalty_rate: u64) -> Balance {
+        (U256::from(principal) * U256::from(penalty_rate) / U256::from(INTEREST_DIVISOR)).as_u128()
+    }
+
+    // A term loan past its maturity and grace period can be liquidated whatever its health
+    pub fn is_overdue(&self, loan: &Loan) -> bool {
+        loan.term
+            .as_ref()
+            .is_some_and(|term| env::block_timestamp() > term.maturity + term.grace_period)
+    }
+
+    // Tokens already in pool_supply that are shared between the lenders
+    fn add_reward(&mut self, reward: Balance) {
+        if self.total_share > 0 {
+            self.reward_per_share += (U256::from(reward) * U256::from(SHARE_DIVISOR)
+                / U256::from(self.total_share))
+            .as_u128();
+        }
+    }
+
+    fn remove_principal(&mut self, loan: &Loan, principal: Balance) {
+        self.amount_borrowed -= principal;
+        if loan.rate_mode == RateMode::Stable {
+            self.stable_borrowed -= principal;
+        }
+    }
+
+    // Lend tokens that have to come back with the fee in the same promise chain, returns the fee
+    pub fn start_flash_loan(
+        &mut self,
+        initiator: AccountId,
+        receiver: AccountId,
+        amount: Balance,
+    ) -> Balance {
+        assert!(self.flash_loan.is_none(), "{}", ERR_FLASH_LOAN_IN_PROGRESS);
+        assert!(
+            amount <= self.pool_supply,
+            "Dont enough token to borrow from pool"
+        );
+        let fee = self.get_flash_loan_fee(amount);
+        self.pool_supply -= amount;
+        self.flash_loan = Some(FlashLoan {
+            initiator,
+            receiver,
+            amount,
+            fee,
+            repaid: 0,
+        });
+        fee
+    }
+
+    pub fn get_flash_loan_fee(&self, amount: Balance) -> Balance {
+        (U256::from(amount) * U256::from(self.flash_loan_fee) / U256::from(INTEREST_DIVISOR))
+            .as_u128()
+    }
+
+    // Returns the part of the repayment above amount + fee, refunded to the sender
+    pub fn repay_flash_loan(&mut self, amount: Balance) -> Balance {
+        let mut flash_loan = self.flash_loan.take().expect(ERR_NO_FLASH_LOAN);
+        let accepted = amount.min(flash_loan.amount + flash_loan.fee - flash_loan.repaid);
+        flash_loan.repaid += accepted;
+        self.pool_supply += accepted;
+        self.flash_loan = Some(flash_loan);
+        amount - accepted
+    }
+
+    // Tokens the receiver did not use were refunded by the token contract. Repayments cover
+    // the amount before the fee, only the part of the fee that was paid goes to lenders.
+    // Anything not repaid becomes a loan of the initiator with its origination fee, whose
+    // collateral was checked against that loan before the tokens were sent. Returns the loan
+    pub fn finish_flash_loan(&mut self, unused: Balance) -> Balance {
+        let flash_loan = self.flash_loan.take().expect(ERR_NO_FLASH_LOAN);
+        self.pool_supply += unused;
+        self.update_pool();
+        let repaid = flash_loan.repaid + unused;
+        self.add_reward(repaid.saturating_sub(flash_loan.amount).min(flash_loan.fee));
+        let owed = flash_loan.amount + flash_loan.fee;
+        if repaid >= owed {
+            return 0;
+        }
+        let shortfall = owed - repaid;
+        let fee = self.get_origination_fee(shortfall);
+        // The tokens already left the pool, borrow only records the loan. As for any loan, the
+        // fee is lent from the pool to the treasury
+        self.pool_supply += shortfall;
+        self.borrow(&flash_loan.initiator, shortfall + fee, None);
+        self.treasury += fee;
+        shortfall + fee
+    }
+
+    // Largest loan a flash loan of amount can leave, with its origination fee
+    pub fn get_flash_loan_debt(&self, amount: Balance) -> Balance {
+        let owed = amount + self.get_flash_loan_fee(amount);
+        owed + self.get_origination_fee(owed)
     }
 
     pub fn withdraw(&mut self, lender_id: AccountId, amount: Balance, interest: Balance) {
         self.update_pool();
+        self.settle_incentives(&lender_id);
         let mut lender = self.lenders.get(&lender_id).unwrap();
         self.pool_supply -= amount + interest;
         lender.acc_reward = 0;
-        lender.share -= amount;
+        // Rounded up so the share left is never worth more than the deposit left
+        let share = self.amount_to_share_up(amount).min(lender.share);
+        lender.share -= share;
         lender.reward_debt = self.reward_per_share * lender.share / SHARE_DIVISOR;
-        self.total_share -= amount;
-        self.lenders.insert(&lender_id, &lender);
+        self.total_share -= share;
+        if lender.share == 0 {
+            self.lenders.remove(&lender_id);
+        } else {
+            self.lenders.insert(&lender_id, &lender);
+        }
+        self.checkpoint_incentives(&lender_id);
+    }
+
+    pub fn set_collateral(&mut self, lender_id: &AccountId, is_collateral: bool) {
+        let mut lender = self.lenders.get(lender_id).expect("ERR_NO_LENDER");
+        lender.is_collateral = is_collateral;
+        self.lenders.insert(lender_id, &lender);
+    }
+
+    // Referred loans keep their referrer
+    pub fn set_referrer(&mut self, borrower_id: &AccountId, referrer_id: Option<AccountId>) {
+        let mut loan = self.borrowers.get(borrower_id).expect(ERR_NO_BORROWER);
+        if loan.referrer.is_none() && referrer_id.is_some() {
+            self.update_pool();
+            loan.referrer = referrer_id;
+            self.borrowers.insert(borrower_id, &loan);
+        }
+    }
+
+    // Referral reward of a referrer, including the pending part
+    pub fn get_referral_reward(&self, referrer_id: &AccountId) -> Balance {
+        let pending_reward: Balance = self
+            .get_pending_referral_rewards()
+            .iter()
+            .filter(|(loan_referrer_id, _)| loan_referrer_id == referrer_id)
+            .map(|(_, reward)| reward)
+            .sum();
+        self.referral_rewards.get(referrer_id).unwrap_or(0) + pending_reward
+    }
+
+    // Remove amount from the referral reward before it is transferred
+    pub fn claim_referral_reward(&mut self, referrer_id: &AccountId, amount: Balance) {
+        self.update_pool();
+        let reward = self.referral_rewards.get(referrer_id).unwrap_or(0) - amount;
+        if reward == 0 {
+            self.referral_rewards.remove(referrer_id);
+        } else {
+            self.referral_rewards.insert(referrer_id, &reward);
+        }
+        self.pool_supply -= amount;
+    }
+
+    // Give back the referral reward of a failed transfer
+    pub fn restore_referral_reward(&mut self, referrer_id: &AccountId, amount: Balance) {
+        let reward = self.referral_rewards.get(referrer_id).unwrap_or(0) + amount;
+        self.referral_rewards.insert(referrer_id, &reward);
+        self.pool_supply += amount;
+    }
+
+    pub fn set_auto_compound(&mut self, lender_id: &AccountId, auto_compound: bool) {
+        let mut lender = self.lenders.get(lender_id).expect("ERR_NO_LENDER");
+        lender.auto_compound = auto_compound;
+        self.lenders.insert(lender_id, &lender);
+    }
+
+    // Add the claimable interest of a lender to its share, the tokens are already in pool_supply.
+    // Interest above the supply cap stays claimable. The pool must be updated before
+    pub fn compound(&mut self, lender_id: &AccountId) -> Balance {
+        self.settle_incentives(lender_id);
+        let mut lender = self.lenders.get(lender_id).expect("ERR_NO_LENDER");
+        let claimable = lender.acc_reward + self.reward_per_share * lender.share / SHARE_DIVISOR
+            - lender.reward_debt;
+        let interest = self
+            .remaining_supply_capacity()
+            .map_or(claimable, |capacity| claimable.min(capacity));
+        lender.acc_reward = claimable - interest;
+        let share = self.amount_to_share(interest);
+        lender.share += share;
+        lender.reward_debt = self.reward_per_share * lender.share / SHARE_DIVISOR;
+        self.lenders.insert(lender_id, &lender);
+        self.total_share += share;
+        self.checkpoint_incentives(lender_id);
+        interest
+    }
+
+    // Outstanding loan amount with accrued interest
+    pub fn get_debt(&self, borrower_id: &AccountId) -> Balance {
+        self.borrowers
+            .get(borrower_id)
+            .map_or(0, |loan| loan.amount + self.get_interest(&loan))
+    }
+
+    // Amount that closes the loan now, with the early repayment penalty of a term loan
+    pub fn get_repay_amount(&self, borrower_id: &AccountId) -> Balance {
+        self.borrowers.get(borrower_id).map_or(0, |loan| {
+            loan.amount
+                + self.get_interest(&loan)
+                + self.get_penalty(loan.principal, self.get_early_repayment_penalty(&loan))
+        })
+    }
+
+    // Withdrawals that would leave less than the minimum deposit take out the whole deposit
+    pub fn withdraw_amount(&self, lender_id: &AccountId, amount: Balance) -> Balance {
+        let deposit = self.get_deposit(lender_id);
+        assert!(amount <= deposit, "Amount withdraw exceeds deposit");
+        if deposit - amount < self.min_deposit {
+            deposit
+        } else {
+            amount
+        }
+    }
+
+    // Lending tokens the share of a lender is worth, without its interest
+    pub fn get_deposit(&self, lender_id: &AccountId) -> Balance {
+        self.lenders
+            .get(lender_id)
+            .map_or(0, |lender| self.share_to_amount(lender.share))
+    }
+
+    pub fn share_to_amount(&self, share: Share) -> Balance {
+        (U256::from(share) * U256::from(self.share_price) / U256::from(SHARE_DIVISOR)).as_u128()
+    }
+
+    pub fn amount_to_share(&self, amount: Balance) -> Share {
+        (U256::from(amount) * U256::from(SHARE_DIVISOR) / U256::from(self.share_price)).as_u128()
+    }
+
+    fn amount_to_share_up(&self, amount: Balance) -> Share {
+        ((U256::from(amount) * U256::from(SHARE_DIVISOR) + U256::from(self.share_price - 1))
+            / U256::from(self.share_price))
+        .as_u128()
     }
 
     pub fn claim(&mut self, lender_id: AccountId) {
@@ -150,104 +796,616 @@ impl LendingPool {
         self.lenders.insert(&lender_id, &lender);
     }
 
+    // Move amount of the borrower's deposit to the liquidator, the borrower keeps its interest
     pub fn liquidate(
         &mut self,
-        borrower_id: AccountId,
-        amount_deposit: Balance,
-        amount_collateral_token_out: Balance,
+        borrower_id: &AccountId,
+        liquidator_id: &AccountId,
+        amount: Balance,
+        fee: Balance,
     ) {
-    }
-
-    pub fn amount_claimable(&self, lender_id: &AccountId) -> Balance {
-        if let Some(lender) = self.lenders.get(&lender_id) {
-            let pending_reward = self.get_pending_reward();
-            let reward_per_share = self.reward_per_share
-                + (U256::from(pending_reward) * U256::from(SHARE_DIVISOR)
-                    / U256::from(self.total_share))
-                .as_u128();
-            reward_per_share * lender.share / SHARE_DIVISOR + lender.acc_reward - lender.reward_debt
+        self.update_pool();
+        self.settle_incentives(borrower_id);
+        let mut borrower = self.lenders.get(borrower_id).expect("ERR_NO_LENDER");
+        borrower.acc_reward +=
+            self.reward_per_share * borrower.share / SHARE_DIVISOR - borrower.reward_debt;
+        let share = self.amount_to_share_up(amount).min(borrower.share);
+        borrower.share -= share;
+        borrower.reward_debt = self.reward_per_share * borrower.share / SHARE_DIVISOR;
+        if borrower.share == 0 && borrower.acc_reward == 0 {
+            self.lenders.remove(borrower_id);
         } else {
-            0
+            self.lenders.insert(borrower_id, &borrower);
         }
+        self.checkpoint_incentives(borrower_id);
+        // The liquidator deposits the seized amount, less the protocol fee
+        self.total_share -= share;
+        self.pool_supply -= amount;
+        self.deposit(liquidator_id.clone(), amount - fee);
+        self.treasury += fee;
     }
 
-    pub fn get_pending_reward(&self) -> Balance {
-        self.borrowers.values().fold(0, |acc, borrower| {
-            acc + self.get_pending_interest(&borrower)
-        })
+    pub fn get_origination_fee(&self, amount: Balance) -> Balance {
+        (U256::from(amount) * U256::from(self.origination_fee) / U256::from(INTEREST_DIVISOR))
+            .as_u128()
     }
 
-    pub fn get_pending_interest(&self, borrower: &Loan) -> Balance {
-        (U256::from(self.interest_rate)
-            * U256::from(env::block_timestamp() - self.lastest_reward_time)
-            * U256::from(borrower.amount)
-            / U256::from(ONE_DAY)
-            / U256::from(365u128)
+    // Protocol fee on the bonus of seized, which includes the liquidator incentive
+    pub fn get_liquidation_fee(&self, seized: Balance) -> Balance {
+        (U256::from(seized) * U256::from(LIQUIDATOR_INCENTIVE) * U256::from(self.liquidation_fee)
+            / U256::from(BORROW_RATE_DIVISOR + LIQUIDATOR_INCENTIVE)
             / U256::from(INTEREST_DIVISOR))
         .as_u128()
     }
 
-    pub fn get_interest(&self, borrower: &Loan) -> Balance {
-        (U256::from(self.interest_rate)
-            * U256::from(env::block_timestamp() - borrower.loan_start_time)
-            * U256::from(borrower.amount)
-            / U256::from(ONE_DAY)
-            / U256::from(365u128)
-            / U256::from(INTEREST_DIVISOR))
-        .as_u128()
+    // Write off the debt of a borrower whose collateral is gone: the reserve covers what it can,
+    // the rest lowers the share price, so every deposit loses in proportion to its share.
+    // Returns the debt, and the part covered by the reserve
+    pub fn write_off(&mut self, borrower_id: &AccountId) -> (Balance, Balance) {
+        self.update_pool();
+        self.settle_incentives(borrower_id);
+        let loan = self.borrowers.get(borrower_id).expect(ERR_NO_BORROWER);
+        let debt = loan.amount + self.get_interest(&loan);
+        self.remove_principal(&loan, loan.principal);
+        self.borrowers.remove(borrower_id);
+        self.checkpoint_incentives(borrower_id);
+        let covered = self.cover_bad_debt(debt);
+        (debt, covered)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use near_sdk::MockedBlockchain;
-    use near_sdk::{testing_env, VMContext};
-    use near_sdk::{AccountId, Balance};
+    // The deposit of a borrower whose loans are written off goes to the reserve with its interest,
+    // the tokens stay in the pool. Returns the amount seized
+    pub fn seize_deposit(&mut self, lender_id: &AccountId) -> Balance {
+        self.update_pool();
+        self.settle_incentives(lender_id);
+        let lender = match self.lenders.get(lender_id) {
+            Some(lender) => lender,
+            None => return 0,
+        };
+        let amount = self.share_to_amount(lender.share) + self.amount_claimable(lender_id);
+        self.total_share -= lender.share;
+        self.lenders.remove(lender_id);
+        self.checkpoint_incentives(lender_id);
+        self.reserve += amount;
+        amount
+    }
 
-    fn get_context(
-        _account_id: String,
-        block_timestamp: Timestamp,
-        attached_deposit: Balance,
-    ) -> VMContext {
-        VMContext {
-            current_account_id: _account_id.clone(),
-            signer_account_id: _account_id.clone(),
-            signer_account_pk: vec![0, 1, 2],
-            predecessor_account_id: _account_id,
-            input: vec![],
-            block_index: 0,
-            block_timestamp,
-            account_balance: 1_00_000_000_000_000_000_000_000_000,
-            account_locked_balance: 0,
-            storage_usage: 1000_000_000,
-            attached_deposit,
-            prepaid_gas: 10u64.pow(18),
-            random_seed: vec![0, 1, 2],
-            is_view: false,
-            output_data_receivers: vec![],
-            epoch_height: 19,
+    // The reserve covers what it can of a debt that will not be repaid, the rest lowers the
+    // share price. Returns the part covered by the reserve
+    fn cover_bad_debt(&mut self, debt: Balance) -> Balance {
+        let covered = debt.min(self.reserve);
+        self.reserve -= covered;
+        self.bad_debt += debt;
+        let total_deposit = self.share_to_amount(self.total_share);
+        let loss = (debt - covered).min(total_deposit);
+        if loss > 0 {
+            // A share keeps a price of at least 1 so deposits can still be made
+            self.share_price = ((U256::from(self.share_price) * U256::from(total_deposit - loss)
+                / U256::from(total_deposit))
+            .as_u128())
+            .max(1);
+            self.uncovered_bad_debt += loss;
         }
+        covered
     }
 
-    #[test]
-    fn test_lending_pool() {
-        let context = get_context(String::from("bob.near"), 0, 0);
-        let deposit_amount: Balance = 1000_000_000_000;
-        let borrow_amount: Balance = 1_000_000_000;
-        testing_env!(context.clone());
-        let mut lending_pool = LendingPool {
-            pool_id: 0,
-            lending_token: String::from("test-token"),
-            interest_rate: 2000,
-            pool_supply: 0,
-            amount_borrowed: 0,
+    // Move amount of a deposit that is not used as collateral into the safety module. The stake
+    // is kept in lending tokens, it is not lowered by the share price but by slashing
+    pub fn stake(&mut self, staker_id: &AccountId, amount: Balance) {
+        self.update_pool();
+        self.settle_incentives(staker_id);
+        let mut lender = self.lenders.get(staker_id).expect("ERR_NO_LENDER");
+        assert!(!lender.is_collateral, "{}", ERR_STAKE_COLLATERAL);
+        assert!(
+            amount > 0 && amount <= self.share_to_amount(lender.share),
+            "{}",
+            ERR_NOT_ENOUGH_SHARE
+        );
+        lender.acc_reward +=
+            self.reward_per_share * lender.share / SHARE_DIVISOR - lender.reward_debt;
+        let share = self.amount_to_share_up(amount).min(lender.share);
+        lender.share -= share;
+        lender.reward_debt = self.reward_per_share * lender.share / SHARE_DIVISOR;
+        self.lenders.insert(staker_id, &lender);
+        self.total_share -= share;
+        self.checkpoint_incentives(staker_id);
+        let mut staker = self.get_staker(staker_id);
+        let stake_share = self.amount_to_stake_share(amount);
+        staker.share += stake_share;
+        self.total_stake_share += stake_share;
+        self.total_staked += amount;
+        self.save_staker(staker_id, staker);
+    }
+
+    // Start the cooldown of amount of the stake, replacing the previous request. Returns the end
+    // of the cooldown
+    pub fn request_unstake(&mut self, staker_id: &AccountId, amount: Balance) -> Timestamp {
+        let mut staker = self.stakers.get(staker_id).expect(ERR_NO_STAKE);
+        assert!(
+            amount > 0 && amount <= self.stake_share_to_amount(staker.share),
+            "{}",
+            ERR_NOT_ENOUGH_SHARE
+        );
+        // Rounded up so the stake share in cooldown is worth at least amount
+        staker.cooldown_share = self.amount_to_stake_share_up(amount).min(staker.share);
+        staker.cooldown_end = env::block_timestamp() + self.stake_cooldown;
+        self.stakers.insert(staker_id, &staker);
+        staker.cooldown_end
+    }
+
+    // Move the stake that finished its cooldown back to the deposit of the staker. Returns the
+    // amount unstaked, lower than requested if the stake was slashed in between
+    pub fn unstake(&mut self, staker_id: &AccountId) -> Balance {
+        self.update_pool();
+        self.stakers.get(staker_id).expect(ERR_NO_STAKE);
+        let mut staker = self.get_staker(staker_id);
+        assert!(
+            staker.cooldown_share > 0 && env::block_timestamp() >= staker.cooldown_end,
+            "{}",
+            ERR_COOLDOWN_NOT_FINISHED
+        );
+        let stake_share = staker.cooldown_share;
+        let amount = self.stake_share_to_amount(stake_share);
+        staker.share -= stake_share;
+        staker.cooldown_share = 0;
+        self.total_stake_share -= stake_share;
+        self.total_staked -= amount;
+        self.save_staker(staker_id, staker);
+        self.pool_supply -= amount;
+        self.deposit(staker_id.clone(), amount);
+        amount
+    }
+
+    // The reward of a staker is added to its deposit, it is already part of pool_supply
+    pub fn claim_stake_reward(&mut self, staker_id: &AccountId) -> Balance {
+        self.update_pool();
+        self.stakers.get(staker_id).expect(ERR_NO_STAKE);
+        let mut staker = self.get_staker(staker_id);
+        let reward = staker.acc_reward;
+        staker.acc_reward = 0;
+        self.save_staker(staker_id, staker);
+        if reward > 0 {
+            self.pool_supply -= reward;
+            self.deposit(staker_id.clone(), reward);
+        }
+        reward
+    }
+
+    // Take up to amount from the stakers, capped by max_slash_rate and the uncovered bad debt, and
+    // share it between the lenders. Every stake share loses the same part of its value.
+    // Returns the amount slashed
+    pub fn slash(&mut self, amount: Balance) -> Balance {
+        self.update_pool();
+        let amount = amount
+            .min(self.get_max_slash())
+            .min(self.uncovered_bad_debt);
+        if amount == 0 {
+            return 0;
+        }
+        self.total_staked -= amount;
+        self.uncovered_bad_debt -= amount;
+        self.add_reward(amount);
+        amount
+    }
+
+    // Lending tokens a staker has in the safety module
+    pub fn get_stake_amount(&self, staker_id: &AccountId) -> Balance {
+        self.stakers
+            .get(staker_id)
+            .map_or(0, |staker| self.stake_share_to_amount(staker.share))
+    }
+
+    fn stake_share_to_amount(&self, stake_share: Share) -> Balance {
+        if self.total_stake_share == 0 {
+            return 0;
+        }
+        (U256::from(stake_share) * U256::from(self.total_staked)
+            / U256::from(self.total_stake_share))
+        .as_u128()
+    }
+
+    fn amount_to_stake_share(&self, amount: Balance) -> Share {
+        if self.total_stake_share == 0 {
+            return amount;
+        }
+        (U256::from(amount) * U256::from(self.total_stake_share) / U256::from(self.total_staked))
+            .as_u128()
+    }
+
+    fn amount_to_stake_share_up(&self, amount: Balance) -> Share {
+        if self.total_stake_share == 0 {
+            return amount;
+        }
+        ((U256::from(amount) * U256::from(self.total_stake_share)
+            + U256::from(self.total_staked - 1))
+            / U256::from(self.total_staked))
+        .as_u128()
+    }
+
+    pub fn get_max_slash(&self) -> Balance {
+        (U256::from(self.total_staked) * U256::from(self.max_slash_rate)
+            / U256::from(INTEREST_DIVISOR))
+        .as_u128()
+    }
+
+    // Stake reward of a staker, including the pending part
+    pub fn get_stake_reward(&self, staker_id: &AccountId) -> Balance {
+        let staker = match self.stakers.get(staker_id) {
+            Some(staker) => staker,
+            None => return 0,
+        };
+        let mut stake_reward_per_share = self.stake_reward_per_share;
+        if self.total_share > 0 && self.total_stake_share > 0 {
+            let reserve = self.get_reserve_part(self.get_pending_total_interest());
+            stake_reward_per_share += (U256::from(self.get_stake_reward_part(reserve))
+                * U256::from(SHARE_DIVISOR)
+                / U256::from(self.total_stake_share))
+            .as_u128();
+        }
+        staker.acc_reward + stake_reward_per_share * staker.share / SHARE_DIVISOR
+            - staker.reward_debt
+    }
+
+    // Staker with its reward settled, save_staker has to be called after it changes
+    fn get_staker(&self, staker_id: &AccountId) -> StakeInfo {
+        let mut staker = self.stakers.get(staker_id).unwrap_or_default();
+        staker.acc_reward +=
+            self.stake_reward_per_share * staker.share / SHARE_DIVISOR - staker.reward_debt;
+        staker
+    }
+
+    fn save_staker(&mut self, staker_id: &AccountId, mut staker: StakeInfo) {
+        staker.reward_debt = self.stake_reward_per_share * staker.share / SHARE_DIVISOR;
+        if staker.share == 0 && staker.acc_reward == 0 {
+            self.stakers.remove(staker_id);
+        } else {
+            self.stakers.insert(staker_id, &staker);
+        }
+    }
+
+    // Start an emission schedule, returns its incentive id
+    pub fn add_incentive(&mut self, mut incentive: Incentive) -> u64 {
+        self.update_incentives();
+        incentive.reward_per_share = 0;
+        incentive.last_update = env::block_timestamp();
+        self.incentives.push(incentive);
+        self.incentives.len() as u64 - 1
+    }
+
+    // Rewards of an account by incentive id, including the pending part
+    pub fn get_incentive_rewards(&self, account_id: &AccountId) -> Vec<Balance> {
+        let rewards = self.incentive_rewards.get(account_id).unwrap_or_default();
+        self.incentives
+            .iter()
+            .enumerate()
+            .map(|(incentive_id, incentive)| {
+                let reward = rewards.get(incentive_id).cloned().unwrap_or_default();
+                let reward_per_share =
+                    incentive.get_reward_per_share(self.get_incentive_total(incentive.side));
+                reward.acc_reward
+                    + (U256::from(reward_per_share)
+                        * U256::from(self.get_incentive_weight(account_id, incentive.side))
+                        / U256::from(SHARE_DIVISOR))
+                    .as_u128()
+                    - reward.reward_debt
+            })
+            .collect()
+    }
+
+    // Remove amount from the rewards of an account before it is transferred
+    pub fn claim_incentive(&mut self, account_id: &AccountId, incentive_id: u64, amount: Balance) {
+        self.settle_incentives(account_id);
+        let mut rewards = self.incentive_rewards.get(account_id).unwrap();
+        rewards[incentive_id as usize].acc_reward -= amount;
+        self.incentive_rewards.insert(account_id, &rewards);
+    }
+
+    // Give back the rewards of a failed transfer
+    pub fn restore_incentive(
+        &mut self,
+        account_id: &AccountId,
+        incentive_id: u64,
+        amount: Balance,
+    ) {
+        let mut rewards = self.incentive_rewards.get(account_id).unwrap();
+        rewards[incentive_id as usize].acc_reward += amount;
+        self.incentive_rewards.insert(account_id, &rewards);
+    }
+
+    fn update_incentives(&mut self) {
+        for incentive_id in 0..self.incentives.len() {
+            let total = self.get_incentive_total(self.incentives[incentive_id].side);
+            let incentive = &mut self.incentives[incentive_id];
+            incentive.reward_per_share = incentive.get_reward_per_share(total);
+            incentive.last_update = env::block_timestamp();
+        }
+    }
+
+    // Add the rewards of an account before its share or principal changes
+    fn settle_incentives(&mut self, account_id: &AccountId) {
+        if self.incentives.is_empty() {
+            return;
+        }
+        self.update_incentives();
+        self.update_incentive_rewards(account_id, true);
+    }
+
+    // Count the rewards of an account from its new share or principal
+    fn checkpoint_incentives(&mut self, account_id: &AccountId) {
+        if self.incentives.is_empty() {
+            return;
+        }
+        self.update_incentive_rewards(account_id, false);
+    }
+
+    fn update_incentive_rewards(&mut self, account_id: &AccountId, settle: bool) {
+        let mut rewards = self.incentive_rewards.get(account_id).unwrap_or_default();
+        rewards.resize(self.incentives.len(), IncentiveReward::default());
+        for (incentive, reward) in self.incentives.iter().zip(rewards.iter_mut()) {
+            let accumulated = (U256::from(incentive.reward_per_share)
+                * U256::from(self.get_incentive_weight(account_id, incentive.side))
+                / U256::from(SHARE_DIVISOR))
+            .as_u128();
+            if settle {
+                reward.acc_reward += accumulated - reward.reward_debt;
+            }
+            reward.reward_debt = accumulated;
+        }
+        self.incentive_rewards.insert(account_id, &rewards);
+    }
+
+    fn get_incentive_total(&self, side: IncentiveSide) -> Balance {
+        match side {
+            IncentiveSide::Supply => self.total_share,
+            IncentiveSide::Borrow => self.amount_borrowed,
+        }
+    }
+
+    fn get_incentive_weight(&self, account_id: &AccountId, side: IncentiveSide) -> Balance {
+        match side {
+            IncentiveSide::Supply => self
+                .lenders
+                .get(account_id)
+                .map_or(0, |lender| lender.share),
+            IncentiveSide::Borrow => self
+                .borrowers
+                .get(account_id)
+                .map_or(0, |loan| loan.principal),
+        }
+    }
+
+    // Amount that can still be deposited before the supply cap is reached
+    pub fn remaining_supply_capacity(&self) -> Option<Balance> {
+        self.supply_cap
+            .map(|supply_cap| supply_cap.saturating_sub(self.share_to_amount(self.total_share)))
+    }
+
+    // Amount that can still be borrowed before the borrow cap is reached
+    pub fn remaining_borrow_capacity(&self) -> Option<Balance> {
+        self.borrow_cap
+            .map(|borrow_cap| borrow_cap.saturating_sub(self.amount_borrowed))
+    }
+
+    // Interest rate /10000 the loan pays
+    pub fn get_borrow_index(&self) -> u128 {
+        self.borrow_index
+            + self.interest_rate as u128
+                * (env::block_timestamp() - self.lastest_reward_time) as u128
+    }
+
+    // The interest of a loan accrues from now on, on its current amount
+    fn restart_interest(&self, loan: &mut Loan) {
+        loan.loan_start_time = env::block_timestamp();
+        loan.borrow_index = self.get_borrow_index();
+    }
+
+    pub fn get_loan_rate(&self, loan: &Loan) -> u64 {
+        match loan.rate_mode {
+            RateMode::Variable => self.interest_rate,
+            RateMode::Stable => loan.stable_rate,
+        }
+    }
+
+    // Rate /10000 locked by new stable loans
+    pub fn get_stable_rate(&self) -> u64 {
+        self.interest_rate + self.stable_rate_premium
+    }
+
+    // Share /10000 of the pool's tokens that is lent out
+    pub fn utilization(&self) -> u64 {
+        if self.amount_borrowed == 0 {
+            return 0;
+        }
+        (U256::from(self.amount_borrowed) * U256::from(INTEREST_DIVISOR)
+            / U256::from(self.amount_borrowed + self.pool_supply))
+        .as_u64()
+    }
+
+    pub fn amount_claimable(&self, lender_id: &AccountId) -> Balance {
+        if let Some(lender) = self.lenders.get(&lender_id) {
+            let pending_reward = self.get_pending_reward();
+            let reward_per_share = self.reward_per_share
+                + (U256::from(pending_reward) * U256::from(SHARE_DIVISOR)
+                    / U256::from(self.total_share))
+                .as_u128();
+            reward_per_share * lender.share / SHARE_DIVISOR + lender.acc_reward - lender.reward_debt
+        } else {
+            0
+        }
+    }
+
+    // Interest of the lenders, without the part of the reserve
+    pub fn get_pending_reward(&self) -> Balance {
+        let pending_interest = self.get_pending_total_interest();
+        let referral_reward: Balance = self
+            .get_pending_referral_rewards()
+            .iter()
+            .map(|(_, reward)| reward)
+            .sum();
+        pending_interest - self.get_reserve_part(pending_interest) - referral_reward
+    }
+
+    // Referral part of the pending interest of each referred loan
+    fn get_pending_referral_rewards(&self) -> Vec<(AccountId, Balance)> {
+        if self.referral_factor == 0 {
+            return vec![];
+        }
+        self.borrowers
+            .values()
+            .filter_map(|loan| {
+                let referrer_id = loan.referrer.clone()?;
+                let interest = self.get_pending_interest(&loan);
+                let reward = (U256::from(interest - self.get_reserve_part(interest))
+                    * U256::from(self.referral_factor)
+                    / U256::from(INTEREST_DIVISOR))
+                .as_u128();
+                Some((referrer_id, reward))
+            })
+            .collect()
+    }
+
+    fn get_pending_total_interest(&self) -> Balance {
+        self.borrowers.values().fold(0, |acc, borrower| {
+            acc + self.get_pending_interest(&borrower)
+        })
+    }
+
+    fn get_reserve_part(&self, interest: Balance) -> Balance {
+        (U256::from(interest) * U256::from(self.reserve_factor) / U256::from(INTEREST_DIVISOR))
+            .as_u128()
+    }
+
+    // Part of the reserve income paid to stakers, none while nothing is staked
+    fn get_stake_reward_part(&self, reserve: Balance) -> Balance {
+        if self.total_stake_share == 0 {
+            return 0;
+        }
+        (U256::from(reserve) * U256::from(self.safety_module_factor) / U256::from(INTEREST_DIVISOR))
+            .as_u128()
+    }
+
+    pub fn get_pending_interest(&self, borrower: &Loan) -> Balance {
+        (U256::from(self.get_loan_rate(borrower))
+            * U256::from(env::block_timestamp() - self.lastest_reward_time)
+            * U256::from(borrower.amount)
+            / U256::from(ONE_DAY)
+            / U256::from(365u128)
+            / U256::from(INTEREST_DIVISOR))
+        .as_u128()
+    }
+
+    pub fn get_interest(&self, borrower: &Loan) -> Balance {
+        // Interest rate /10000 times the nanoseconds it was charged for
+        let accrued_rate = match borrower.rate_mode {
+            RateMode::Variable => U256::from(self.get_borrow_index() - borrower.borrow_index),
+            RateMode::Stable => {
+                U256::from(borrower.stable_rate)
+                    * U256::from(env::block_timestamp() - borrower.loan_start_time)
+            }
+        };
+        (accrued_rate * U256::from(borrower.amount)
+            / U256::from(ONE_DAY)
+            / U256::from(365u128)
+            / U256::from(INTEREST_DIVISOR))
+        .as_u128()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::test_utils::{add_pool, new_contract, price, set_context, storage};
+    use near_sdk::MockedBlockchain;
+    use near_sdk::{testing_env, VMContext};
+    use near_sdk::{AccountId, Balance};
+
+    fn get_context(
+        _account_id: String,
+        block_timestamp: Timestamp,
+        attached_deposit: Balance,
+    ) -> VMContext {
+        VMContext {
+            current_account_id: _account_id.clone(),
+            signer_account_id: _account_id.clone(),
+            signer_account_pk: vec![0, 1, 2],
+            predecessor_account_id: _account_id,
+            input: vec![],
+            block_index: 0,
+            block_timestamp,
+            account_balance: 1_00_000_000_000_000_000_000_000_000,
+            account_locked_balance: 0,
+            storage_usage: 1000_000_000,
+            attached_deposit,
+            prepaid_gas: 10u64.pow(18),
+            random_seed: vec![0, 1, 2],
+            is_view: false,
+            output_data_receivers: vec![],
+            epoch_height: 19,
+        }
+    }
+
+    pub(crate) fn new_lending_pool(block_timestamp: Timestamp) -> LendingPool {
+        LendingPool {
+            pool_id: 0,
+            lending_token: String::from("test-token"),
+            interest_rate: 2000,
+            pool_supply: 0,
+            amount_borrowed: 0,
             borrowers: UnorderedMap::new(b"borrowers".to_vec()),
             lenders: UnorderedMap::new(b"lenders".to_vec()),
             total_share: 0,
             reward_per_share: 0,
-            lastest_reward_time: context.block_timestamp,
-        };
+            lastest_reward_time: block_timestamp,
+            borrow_index: 0,
+            paused_actions: vec![],
+            collateral_factor: 50,
+            supply_cap: None,
+            borrow_cap: None,
+            min_borrow: 0,
+            min_deposit: 0,
+            dust_threshold: 0,
+            symbol: String::from("TEST"),
+            icon: None,
+            flash_loan_fee: 9,
+            flash_loan: None,
+            stable_borrowed: 0,
+            stable_rate_premium: 200,
+            rebalance_utilization: 9_500,
+            grace_period: ONE_DAY * 7,
+            early_repayment_penalty: 100,
+            debt_ceiling: None,
+            isolated_debt: 0,
+            borrowable_in_isolation: false,
+            emode_category: None,
+            reserve_factor: 0,
+            reserve: 0,
+            bad_debt: 0,
+            stakers: UnorderedMap::new(b"stakers".to_vec()),
+            total_staked: 0,
+            total_stake_share: 0,
+            stake_reward_per_share: 0,
+            safety_module_factor: 5_000,
+            max_slash_rate: 3_000,
+            stake_cooldown: ONE_DAY * 10,
+            uncovered_bad_debt: 0,
+            share_price: SHARE_DIVISOR,
+            incentives: vec![],
+            incentive_rewards: UnorderedMap::new(b"incentive_rewards".to_vec()),
+            referral_factor: 1_000,
+            referral_rewards: UnorderedMap::new(b"referral_rewards".to_vec()),
+            origination_fee: 0,
+            liquidation_fee: 0,
+            treasury: 0,
+        }
+    }
+
+    #[test]
+    fn test_lending_pool() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        let deposit_amount: Balance = 1_000_000_000_000;
+        let borrow_amount: Balance = 1_000_000_000;
+        testing_env!(context.clone());
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
         //lender deposit at day 0
         lending_pool.deposit(String::from("lender.near"), deposit_amount);
         assert_eq!(lending_pool.pool_supply, deposit_amount, "total supply err");
@@ -257,7 +1415,7 @@ mod tests {
             .unwrap();
         assert_eq!(lender.share, deposit_amount, "err lender share");
         //bob borrowed at day 0
-        lending_pool.borrow(&String::from("bob.near"), borrow_amount);
+        lending_pool.borrow(&String::from("bob.near"), borrow_amount, None);
         assert_eq!(
             lending_pool.pool_supply,
             deposit_amount - borrow_amount,
@@ -349,7 +1507,7 @@ mod tests {
             "Err pool supply after claim"
         );
         //alice borrowed at day 20
-        lending_pool.borrow(&String::from("alice.near"), borrow_amount);
+        lending_pool.borrow(&String::from("alice.near"), borrow_amount, None);
 
         //day 30
         let context = get_context(String::from("bob.near"), ONE_DAY * 30, 0);
@@ -388,4 +1546,634 @@ mod tests {
             2 * interest / 3
         );
     }
+
+    // Pool 0 lends usdc, lender deposited 1_000 USDC
+    fn new_pool_contract() -> LendingContract {
+        let mut contract = new_contract();
+        let mut pool = add_pool(&mut contract, "usdc", 6);
+        pool.deposit("lender".to_string(), 1_000_000_000);
+        contract.pools.replace(0, &pool);
+        contract
+    }
+
+    fn deposit_transfer(contract: &mut LendingContract, amount: Balance) -> PromiseOrValue<U128> {
+        set_context("usdc", vec![]);
+        contract.ft_on_transfer(
+            ValidAccountId::try_from("alice").unwrap(),
+            U128::from(amount),
+            "{\"transfer_type\": \"Deposit\", \"token\": \"usdc\", \"pool_id\": 0}".to_string(),
+        )
+    }
+
+    // Only the part of a deposit under the supply cap is accepted, the rest is refunded
+    #[test]
+    fn test_supply_cap_refund() {
+        let mut contract = new_pool_contract();
+        contract.set_pool_caps(0, Some(U128(1_100_000_000)), None);
+        assert_eq!(
+            contract.get_pool(0).remaining_supply_capacity,
+            Some(100_000_000)
+        );
+        let refund = deposit_transfer(&mut contract, 150_000_000);
+        assert!(matches!(refund, PromiseOrValue::Value(U128(50_000_000))));
+        let pool = contract.pools.get(0).unwrap();
+        assert_eq!(
+            pool.lenders.get(&"alice".to_string()).unwrap().share,
+            100_000_000
+        );
+        assert_eq!(pool.remaining_supply_capacity(), Some(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SUPPLY_CAP_EXCEEDED")]
+    fn test_supply_cap_reached() {
+        let mut contract = new_pool_contract();
+        contract.set_pool_caps(0, Some(U128(1_000_000_000)), None);
+        deposit_transfer(&mut contract, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BORROW_CAP_EXCEEDED")]
+    fn test_borrow_cap() {
+        let mut contract = new_pool_contract();
+        contract.set_pool_caps(0, None, Some(U128(100_000_000)));
+        assert_eq!(
+            contract.pools.get(0).unwrap().remaining_borrow_capacity(),
+            Some(100_000_000)
+        );
+        set_context("lending", vec![price("1"), storage(true)]);
+        let loan = contract
+            .pools
+            .get(0)
+            .unwrap()
+            .new_loan(&"alice".to_string(), 100_000_001);
+        contract.check_borrowable("alice".to_string(), 0, loan, vec![], vec![]);
+    }
+
+    #[test]
+    fn test_repay_interest_before_principal() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let deposit_amount: Balance = 1_000_000_000_000;
+        let borrow_amount: Balance = 1_000_000_000;
+        let interest: Balance = 5_479_452; // 10 days at 20%
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.deposit(String::from("lender.near"), deposit_amount);
+        lending_pool.borrow(&String::from("bob.near"), borrow_amount, None);
+
+        // day 10, payment smaller than the interest, the rest is capitalized
+        let context = get_context(String::from("bob.near"), ONE_DAY * 10, 0);
+        testing_env!(context.clone());
+        let refund = lending_pool.repay(String::from("bob.near"), 1_000_000);
+        assert_eq!(refund, 0, "err refund");
+        let loan = lending_pool
+            .borrowers
+            .get(&String::from("bob.near"))
+            .unwrap();
+        assert_eq!(
+            loan.amount,
+            borrow_amount + interest - 1_000_000,
+            "err loan"
+        );
+        assert_eq!(loan.principal, borrow_amount, "err principal");
+        assert_eq!(
+            lending_pool.amount_borrowed, borrow_amount,
+            "err amount borrowed"
+        );
+        assert_eq!(
+            lending_pool.pool_supply,
+            deposit_amount - borrow_amount + 1_000_000,
+            "err pool supply"
+        );
+
+        // remaining interest and part of the principal
+        lending_pool.repay(String::from("bob.near"), interest - 1_000_000 + 100_000_000);
+        let loan = lending_pool
+            .borrowers
+            .get(&String::from("bob.near"))
+            .unwrap();
+        assert_eq!(loan.amount, 900_000_000, "err loan");
+        assert_eq!(loan.principal, 900_000_000, "err principal");
+        assert_eq!(
+            lending_pool.amount_borrowed, 900_000_000,
+            "err amount borrowed"
+        );
+        assert_eq!(
+            lending_pool.pool_supply,
+            deposit_amount - 900_000_000 + interest,
+            "err pool supply"
+        );
+    }
+
+    #[test]
+    fn test_repay_full_and_dust() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let deposit_amount: Balance = 1_000_000_000_000;
+        let borrow_amount: Balance = 1_000_000_000;
+        let interest: Balance = 5_479_452; // 10 days at 20%
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.dust_threshold = 1_000;
+        lending_pool.deposit(String::from("lender.near"), deposit_amount);
+        lending_pool.borrow(&String::from("bob.near"), borrow_amount, None);
+        lending_pool.borrow(&String::from("alice.near"), borrow_amount, None);
+
+        // day 10, bob overpays and gets the surplus back
+        let context = get_context(String::from("bob.near"), ONE_DAY * 10, 0);
+        testing_env!(context.clone());
+        let refund = lending_pool.repay(String::from("bob.near"), 2 * borrow_amount);
+        assert_eq!(refund, borrow_amount - interest, "err refund");
+        assert!(lending_pool
+            .borrowers
+            .get(&String::from("bob.near"))
+            .is_none());
+        assert_eq!(
+            lending_pool.amount_borrowed, borrow_amount,
+            "err amount borrowed"
+        );
+        assert_eq!(
+            lending_pool.pool_supply,
+            deposit_amount - borrow_amount + interest,
+            "err pool supply"
+        );
+
+        // alice leaves less than the dust threshold, her loan is closed out and the dust is
+        // paid by the reserve, then the treasury
+        let reserve = lending_pool.reserve;
+        lending_pool.treasury = 1_000;
+        let pool_supply = lending_pool.pool_supply;
+        let dust = reserve + 500;
+        lending_pool.repay(String::from("alice.near"), borrow_amount + interest - dust);
+        assert!(lending_pool
+            .borrowers
+            .get(&String::from("alice.near"))
+            .is_none());
+        assert_eq!(lending_pool.amount_borrowed, 0, "err amount borrowed");
+        assert_eq!(lending_pool.bad_debt, 0, "err bad debt");
+        assert_eq!(lending_pool.reserve, 0, "err reserve");
+        assert_eq!(lending_pool.treasury, 500, "err treasury");
+        assert_eq!(
+            lending_pool.pool_supply,
+            pool_supply + borrow_amount + interest - dust + 500,
+            "err pool supply"
+        );
+        assert_eq!(
+            lending_pool.get_deposit(&String::from("lender.near")),
+            deposit_amount,
+            "err deposit"
+        );
+    }
+
+    // Lenders never pay the dust, the loan stays open when the protocol cannot cover it
+    #[test]
+    fn test_dust_not_covered() {
+        testing_env!(get_context(String::from("bob.near"), 0, 0));
+        let mut lending_pool = new_lending_pool(0);
+        lending_pool.dust_threshold = 1_000;
+        lending_pool.deposit(String::from("lender.near"), 1_000_000);
+        lending_pool.borrow(&String::from("alice.near"), 100_000, None);
+        lending_pool.repay(String::from("alice.near"), 99_500);
+        assert_eq!(lending_pool.get_debt(&String::from("alice.near")), 500);
+        assert_eq!(lending_pool.bad_debt, 0);
+    }
+
+    #[test]
+    fn test_withdraw_amount() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.min_deposit = 1_000;
+        lending_pool.deposit(String::from("lender.near"), 10_000);
+        let lender = String::from("lender.near");
+        assert_eq!(lending_pool.withdraw_amount(&lender, 5_000), 5_000);
+        assert_eq!(lending_pool.withdraw_amount(&lender, 9_000), 9_000);
+        // less than the minimum deposit would be left, the whole deposit is withdrawn
+        assert_eq!(lending_pool.withdraw_amount(&lender, 9_001), 10_000);
+        lending_pool.withdraw(lender.clone(), 10_000, 0);
+        assert!(lending_pool.lenders.get(&lender).is_none());
+        assert_eq!(lending_pool.total_share, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount withdraw exceeds deposit")]
+    fn test_withdraw_amount_exceeds_deposit() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.deposit(String::from("lender.near"), 10_000);
+        lending_pool.withdraw_amount(&String::from("lender.near"), 10_001);
+    }
+
+    #[test]
+    fn test_flash_loan() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let deposit_amount: Balance = 1_000_000_000_000;
+        let flash_amount: Balance = 1_000_000_000;
+        let fee: Balance = 900_000; // 0.09%
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.deposit(String::from("lender.near"), deposit_amount);
+
+        // fully repaid, fee goes to the lender
+        let flash_fee = lending_pool.start_flash_loan(
+            String::from("bob.near"),
+            String::from("receiver.near"),
+            flash_amount,
+        );
+        assert_eq!(flash_fee, fee, "err fee");
+        let refund = lending_pool.repay_flash_loan(flash_amount + fee + 100);
+        assert_eq!(refund, 100, "err refund");
+        assert_eq!(lending_pool.finish_flash_loan(0), 0, "err shortfall");
+        assert_eq!(lending_pool.pool_supply, deposit_amount + fee);
+        assert_eq!(
+            lending_pool.amount_claimable(&String::from("lender.near")),
+            fee,
+            "err amount claimable"
+        );
+
+        // half used and not repaid, becomes a loan of bob
+        lending_pool.start_flash_loan(
+            String::from("bob.near"),
+            String::from("receiver.near"),
+            flash_amount,
+        );
+        let shortfall = lending_pool.finish_flash_loan(flash_amount / 2);
+        assert_eq!(shortfall, flash_amount / 2 + fee, "err shortfall");
+        // the unpaid fee is not credited to lenders
+        assert_eq!(
+            lending_pool.amount_claimable(&String::from("lender.near")),
+            fee,
+            "err amount claimable"
+        );
+        let loan = lending_pool
+            .borrowers
+            .get(&String::from("bob.near"))
+            .unwrap();
+        assert_eq!(loan.amount, shortfall, "err loan");
+        assert_eq!(
+            lending_pool.amount_borrowed, shortfall,
+            "err amount borrowed"
+        );
+        assert!(lending_pool.flash_loan.is_none());
+
+        // the amount is repaid with half of the fee, only that half goes to the lender
+        lending_pool.start_flash_loan(
+            String::from("bob.near"),
+            String::from("receiver.near"),
+            flash_amount,
+        );
+        lending_pool.repay_flash_loan(flash_amount + fee / 2);
+        assert_eq!(lending_pool.finish_flash_loan(0), fee / 2, "err shortfall");
+        assert_eq!(
+            lending_pool.amount_claimable(&String::from("lender.near")),
+            fee + fee / 2,
+            "err amount claimable"
+        );
+
+        // a shortfall loan pays the origination fee to the treasury like any loan
+        lending_pool.origination_fee = 100;
+        let debt = lending_pool
+            .borrowers
+            .get(&String::from("bob.near"))
+            .unwrap()
+            .amount;
+        let pool_supply = lending_pool.pool_supply;
+        lending_pool.start_flash_loan(
+            String::from("bob.near"),
+            String::from("receiver.near"),
+            flash_amount,
+        );
+        lending_pool.repay_flash_loan(flash_amount);
+        assert_eq!(
+            lending_pool.finish_flash_loan(0),
+            fee + 9_000,
+            "err shortfall"
+        );
+        assert_eq!(lending_pool.treasury, 9_000, "err treasury");
+        assert_eq!(
+            lending_pool
+                .borrowers
+                .get(&String::from("bob.near"))
+                .unwrap()
+                .amount,
+            debt + fee + 9_000,
+            "err loan"
+        );
+        assert_eq!(lending_pool.pool_supply, pool_supply - 9_000);
+        assert_eq!(
+            lending_pool.get_flash_loan_debt(flash_amount),
+            flash_amount + fee + 10_009_000
+        );
+    }
+
+    #[test]
+    fn test_update_interest_rate() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.deposit(String::from("lender.near"), 1_000_000_000_000);
+        lending_pool.borrow(&String::from("bob.near"), 1_000_000_000, None);
+
+        // day 10, rate is halved after 10 days at 20%, the loans are left as they are
+        let context = get_context(String::from("bob.near"), ONE_DAY * 10, 0);
+        testing_env!(context.clone());
+        lending_pool.update_param("interest_rate", |pool| &mut pool.interest_rate, 1000);
+        let loan = lending_pool
+            .borrowers
+            .get(&String::from("bob.near"))
+            .unwrap();
+        assert_eq!(loan.amount, 1_000_000_000, "err loan");
+        assert_eq!(loan.loan_start_time, 0, "err loan");
+        assert_eq!(lending_pool.get_interest(&loan), 5_479_452, "err interest");
+        assert_eq!(
+            lending_pool.amount_claimable(&String::from("lender.near")),
+            5_479_452,
+            "err amount claimable"
+        );
+
+        // day 20, 10 more days at 10%
+        let context = get_context(String::from("bob.near"), ONE_DAY * 20, 0);
+        testing_env!(context.clone());
+        assert_eq!(
+            lending_pool.get_interest(&loan),
+            5_479_452 + 2_739_726,
+            "err interest"
+        );
+        assert_eq!(
+            lending_pool.amount_claimable(&String::from("lender.near")),
+            5_479_452 + 2_739_726,
+            "err amount claimable"
+        );
+    }
+
+    #[test]
+    fn test_stable_rate() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.deposit(String::from("lender.near"), 1_000_000_000_000);
+        lending_pool.borrow(
+            &String::from("bob.near"),
+            1_000_000_000,
+            Some(RateMode::Stable),
+        );
+        lending_pool.borrow(&String::from("alice.near"), 1_000_000_000, None);
+        assert_eq!(lending_pool.stable_borrowed, 1_000_000_000);
+        assert_eq!(lending_pool.amount_borrowed, 2_000_000_000);
+        let loan = lending_pool
+            .borrowers
+            .get(&String::from("bob.near"))
+            .unwrap();
+        assert_eq!(loan.stable_rate, 2200, "err locked rate");
+
+        // day 10, the variable rate is halved but the stable loan keeps 22%
+        let context = get_context(String::from("bob.near"), ONE_DAY * 10, 0);
+        testing_env!(context.clone());
+        lending_pool.update_param("interest_rate", |pool| &mut pool.interest_rate, 1000);
+        let context = get_context(String::from("bob.near"), ONE_DAY * 20, 0);
+        testing_env!(context.clone());
+        assert_eq!(lending_pool.get_interest(&loan), 12_054_794, "err interest");
+
+        // A loan keeps its mode until it is swapped
+        assert_eq!(
+            lending_pool.get_rate_mode(&String::from("bob.near"), None),
+            RateMode::Stable
+        );
+        assert_eq!(
+            lending_pool.swap_rate_mode(&String::from("bob.near")),
+            RateMode::Variable
+        );
+        assert_eq!(lending_pool.stable_borrowed, 0);
+        let loan = lending_pool
+            .borrowers
+            .get(&String::from("bob.near"))
+            .unwrap();
+        assert_eq!(lending_pool.get_loan_rate(&loan), 1000, "err variable rate");
+
+        // Swapping back locks the current stable rate, rebalanced when utilization is too high
+        lending_pool.swap_rate_mode(&String::from("bob.near"));
+        lending_pool.update_param("interest_rate", |pool| &mut pool.interest_rate, 3000);
+        lending_pool.rebalance_utilization = 0;
+        assert_eq!(
+            lending_pool.rebalance_stable_rate(&String::from("bob.near")),
+            3200
+        );
+        lending_pool.repay(String::from("bob.near"), 2_000_000_000);
+        assert_eq!(lending_pool.stable_borrowed, 0);
+        assert_eq!(lending_pool.amount_borrowed, 1_000_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_RATE_MODE_MISMATCH")]
+    fn test_rate_mode_mismatch() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.deposit(String::from("lender.near"), 1_000_000_000_000);
+        lending_pool.borrow(&String::from("bob.near"), 1_000_000_000, None);
+        lending_pool.borrow(
+            &String::from("bob.near"),
+            1_000_000_000,
+            Some(RateMode::Stable),
+        );
+    }
+
+    #[test]
+    fn test_term_loan() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.deposit(String::from("lender.near"), 1_000_000_000_000);
+        let term = lending_pool
+            .get_loan_term(&String::from("bob.near"), Some(ONE_DAY * 30))
+            .unwrap();
+        assert_eq!(term.maturity, ONE_DAY * 30, "err maturity");
+        lending_pool.reserve_loan(1_000_000_000);
+        lending_pool.borrow_term(&String::from("bob.near"), 1_000_000_000, term);
+        let loan = lending_pool
+            .borrowers
+            .get(&String::from("bob.near"))
+            .unwrap();
+        assert_eq!(loan.rate_mode, RateMode::Stable);
+        assert_eq!(loan.stable_rate, 2200, "err locked rate");
+
+        // day 10, 1% penalty on the principal repaid before maturity
+        let context = get_context(String::from("bob.near"), ONE_DAY * 10, 0);
+        testing_env!(context.clone());
+        assert_eq!(
+            lending_pool.get_repay_amount(&String::from("bob.near")),
+            1_000_000_000 + 6_027_397 + 10_000_000,
+            "err repay amount"
+        );
+        lending_pool.repay(String::from("bob.near"), 106_027_397);
+        let loan = lending_pool
+            .borrowers
+            .get(&String::from("bob.near"))
+            .unwrap();
+        assert_eq!(loan.principal, 900_990_100, "err principal");
+        assert_eq!(loan.amount, 900_990_100, "err loan");
+        assert_eq!(lending_pool.amount_borrowed, 900_990_100);
+        assert_eq!(
+            lending_pool.amount_claimable(&String::from("lender.near")),
+            6_027_397 + 990_100,
+            "err amount claimable"
+        );
+
+        // No penalty after maturity, liquidatable after the grace period
+        let context = get_context(String::from("bob.near"), ONE_DAY * 37, 0);
+        testing_env!(context.clone());
+        assert_eq!(lending_pool.get_early_repayment_penalty(&loan), 0);
+        assert!(!lending_pool.is_overdue(&loan));
+        let context = get_context(String::from("bob.near"), ONE_DAY * 37 + 1, 0);
+        testing_env!(context.clone());
+        assert!(lending_pool.is_overdue(&loan));
+        let debt = lending_pool.get_debt(&String::from("bob.near"));
+        assert_eq!(lending_pool.repay(String::from("bob.near"), debt + 1), 1);
+        assert_eq!(lending_pool.stable_borrowed, 0);
+        assert_eq!(lending_pool.amount_borrowed, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TERM_LOAN")]
+    fn test_term_loan_cannot_extend() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.deposit(String::from("lender.near"), 1_000_000_000_000);
+        let term = lending_pool
+            .get_loan_term(&String::from("bob.near"), Some(ONE_DAY * 30))
+            .unwrap();
+        lending_pool.borrow_term(&String::from("bob.near"), 1_000_000_000, term);
+        lending_pool.get_loan_term(&String::from("bob.near"), None);
+    }
+
+    #[test]
+    fn test_liquidate() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.deposit(String::from("bob.near"), 1_000_000_000);
+        // Half of the 20_000_000 bonus goes to the treasury
+        lending_pool.liquidation_fee = 5_000;
+        let fee = lending_pool.get_liquidation_fee(420_000_000);
+        assert_eq!(fee, 10_000_000);
+        lending_pool.liquidate(
+            &String::from("bob.near"),
+            &String::from("liquidator.near"),
+            420_000_000,
+            fee,
+        );
+        let bob = lending_pool.lenders.get(&String::from("bob.near")).unwrap();
+        let liquidator = lending_pool
+            .lenders
+            .get(&String::from("liquidator.near"))
+            .unwrap();
+        assert_eq!(bob.share, 580_000_000);
+        assert_eq!(liquidator.share, 410_000_000);
+        assert_eq!(lending_pool.total_share, 990_000_000);
+        assert_eq!(lending_pool.pool_supply, 990_000_000);
+        assert_eq!(lending_pool.treasury, 10_000_000);
+    }
+
+    #[test]
+    fn test_write_off() {
+        let context = get_context(String::from("bob.near"), 0, 0);
+        testing_env!(context.clone());
+        let mut lending_pool = new_lending_pool(context.block_timestamp);
+        lending_pool.reserve_factor = 1_000;
+        lending_pool.deposit(String::from("alice.near"), 3_000_000_000);
+        lending_pool.deposit(String::from("carol.near"), 1_000_000_000);
+        lending_pool.borrow(&String::from("bob.near"), 1_000_000_000, None);
+
+        // After a year at 20%, 10% of the interest went to the reserve
+        let context = get_context(String::from("bob.near"), ONE_DAY * 365, 0);
+        testing_env!(context.clone());
+        assert_eq!(
+            lending_pool.write_off(&String::from("bob.near")),
+            (1_200_000_000, 20_000_000)
+        );
+        assert_eq!(lending_pool.reserve, 0);
+        assert_eq!(lending_pool.bad_debt, 1_200_000_000);
+        assert_eq!(lending_pool.amount_borrowed, 0);
+
+        // The rest of the loss lowers the share price, so the deposits in proportion. The
+        // shares and the interest stay
+        assert_eq!(lending_pool.share_price, 705_000_000_000);
+        assert_eq!(lending_pool.uncovered_bad_debt, 1_180_000_000);
+        assert_eq!(lending_pool.total_share, 4_000_000_000);
+        assert_eq!(
+            lending_pool.get_deposit(&String::from("alice.near")),
+            3_000_000_000 - 885_000_000
+        );
+        assert_eq!(
+            lending_pool.get_deposit(&String::from("carol.near")),
+            1_000_000_000 - 295_000_000
+        );
+        assert_eq!(
+            lending_pool.amount_claimable(&String::from("alice.near")),
+            135_000_000
+        );
+        assert_eq!(
+            lending_pool.amount_claimable(&String::from("carol.near")),
+            45_000_000
+        );
+
+        // New deposits get shares at the lowered price
+        lending_pool.deposit(String::from("dave.near"), 705_000_000);
+        assert_eq!(
+            lending_pool
+                .lenders
+                .get(&String::from("dave.near"))
+                .unwrap()
+                .share,
+            1_000_000_000
+        );
+        lending_pool.withdraw(String::from("carol.near"), 705_000_000, 45_000_000);
+        assert!(lending_pool
+            .lenders
+            .get(&String::from("carol.near"))
+            .is_none());
+        assert_eq!(lending_pool.total_share, 4_000_000_000);
+    }
+
+    #[test]
+    fn test_set_pool_limits() {
+        let mut contract = new_pool_contract();
+        contract.set_pool_limits(0, U128(1_000_000), U128(100_000), U128(1_000));
+        let pool = contract.pools.get(0).unwrap();
+        assert_eq!(pool.min_borrow, 1_000_000);
+        assert_eq!(pool.min_deposit, 100_000);
+        assert_eq!(pool.dust_threshold, 1_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DUST_ABOVE_MIN_BORROW")]
+    fn test_dust_above_min_borrow() {
+        let mut contract = new_pool_contract();
+        contract.set_pool_limits(0, U128(1_000), U128(0), U128(1_001));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BELOW_MIN_BORROW")]
+    fn test_below_min_borrow() {
+        let mut contract = new_pool_contract();
+        contract.set_pool_limits(0, U128(1_000_000), U128(0), U128(0));
+        contract.internal_borrow(
+            "alice".to_string(),
+            "alice".to_string(),
+            0,
+            U128(999_999),
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BELOW_MIN_DEPOSIT")]
+    fn test_below_min_deposit() {
+        let mut contract = new_pool_contract();
+        contract.set_pool_limits(0, U128(0), U128(100_000), U128(0));
+        let mut pool = contract.pools.get(0).unwrap();
+        contract.internal_deposit(&mut pool, "alice".to_string(), 99_999);
+    }
 }
@@ -0,0 +1,305 @@
+use crate::utils::{
+    SwapAction, ERR_INVALID_LEVERAGE, ERR_NO_COLLATERAL, ERR_NO_DEX, ERR_SAME_POOL,
+    ERR_WRONG_SWAP_PURPOSE, LEVERAGE_DIVISOR,
+};
+use crate::*;
+
+#[near_bindgen]
+impl LendingContract {
+    // Multiply the collateral deposited in collateral_pool_id up to leverage /100 times:
+    // borrow from borrow_pool_id, swap on the dex pool set for the two tokens and deposit the
+    // output as collateral. Looping borrow, swap and deposit ends in the same position as
+    // borrowing the whole difference at once, so it is done with a single swap and one borrow
+    // limit check
+    #[payable]
+    pub fn leverage(
+        &mut self,
+        borrow_pool_id: u64,
+        collateral_pool_id: u64,
+        leverage: u64,
+    ) -> Promise {
+        assert_one_yocto();
+        assert!(self.dex.is_some(), "{}", ERR_NO_DEX);
+        assert!(
+            leverage as u128 > LEVERAGE_DIVISOR,
+            "{}",
+            ERR_INVALID_LEVERAGE
+        );
+        assert_ne!(borrow_pool_id, collateral_pool_id, "{}", ERR_SAME_POOL);
+        let borrow_pool = self.pools.get(borrow_pool_id).expect(ERR_NO_POOL);
+        let collateral_pool = self.pools.get(collateral_pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(&borrow_pool, ActionType::Borrow);
+        self.assert_not_paused(&collateral_pool, ActionType::Deposit);
+        let account_id = env::predecessor_account_id();
+        assert!(
+            collateral_pool
+                .lenders
+                .get(&account_id)
+                .is_some_and(|lender| lender.is_collateral),
+            "{}",
+            ERR_NO_COLLATERAL
+        );
+        let dex_pool_id =
+            self.get_dex_pool_id(&borrow_pool.lending_token, &collateral_pool.lending_token);
+        let loans = self.get_all_loans(&account_id);
+        let deposits = self.get_all_deposits(&account_id);
+        let tokens = LendingContract::get_price_tokens(
+            &loans,
+            &deposits,
+            &[borrow_pool.lending_token, collateral_pool.lending_token],
+        );
+        self.fetch_prices(&tokens)
+            .then(self_contract::leverage_callback(
+                account_id,
+                leverage,
+                dex_pool_id,
+                SwapArgs {
+                    purpose: SwapPurpose::Leverage {
+                        borrow_pool_id,
+                        collateral_pool_id,
+                    },
+                    loans,
+                    deposits,
+                },
+                &env::current_account_id(),
+                0,
+                200_000_000_000_000,
+            ))
+    }
+
+    #[private]
+    pub fn leverage_callback(
+        &mut self,
+        account_id: AccountId,
+        leverage: u64,
+        dex_pool_id: u64,
+        args: SwapArgs,
+    ) -> Promise {
+        let SwapArgs {
+            purpose,
+            loans,
+            deposits,
+        } = args;
+        let (borrow_pool_id, collateral_pool_id) = match purpose {
+            SwapPurpose::Leverage {
+                borrow_pool_id,
+                collateral_pool_id,
+            } => (borrow_pool_id, collateral_pool_id),
+            _ => env::panic(ERR_WRONG_SWAP_PURPOSE.as_bytes()),
+        };
+        let mut borrow_pool = self.pools.get(borrow_pool_id).expect(ERR_NO_POOL);
+        let collateral_pool = self.pools.get(collateral_pool_id).expect(ERR_NO_POOL);
+        let tokens = LendingContract::get_price_tokens(
+            &loans,
+            &deposits,
+            &[
+                borrow_pool.lending_token.clone(),
+                collateral_pool.lending_token.clone(),
+            ],
+        );
+        let prices = LendingContract::process_prices(&tokens);
+
+        let mut deposits = deposits;
+        let deposit = LendingContract::find_deposit(&mut deposits, &collateral_pool);
+        let collateral_needed =
+            deposit.share * (leverage as u128 - LEVERAGE_DIVISOR) / LEVERAGE_DIVISOR;
+        let amount = self.convert_amount(
+            &collateral_pool.lending_token,
+            &borrow_pool.lending_token,
+            collateral_needed,
+            &prices,
+        );
+        // The origination fee is kept from the tokens that are swapped
+        let fee = borrow_pool.get_origination_fee(amount);
+        let min_amount_out = self.get_min_amount_out(
+            &borrow_pool.lending_token,
+            &collateral_pool.lending_token,
+            amount - fee,
+            &prices,
+            None,
+        );
+        borrow_pool.reserve_loan(amount);
+        self.assert_no_pending_borrow(&account_id, borrow_pool_id);
+        assert!(
+            borrow_pool.get_debt(&account_id) + amount >= borrow_pool.min_borrow,
+            "{}",
+            ERR_BELOW_MIN_BORROW
+        );
+        if let Some(remaining_supply_capacity) = collateral_pool.remaining_supply_capacity() {
+            assert!(
+                min_amount_out <= remaining_supply_capacity,
+                "{}",
+                ERR_SUPPLY_CAP_EXCEEDED
+            );
+        }
+
+        // The position is checked with the smallest output the swap accepts
+        deposit.share += min_amount_out;
+        let mut loan = borrow_pool.new_loan(&account_id, amount);
+        loan.rate_mode = borrow_pool.get_rate_mode(&account_id, None);
+        loan.term = borrow_pool.get_loan_term(&account_id, None);
+        self.assert_borrowable(&borrow_pool, &loan, loans, &deposits, &prices);
+        self.assert_debt_ceiling(
+            &deposits,
+            self.get_isolated_debt(&borrow_pool.lending_token, amount),
+        );
+        self.add_isolated_debt(&deposits, &borrow_pool.lending_token, amount);
+
+        log!(
+            "{} leverage {}x: borrow {} token from pool {} for at least {} token of pool {}",
+            account_id,
+            leverage as f64 / LEVERAGE_DIVISOR as f64,
+            amount,
+            borrow_pool_id,
+            min_amount_out,
+            collateral_pool_id
+        );
+        self.record_loan(&mut borrow_pool, &loan, fee);
+        self.pools.replace(borrow_pool_id, &borrow_pool);
+        self.internal_swap(
+            account_id,
+            SwapAction {
+                pool_id: dex_pool_id,
+                token_in: borrow_pool.lending_token,
+                amount_in: Some(U128::from(amount - fee)),
+                token_out: collateral_pool.lending_token,
+                min_amount_out: U128::from(min_amount_out),
+            },
+            purpose,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{account, add_pool, context, new_contract, price, set_context_with};
+    use crate::utils::SwapAction;
+    use crate::*;
+    use mock_amm::MockAmm;
+
+    fn set_context(
+        current_account_id: &str,
+        predecessor_account_id: &str,
+        promise_results: Vec<PromiseResult>,
+    ) {
+        set_context_with(
+            context(predecessor_account_id).current_account_id(account(current_account_id)),
+            promise_results,
+        );
+    }
+
+    // Leverage against the mock exchange, from the borrow to the deposit of the swap output
+    #[test]
+    fn test_leverage() {
+        let mut contract = new_contract();
+        contract.dex = Some("amm".to_string());
+        let mut usdc = add_pool(&mut contract, "usdc", 6);
+        usdc.origination_fee = 100;
+        usdc.deposit("lender".to_string(), 1_000_000_000);
+        contract.pools.replace(0, &usdc);
+        let mut wnear = add_pool(&mut contract, "wnear", 24);
+        wnear.deposit("alice".to_string(), 10 * 10u128.pow(24));
+        contract.pools.replace(1, &wnear);
+        contract.set_dex_pool(account("usdc"), account("wnear"), Some(0));
+
+        // 1 wNEAR for 5 USDC, the oracle price
+        set_context("amm", "owner", vec![]);
+        let mut amm = MockAmm::new();
+        amm.add_pool(
+            vec![account("wnear"), account("usdc")],
+            vec![U128::from(10u128.pow(24)), U128::from(5_000_000)],
+        );
+
+        // 1.5x borrows 25 USDC for 5 more wNEAR, 1% of it is the origination fee
+        set_context("lending", "alice", vec![]);
+        let deposits = contract.get_all_deposits(&"alice".to_string());
+        contract.leverage(0, 1, 150);
+        set_context("lending", "lending", vec![price("5"), price("1")]);
+        let purpose = SwapPurpose::Leverage {
+            borrow_pool_id: 0,
+            collateral_pool_id: 1,
+        };
+        contract.leverage_callback(
+            "alice".to_string(),
+            150,
+            0,
+            SwapArgs {
+                purpose: purpose.clone(),
+                loans: vec![],
+                deposits,
+            },
+        );
+        let usdc = contract.pools.get(0).unwrap();
+        assert_eq!(usdc.get_debt(&"alice".to_string()), 25_000_000);
+        assert_eq!(usdc.treasury, 250_000);
+
+        // The exchange receives the tokens, swaps them and sends the output back
+        set_context("amm", "usdc", vec![]);
+        let unused = amm.ft_on_transfer(account("lending"), U128::from(24_750_000), "".to_string());
+        assert!(matches!(unused, PromiseOrValue::Value(U128(0))));
+        set_context(
+            "lending",
+            "lending",
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&U128::from(24_750_000)).unwrap(),
+            )],
+        );
+        let action = SwapAction {
+            pool_id: 0,
+            token_in: "usdc".to_string(),
+            amount_in: Some(U128::from(24_750_000)),
+            token_out: "wnear".to_string(),
+            min_amount_out: U128::from(4_851 * 10u128.pow(21)),
+        };
+        contract.on_dex_deposit("alice".to_string(), action.clone(), purpose.clone());
+        set_context("amm", "lending", vec![]);
+        let amount_out = amm.swap(
+            vec![mock_amm::SwapAction {
+                pool_id: 0,
+                token_in: "usdc".to_string(),
+                amount_in: Some(U128::from(24_750_000)),
+                token_out: "wnear".to_string(),
+                min_amount_out: U128::from(4_851 * 10u128.pow(21)),
+            }],
+            None,
+        );
+        assert_eq!(amount_out, U128::from(4_950 * 10u128.pow(21)));
+        amm.withdraw(account("wnear"), amount_out, None);
+
+        set_context(
+            "lending",
+            "lending",
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&amount_out).unwrap(),
+            )],
+        );
+        contract.on_dex_swap("alice".to_string(), action, purpose.clone());
+        set_context(
+            "lending",
+            "lending",
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.on_dex_withdraw(
+            DexWithdrawal {
+                account_id: "alice".to_string(),
+                token: "wnear".to_string(),
+                amount: amount_out,
+                purpose,
+                swapped: true,
+            },
+            None,
+        );
+        assert_eq!(
+            contract
+                .pools
+                .get(1)
+                .unwrap()
+                .get_deposit(&"alice".to_string()),
+            14_950 * 10u128.pow(21)
+        );
+        assert_eq!(
+            amm.get_deposit(account("lending"), account("wnear")),
+            U128::from(0)
+        );
+    }
+}
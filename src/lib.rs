@@ -1,21 +1,57 @@
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::{LazyOption, UnorderedMap, Vector};
-use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
     assert_one_yocto, env, ext_contract, log, near_bindgen, serde_json, AccountId, Balance, Gas,
     PanicOnDefault, Promise, PromiseOrValue, PromiseResult, Timestamp,
 };
+use std::collections::HashMap;
 near_sdk::setup_alloc!();
-use lending_pool::{LenderInfo, LendingPool, Loan};
+use batch::Action;
+use dex::{DexWithdrawal, SwapArgs, SwapPurpose};
+use emode::EModeCategory;
+use governance::Proposal;
+use lending_pool::{
+    FlashLoan, Incentive, IncentiveSide, LenderInfo, LendingPool, Loan, RateMode, StakeInfo,
+};
+use liquidation::Liquidation;
 use utils::{
-    ft_contract, oracle_contract, self_contract, Response, Share, TransferPayload, TransferType,
-    BORROW_RATE_DIVISOR, ERR_BORROW_VALUE_LIMITED, ERR_NO_BORROWER, ERR_NO_POOL,
-    ERR_ORACLE_DATA_EXPIRED, MAX_BORROW_RATE, ORACLE, ORACLE_DATA_EXPIRATION, PRICE_DIVISOR,
-    PROMISE_NOT_SUCCESSFUL, U256, WRONG_FORMAT_PROMISE_RESULT,
+    ft_contract, oracle_contract, self_contract, ActionType, Response, Share, StorageBalance,
+    TransferPayload, TransferType, BORROW_RATE_DIVISOR, EARLY_REPAYMENT_PENALTY,
+    ERR_BELOW_MIN_BORROW, ERR_BELOW_MIN_DEPOSIT, ERR_BORROW_CAP_EXCEEDED, ERR_BORROW_PENDING,
+    ERR_BORROW_VALUE_LIMITED, ERR_INVALID_TOKEN, ERR_NOT_REGISTERED, ERR_NO_BORROWER,
+    ERR_NO_COLLATERAL, ERR_NO_POOL, ERR_NO_STORAGE_DEPOSIT, ERR_ORACLE_DATA_EXPIRED,
+    ERR_POOL_EXISTS, ERR_REPAY_MAX_NOT_ENOUGH, ERR_STORAGE_DEPOSIT_FAILED, ERR_SUPPLY_CAP_EXCEEDED,
+    FLASH_LOAN_FEE, GAS_FOR_PRICE, GAS_FOR_RESOLVE_EXECUTE, GRACE_PERIOD, LIQUIDATION_FEE,
+    MAX_BORROW_RATE, MAX_DECIMALS, MAX_SLASH_RATE, ORACLE, ORACLE_DATA_EXPIRATION, ORIGINATION_FEE,
+    PRICE_DIVISOR, PROMISE_NOT_SUCCESSFUL, REBALANCE_UTILIZATION, REFERRAL_FACTOR, RESERVE_FACTOR,
+    SAFETY_MODULE_FACTOR, SHARE_DIVISOR, STABLE_RATE_PREMIUM, STAKE_COOLDOWN, TIMELOCK_DELAY, U256,
+    WRONG_FORMAT_PROMISE_RESULT,
 };
+mod batch;
+mod collateral;
+mod compound;
+mod delegation;
+mod dex;
+mod emode;
+mod flash_loan;
+mod governance;
+mod incentive;
+mod isolation;
 mod lending_pool;
+mod leverage;
+mod liquidation;
+mod pause;
+mod referral;
+mod safety_module;
+mod stable_rate;
+mod term_loan;
+#[cfg(test)]
+mod test_utils;
+mod treasury;
 mod utils;
 mod view;
 
@@ -28,6 +64,27 @@ pub struct LendingContract {
     pub pools: Vector<LendingPool>,
     pub pool_count: u64,
     pub token_decimals: UnorderedMap<AccountId, u8>,
+    pub guardian: AccountId,
+    pub paused_actions: Vec<ActionType>,
+    pub oracle: AccountId,
+    pub proposals: UnorderedMap<u64, Proposal>,
+    pub proposal_count: u64,
+    pub timelock_delay: Timestamp,
+    pub dex: Option<AccountId>,
+    pub dex_pool_ids: UnorderedMap<String, u64>,
+    pub delegations: UnorderedMap<String, Balance>,
+    pub emode_categories: UnorderedMap<u64, EModeCategory>,
+    // Efficiency mode category chosen by each account
+    pub account_emodes: UnorderedMap<AccountId, u64>,
+    // Referrer recorded for each account, set once
+    pub referrers: UnorderedMap<AccountId, AccountId>,
+    // Swap tokens the exchange failed to send back, by withdrawal id
+    pub dex_withdrawals: UnorderedMap<u64, DexWithdrawal>,
+    pub dex_withdrawal_count: u64,
+    // Reward tokens funded for incentives and not yet given to a schedule
+    pub incentive_funds: UnorderedMap<AccountId, Balance>,
+    // Amount of the loans being sent, by borrower and pool
+    pub pending_borrows: UnorderedMap<String, Balance>,
 }
 
 #[near_bindgen]
@@ -36,7 +93,7 @@ impl LendingContract {
     pub fn new(owner: ValidAccountId) -> Self {
         assert!(!env::state_exists(), "Already initialized");
         Self {
-            owner: owner.into(),
+            owner: owner.clone().into(),
             metadata: LazyOption::new(
                 b"metadata".to_vec(),
                 Some(&Metadata {
@@ -51,32 +108,86 @@ impl LendingContract {
             pools: Vector::new(b"pools".to_vec()),
             pool_count: 0,
             token_decimals: UnorderedMap::new(b"token_decimals".to_vec()),
+            guardian: owner.into(),
+            paused_actions: vec![],
+            oracle: ORACLE.to_string(),
+            proposals: UnorderedMap::new(b"proposals".to_vec()),
+            proposal_count: 0,
+            timelock_delay: TIMELOCK_DELAY,
+            dex: None,
+            dex_pool_ids: UnorderedMap::new(b"dex_pool_ids".to_vec()),
+            delegations: UnorderedMap::new(b"delegations".to_vec()),
+            emode_categories: UnorderedMap::new(b"emode_categories".to_vec()),
+            account_emodes: UnorderedMap::new(b"account_emodes".to_vec()),
+            referrers: UnorderedMap::new(b"referrers".to_vec()),
+            dex_withdrawals: UnorderedMap::new(b"dex_withdrawals".to_vec()),
+            dex_withdrawal_count: 0,
+            incentive_funds: UnorderedMap::new(b"incentive_funds".to_vec()),
+            pending_borrows: UnorderedMap::new(b"pending_borrows".to_vec()),
         }
     }
 
-    //owner of contract create a new lending pool between lending token and collateral token
+    //owner of contract create a new lending pool between lending token and collateral token.
+    //Token decimals are read from the token's NEP-148 metadata before the pool is created,
+    //and the attached deposit registers the contract on the token
+    #[payable]
     pub fn create_new_lending_pool(
         &mut self,
         lending_token: ValidAccountId,
-        decimals: u8,
         interest_rate: u64, // interest rate /10000
-    ) {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.owner,
-            "Caller is not owner"
+    ) -> Promise {
+        self.assert_owner();
+        assert!(
+            self.pool_ids_by_lending_token
+                .get(lending_token.as_ref())
+                .is_none(),
+            "{}",
+            ERR_POOL_EXISTS
+        );
+        assert!(env::attached_deposit() > 0, "{}", ERR_NO_STORAGE_DEPOSIT);
+        ft_contract::ft_metadata(lending_token.as_ref(), 0, 5_000_000_000_000)
+            .and(ft_contract::storage_deposit(
+                Some(ValidAccountId::try_from(env::current_account_id()).unwrap()),
+                Some(true),
+                lending_token.as_ref(),
+                env::attached_deposit(),
+                10_000_000_000_000,
+            ))
+            .then(self_contract::create_lending_pool_callback(
+                lending_token.into(),
+                interest_rate,
+                &env::current_account_id(),
+                0,
+                10_000_000_000_000,
+            ))
+    }
+
+    #[private]
+    pub fn create_lending_pool_callback(
+        &mut self,
+        lending_token: AccountId,
+        interest_rate: u64,
+    ) -> u64 {
+        assert!(
+            self.pool_ids_by_lending_token.get(&lending_token).is_none(),
+            "{}",
+            ERR_POOL_EXISTS
         );
+        let metadata = LendingContract::process_ft_metadata(env::promise_result(0));
+        if let PromiseResult::Failed = env::promise_result(1) {
+            env::panic(ERR_STORAGE_DEPOSIT_FAILED.as_bytes());
+        }
         log!(
             "{}",
             format!(
-                "Create lending pool for token: {}, pool id: {}",
-                lending_token, self.pool_count
+                "Create lending pool for token: {} ({}, {} decimals), pool id: {}",
+                lending_token, metadata.symbol, metadata.decimals, self.pool_count
             )
         );
         let pool = LendingPool {
             pool_id: self.pool_count,
-            lending_token: lending_token.clone().into(),
-            interest_rate: interest_rate,
+            lending_token: lending_token.clone(),
+            interest_rate,
             pool_supply: 0,
             amount_borrowed: 0,
             borrowers: UnorderedMap::new(format!("{}borrowers", lending_token).as_bytes()),
@@ -84,169 +195,472 @@ impl LendingContract {
             total_share: 0,
             reward_per_share: 0,
             lastest_reward_time: env::block_timestamp(),
+            borrow_index: 0,
+            paused_actions: vec![],
+            collateral_factor: MAX_BORROW_RATE,
+            supply_cap: None,
+            borrow_cap: None,
+            min_borrow: 0,
+            min_deposit: 0,
+            dust_threshold: 0,
+            symbol: metadata.symbol,
+            icon: metadata.icon,
+            flash_loan_fee: FLASH_LOAN_FEE,
+            flash_loan: None,
+            stable_borrowed: 0,
+            stable_rate_premium: STABLE_RATE_PREMIUM,
+            rebalance_utilization: REBALANCE_UTILIZATION,
+            grace_period: GRACE_PERIOD,
+            early_repayment_penalty: EARLY_REPAYMENT_PENALTY,
+            debt_ceiling: None,
+            isolated_debt: 0,
+            borrowable_in_isolation: false,
+            emode_category: None,
+            reserve_factor: RESERVE_FACTOR,
+            reserve: 0,
+            bad_debt: 0,
+            stakers: UnorderedMap::new(format!("{}stakers", lending_token).as_bytes()),
+            total_staked: 0,
+            total_stake_share: 0,
+            stake_reward_per_share: 0,
+            safety_module_factor: SAFETY_MODULE_FACTOR,
+            max_slash_rate: MAX_SLASH_RATE,
+            stake_cooldown: STAKE_COOLDOWN,
+            uncovered_bad_debt: 0,
+            share_price: SHARE_DIVISOR,
+            incentives: vec![],
+            incentive_rewards: UnorderedMap::new(
+                format!("{}incentive_rewards", lending_token).as_bytes(),
+            ),
+            referral_factor: REFERRAL_FACTOR,
+            origination_fee: ORIGINATION_FEE,
+            liquidation_fee: LIQUIDATION_FEE,
+            treasury: 0,
+            referral_rewards: UnorderedMap::new(
+                format!("{}referral_rewards", lending_token).as_bytes(),
+            ),
         };
         self.pools.push(&pool);
         self.pool_ids_by_lending_token
-            .insert(&lending_token.to_string(), &self.pool_count);
+            .insert(&lending_token, &self.pool_count);
         self.token_decimals
-            .insert(&lending_token.to_string(), &decimals);
+            .insert(&lending_token, &metadata.decimals);
         self.pool_count += 1;
+        pool.pool_id
     }
 
-    // After deposit collateral token, borrower can borrow lending token from pool
+    // After deposit collateral token, borrower can borrow lending token from pool.
+    // Without a rate mode, new loans are variable and existing loans keep their mode
     #[payable]
-    pub fn borrow(&mut self, pool_id: u64, amount: U128) -> Promise {
-        let pool = &self.pools.get(pool_id).expect(ERR_NO_POOL);
-        assert!(
-            Balance::from(amount) <= pool.pool_supply,
-            "Dont enough token to borrow from pool"
-        );
+    pub fn borrow(
+        &mut self,
+        pool_id: u64,
+        amount: U128,
+        rate_mode: Option<RateMode>,
+        referrer: Option<ValidAccountId>,
+    ) -> Promise {
         assert_one_yocto();
-        let all_loans = self.get_all_loans(&env::predecessor_account_id());
-        let all_deposits = self.get_all_deposits(&env::predecessor_account_id());
-        if all_loans.len() > 0 {
-            let mut promises: Promise = oracle_contract::get_data_response(
-                all_loans[0].lending_token.clone(),
-                &ORACLE,
-                0,
-                5_000_000_000_000,
-            );
-            for i in 1..all_loans.len() {
-                promises = promises.and(oracle_contract::get_data_response(
-                    all_loans[i].lending_token.clone(),
-                    &ORACLE,
-                    0,
-                    5_000_000_000_000,
-                ));
-            }
-            for i in 0..all_deposits.len() {
-                promises = promises.and(oracle_contract::get_data_response(
-                    all_deposits[i].lending_token.clone(),
-                    &ORACLE,
-                    0,
-                    5_000_000_000_000,
-                ));
-            }
-            promises
-                .and(oracle_contract::get_data_response(
-                    pool.lending_token.clone(),
-                    &ORACLE,
-                    0,
-                    5_000_000_000_000,
-                ))
-                .then(self_contract::check_borrowable(
-                    env::predecessor_account_id(),
-                    pool_id,
-                    amount,
-                    all_loans,
-                    all_deposits,
-                    &env::current_account_id(),
-                    0,
-                    100_000_000_000_000,
-                ))
-        } else {
-            let mut promises: Promise = oracle_contract::get_data_response(
-                all_deposits[0].lending_token.clone(),
-                &ORACLE,
-                0,
-                5_000_000_000_000,
-            );
-            for i in 1..all_deposits.len() {
-                promises = promises.and(oracle_contract::get_data_response(
-                    all_deposits[i].lending_token.clone(),
-                    &ORACLE,
-                    0,
-                    5_000_000_000_000,
-                ));
-            }
-            promises
-                .and(oracle_contract::get_data_response(
-                    pool.lending_token.clone(),
-                    &ORACLE,
-                    0,
-                    5_000_000_000_000,
-                ))
-                .then(self_contract::check_borrowable(
-                    env::predecessor_account_id(),
-                    pool_id,
-                    amount,
-                    all_loans,
-                    all_deposits,
-                    &env::current_account_id(),
-                    0,
-                    100_000_000_000_000,
-                ))
-        }
+        self.internal_set_referrer(&env::predecessor_account_id(), referrer.map(|r| r.into()));
+        self.internal_borrow(
+            env::predecessor_account_id(),
+            env::predecessor_account_id(),
+            pool_id,
+            amount,
+            rate_mode,
+            None,
+        )
     }
 
+    // The new loan is checked against the positions of its borrower and the tokens go to receiver_id
     #[private]
     pub fn check_borrowable(
         &mut self,
-        borrower_id: AccountId,
+        receiver_id: AccountId,
         pool_id: u64,
-        amount: U128,
+        loan: Loan,
         loans: Vec<Loan>,
         deposits: Vec<LenderInfo>,
     ) -> Promise {
-        let loans_len = loans.len() as u64;
-        let deposits_len = deposits.len() as u64;
-        let mut loan_value: u128 = 0;
-        let mut deposit_value: u128 = 0;
-        for i in 0..loans_len {
-            let price = LendingContract::process_data_response_get_price(env::promise_result(
-                env::promise_results_count() - loans_len - deposits_len - 1 + i,
-            ));
-            let decimals = self
-                .token_decimals
-                .get(&loans[i as usize].lending_token)
-                .unwrap() as u32;
-            loan_value +=
-                loans[i as usize].amount * price / PRICE_DIVISOR as u128 / 10u128.pow(decimals);
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        let tokens = LendingContract::get_price_tokens(
+            &loans,
+            &deposits,
+            std::slice::from_ref(&pool.lending_token),
+        );
+        let prices = LendingContract::process_prices(&tokens);
+        LendingContract::assert_storage_registered(env::promise_result(tokens.len() as u64));
+        self.assert_borrowable(&pool, &loan, loans, &deposits, &prices);
+        self.assert_debt_ceiling(
+            &deposits,
+            self.get_isolated_debt(&pool.lending_token, loan.amount),
+        );
+        if receiver_id != loan.borrower {
+            self.use_delegation(&loan.borrower, &receiver_id, pool_id, loan.amount);
         }
+        // The loan may have changed since the borrow, recording it must not fail after the transfer
+        let mut loan = loan;
+        loan.rate_mode = pool.get_rate_mode(&loan.borrower, Some(loan.rate_mode));
+        pool.assert_loan_term(&loan.borrower, loan.term.is_some());
+        pool.reserve_loan(loan.amount);
+        self.pools.replace(pool_id, &pool);
+        self.add_isolated_debt(&deposits, &pool.lending_token, loan.amount);
+        self.transfer_loan(&pool, receiver_id, loan)
+    }
 
-        for i in 0..deposits_len {
-            let price = LendingContract::process_data_response_get_price(env::promise_result(
-                env::promise_results_count() - deposits_len - 1 + i,
-            ));
-            let decimals = self
-                .token_decimals
-                .get(&deposits[i as usize].lending_token)
-                .unwrap() as u32;
-            deposit_value +=
-                deposits[i as usize].share * price / PRICE_DIVISOR as u128 / 10u128.pow(decimals);
+    // A new loan stays under the borrow cap of its pool and the borrow limit of its borrower
+    fn assert_borrowable(
+        &self,
+        pool: &LendingPool,
+        loan: &Loan,
+        loans: Vec<Loan>,
+        deposits: &[LenderInfo],
+        prices: &HashMap<AccountId, Balance>,
+    ) {
+        if let Some(remaining_borrow_capacity) = pool.remaining_borrow_capacity() {
+            assert!(
+                loan.amount <= remaining_borrow_capacity,
+                "{}",
+                ERR_BORROW_CAP_EXCEEDED
+            );
         }
-        let pool = &self.pools.get(pool_id).expect(ERR_NO_POOL);
-
-        let price = LendingContract::process_data_response_get_price(env::promise_result(
-            env::promise_results_count() - 1,
-        ));
-        let decimals = self.token_decimals.get(&pool.lending_token).unwrap() as u32;
-        loan_value += u128::from(amount) * price / PRICE_DIVISOR as u128 / 10u128.pow(decimals);
+        let mut loans = loans;
+        loans.push(loan.clone());
+        self.assert_borrow_limit(&loan.borrower, &loans, deposits, prices);
+    }
 
+    // Fetch the prices of the borrower's positions and check the storage of the receiver,
+    // a term is the duration of a term loan
+    fn internal_borrow(
+        &mut self,
+        borrower_id: AccountId,
+        receiver_id: AccountId,
+        pool_id: u64,
+        amount: U128,
+        rate_mode: Option<RateMode>,
+        term: Option<Timestamp>,
+    ) -> Promise {
+        let pool = &self.pools.get(pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(pool, ActionType::Borrow);
+        self.assert_no_pending_borrow(&borrower_id, pool_id);
         assert!(
-            loan_value <= MAX_BORROW_RATE * deposit_value / BORROW_RATE_DIVISOR,
+            Balance::from(amount) <= pool.pool_supply,
+            "Dont enough token to borrow from pool"
+        );
+        let debt = pool
+            .borrowers
+            .get(&borrower_id)
+            .map_or(0, |loan| loan.amount);
+        assert!(
+            debt + Balance::from(amount) >= pool.min_borrow,
             "{}",
-            ERR_BORROW_VALUE_LIMITED
+            ERR_BELOW_MIN_BORROW
         );
+        let mut loan = pool.new_loan(&borrower_id, amount.into());
+        loan.rate_mode = pool.get_rate_mode(&borrower_id, rate_mode);
+        loan.term = pool.get_loan_term(&borrower_id, term);
+        let all_loans = self.get_all_loans(&borrower_id);
+        let all_deposits = self.get_all_deposits(&borrower_id);
+        let tokens = LendingContract::get_price_tokens(
+            &all_loans,
+            &all_deposits,
+            std::slice::from_ref(&pool.lending_token),
+        );
+        self.fetch_prices(&tokens)
+            // Borrowed tokens are lost if the receiver is not registered on the lending token
+            .and(ft_contract::storage_balance_of(
+                ValidAccountId::try_from(receiver_id.clone()).unwrap(),
+                &pool.lending_token,
+                0,
+                5_000_000_000_000,
+            ))
+            .then(self_contract::check_borrowable(
+                receiver_id,
+                pool_id,
+                loan,
+                all_loans,
+                all_deposits,
+                &env::current_account_id(),
+                0,
+                100_000_000_000_000,
+            ))
+    }
 
+    // Every token whose price is needed to value the positions, without duplicates
+    fn get_price_tokens(
+        loans: &[Loan],
+        deposits: &[LenderInfo],
+        extra_tokens: &[AccountId],
+    ) -> Vec<AccountId> {
+        let mut tokens: Vec<AccountId> = vec![];
+        for token in loans
+            .iter()
+            .map(|loan| &loan.lending_token)
+            .chain(deposits.iter().map(|deposit| &deposit.lending_token))
+            .chain(extra_tokens.iter())
+        {
+            if !tokens.contains(token) {
+                tokens.push(token.clone());
+            }
+        }
+        tokens
+    }
+
+    // Oracle requests for every token, the callback reads the prices in the same order
+    fn fetch_prices(&self, tokens: &[AccountId]) -> Promise {
+        tokens.iter().skip(1).fold(
+            oracle_contract::get_data_response(tokens[0].clone(), &self.oracle, 0, GAS_FOR_PRICE),
+            |promises, token| {
+                promises.and(oracle_contract::get_data_response(
+                    token.clone(),
+                    &self.oracle,
+                    0,
+                    GAS_FOR_PRICE,
+                ))
+            },
+        )
+    }
+
+    fn process_prices(tokens: &[AccountId]) -> HashMap<AccountId, Balance> {
+        tokens
+            .iter()
+            .enumerate()
+            .map(|(i, token)| {
+                (
+                    token.clone(),
+                    LendingContract::process_data_response_get_price(env::promise_result(i as u64)),
+                )
+            })
+            .collect()
+    }
+
+    fn get_value(
+        &self,
+        token: &AccountId,
+        amount: Balance,
+        prices: &HashMap<AccountId, Balance>,
+    ) -> u128 {
+        let decimals = self.token_decimals.get(token).unwrap() as u32;
+        amount * prices[token] / PRICE_DIVISOR as u128 / 10u128.pow(decimals)
+    }
+
+    // Loans must stay within the value of the collateral weighted by the pools' collateral factor
+    fn assert_borrow_limit(
+        &self,
+        account_id: &AccountId,
+        loans: &[Loan],
+        deposits: &[LenderInfo],
+        prices: &HashMap<AccountId, Balance>,
+    ) {
+        self.assert_isolation(loans, deposits);
+        let (loan_value, deposit_value) =
+            self.get_position_value(account_id, loans, deposits, prices);
+        assert!(loan_value <= deposit_value, "{}", ERR_BORROW_VALUE_LIMITED);
+    }
+
+    // Value of the loans, and borrow limit of the collateral
+    fn get_position_value(
+        &self,
+        account_id: &AccountId,
+        loans: &[Loan],
+        deposits: &[LenderInfo],
+        prices: &HashMap<AccountId, Balance>,
+    ) -> (u128, u128) {
+        let loan_value: u128 = loans
+            .iter()
+            .map(|loan| self.get_value(&loan.lending_token, loan.amount, prices))
+            .sum();
+        let emode_category = self.get_emode_category(account_id, loans);
+        let deposit_value: u128 = deposits
+            .iter()
+            .filter(|deposit| deposit.is_collateral)
+            .map(|deposit| {
+                self.get_value(&deposit.lending_token, deposit.share, prices)
+                    * self.get_collateral_factor(
+                        &self.get_pool_by_token(&deposit.lending_token),
+                        &emode_category,
+                    )
+                    / BORROW_RATE_DIVISOR
+            })
+            .sum();
+        (loan_value, deposit_value)
+    }
+
+    // Send the loan less the origination fee to receiver_id, the pool records it once the
+    // transfer succeeded. Other loans of the borrower from the pool wait until then
+    fn transfer_loan(&mut self, pool: &LendingPool, receiver_id: AccountId, loan: Loan) -> Promise {
+        let key = LendingContract::pending_borrow_key(&loan.borrower, pool.pool_id);
+        self.assert_no_pending_borrow(&loan.borrower, pool.pool_id);
+        self.pending_borrows.insert(&key, &loan.amount);
+        let fee = pool.get_origination_fee(loan.amount);
         ft_contract::ft_transfer(
-            ValidAccountId::try_from(borrower_id.clone()).unwrap(),
-            amount,
+            ValidAccountId::try_from(receiver_id.clone()).unwrap(),
+            U128::from(loan.amount - fee),
             None,
             &pool.lending_token,
             1,
             15_000_000_000_000,
         )
         .then(self_contract::update_borrower(
-            pool_id,
-            borrower_id,
-            amount,
+            pool.pool_id,
+            loan,
+            U128::from(fee),
+            receiver_id,
             &env::current_account_id(),
             0,
             15_000_000_000_000,
         ))
     }
 
+    fn pending_borrow_key(borrower_id: &AccountId, pool_id: u64) -> String {
+        format!("{}:{}", borrower_id, pool_id)
+    }
+
+    fn assert_no_pending_borrow(&self, borrower_id: &AccountId, pool_id: u64) {
+        assert!(
+            self.pending_borrows
+                .get(&LendingContract::pending_borrow_key(borrower_id, pool_id))
+                .is_none(),
+            "{}",
+            ERR_BORROW_PENDING
+        );
+    }
+
+    // Record a loan whose tokens were reserved and sent, with its referrer and origination fee
+    fn record_loan(&self, pool: &mut LendingPool, loan: &Loan, fee: Balance) {
+        log!(
+            "{} borrowed {} token from pool {}",
+            loan.borrower,
+            loan.amount,
+            pool.pool_id
+        );
+        match loan.term.clone() {
+            Some(term) => {
+                log!(
+                    "Term loan of {} matures at {}",
+                    loan.borrower,
+                    term.maturity
+                );
+                pool.borrow_term(&loan.borrower, loan.amount, term)
+            }
+            None => pool.add_loan(&loan.borrower, loan.amount, Some(loan.rate_mode)),
+        }
+        pool.set_referrer(&loan.borrower, self.referrers.get(&loan.borrower));
+        if fee > 0 {
+            pool.treasury += fee;
+            log!(
+                "Origination fee of {} Yocto {} from {} to the treasury",
+                fee,
+                pool.lending_token,
+                loan.borrower
+            );
+        }
+    }
+
+    fn transfer_claim(&self, pool: &LendingPool, lender_id: AccountId) -> Promise {
+        let amount_claimable = pool.amount_claimable(&lender_id);
+        ft_contract::ft_transfer(
+            ValidAccountId::try_from(lender_id.clone()).unwrap(),
+            U128::from(amount_claimable),
+            None,
+            &pool.lending_token,
+            1,
+            10_000_000_000_000,
+        )
+        .then(self_contract::check_claim_success(
+            pool.pool_id,
+            lender_id,
+            &env::current_account_id(),
+            0,
+            10_000_000_000_000,
+        ))
+    }
+
+    fn transfer_withdrawal(
+        &self,
+        pool: &LendingPool,
+        lender_id: AccountId,
+        amount: Balance,
+    ) -> Promise {
+        let interest = pool.amount_claimable(&lender_id);
+        ft_contract::ft_transfer(
+            ValidAccountId::try_from(lender_id.clone()).unwrap(),
+            U128::from(amount + interest),
+            None,
+            &pool.lending_token,
+            1,
+            10_000_000_000_000,
+        )
+        .then(self_contract::check_withdraw_success(
+            pool.pool_id,
+            lender_id,
+            U128::from(amount),
+            U128::from(interest),
+            &env::current_account_id(),
+            0,
+            10_000_000_000_000,
+        ))
+    }
+
+    // Add a deposit to the pool, returns the part above the supply cap to refund
+    fn internal_deposit(
+        &self,
+        pool: &mut LendingPool,
+        lender_id: AccountId,
+        amount: Balance,
+    ) -> Balance {
+        self.assert_not_paused(pool, ActionType::Deposit);
+        // Only accept up to the supply cap, the excess is refunded
+        let accepted = pool
+            .remaining_supply_capacity()
+            .map_or(amount, |capacity| amount.min(capacity));
+        assert!(accepted > 0, "{}", ERR_SUPPLY_CAP_EXCEEDED);
+        assert!(
+            pool.get_deposit(&lender_id) + accepted >= pool.min_deposit,
+            "{}",
+            ERR_BELOW_MIN_DEPOSIT
+        );
+        // update info of lender in pool
+        pool.deposit(lender_id, accepted);
+        amount - accepted
+    }
+
+    // Repays are never blocked by the deposit checks: what is left of them is deposited when it
+    // can be, otherwise refunded
+    fn deposit_remaining(
+        &self,
+        pool: &mut LendingPool,
+        lender_id: AccountId,
+        amount: Balance,
+    ) -> Balance {
+        let accepted = pool
+            .remaining_supply_capacity()
+            .map_or(amount, |capacity| amount.min(capacity));
+        if self.is_paused(pool, ActionType::Deposit)
+            || accepted == 0
+            || pool.get_deposit(&lender_id) + accepted < pool.min_deposit
+        {
+            log!(
+                "{} refunded {} Yocto {}",
+                lender_id,
+                amount,
+                pool.lending_token
+            );
+            return amount;
+        }
+        self.log_deposit(pool, &lender_id, amount);
+        self.internal_deposit(pool, lender_id, amount)
+    }
+
+    fn log_deposit(&self, pool: &LendingPool, lender_id: &AccountId, amount: Balance) {
+        log!(
+            "{} deposited {} Yocto {} to pool {}",
+            lender_id,
+            amount,
+            pool.lending_token,
+            pool.pool_id
+        );
+    }
+
     fn process_data_response_get_price(promise_result: PromiseResult) -> Balance {
         if let PromiseResult::Successful(result) = promise_result {
             if let Ok(response) = near_sdk::serde_json::from_slice::<Response>(&result) {
@@ -264,12 +678,51 @@ impl LendingContract {
         }
     }
 
+    fn process_ft_metadata(promise_result: PromiseResult) -> FungibleTokenMetadata {
+        if let PromiseResult::Successful(result) = promise_result {
+            if let Ok(metadata) = serde_json::from_slice::<FungibleTokenMetadata>(&result) {
+                // Valuations use 10^decimals in u128
+                assert!(
+                    metadata.spec.starts_with("ft-") && metadata.decimals <= MAX_DECIMALS,
+                    "{}",
+                    ERR_INVALID_TOKEN
+                );
+                metadata
+            } else {
+                env::panic(ERR_INVALID_TOKEN.as_bytes());
+            }
+        } else {
+            env::panic(ERR_INVALID_TOKEN.as_bytes());
+        }
+    }
+
+    fn assert_storage_registered(promise_result: PromiseResult) {
+        if let PromiseResult::Successful(result) = promise_result {
+            if let Ok(storage_balance) = serde_json::from_slice::<Option<StorageBalance>>(&result) {
+                assert!(storage_balance.is_some(), "{}", ERR_NOT_REGISTERED);
+            } else {
+                env::panic(WRONG_FORMAT_PROMISE_RESULT);
+            }
+        } else {
+            env::panic(PROMISE_NOT_SUCCESSFUL);
+        }
+    }
+
+    fn get_pool_by_token(&self, lending_token: &AccountId) -> LendingPool {
+        let pool_id = self
+            .pool_ids_by_lending_token
+            .get(lending_token)
+            .expect(ERR_NO_POOL);
+        self.pools.get(pool_id).expect(ERR_NO_POOL)
+    }
+
     fn get_all_deposits(&self, user: &AccountId) -> Vec<LenderInfo> {
         self.pools
             .iter()
             .filter_map(|pool| {
+                // Deposits are valued in lending tokens, with their interest
                 if let Some(mut deposit) = pool.lenders.get(user) {
-                    deposit.share += pool.amount_claimable(user);
+                    deposit.share = pool.get_deposit(user) + pool.amount_claimable(user);
                     Some(deposit)
                 } else {
                     None
@@ -292,36 +745,42 @@ impl LendingContract {
             .collect()
     }
 
-    // Update pool information after transfer lending token to borrower
+    // Update pool information after transfer lending token to borrower, when the transfer failed
+    // the reserved tokens go back to the pool, the isolated debt is released and a loan sent to a
+    // delegatee gives back the delegation
     #[private]
-    pub fn update_borrower(&mut self, pool_id: u64, borrower: &AccountId, amount: U128) -> bool {
-        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
-
-        log!(
-            "{}",
-            format!(
-                "{} borrowed {} token from pool {}",
-                borrower,
-                Balance::from(amount),
-                pool_id
-            )
-        );
-        pool.borrow(borrower, Balance::from(amount));
-        self.pools.replace(pool_id, &pool);
-        return true;
-    }
-
-    // Liquidator transfer lending token to liquidate asset of borrower
-    #[private]
-    pub fn liquidate(
+    pub fn update_borrower(
         &mut self,
-        liquidator: AccountId,
         pool_id: u64,
-        amount: Balance,
-        borrower_id: AccountId,
-    ) {
-        let pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
-        // get Ref finance pool to calculate amount collateral token out
+        loan: Loan,
+        fee: U128,
+        receiver_id: AccountId,
+    ) -> bool {
+        self.pending_borrows
+            .remove(&LendingContract::pending_borrow_key(
+                &loan.borrower,
+                pool_id,
+            ));
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            log!(
+                "Transfer of the loan of {} from pool {} failed",
+                loan.borrower,
+                pool_id
+            );
+            if receiver_id != loan.borrower {
+                self.restore_delegation(&loan.borrower, &receiver_id, pool_id, loan.amount);
+            }
+            let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+            pool.pool_supply += loan.amount;
+            self.pools.replace(pool_id, &pool);
+            let deposits = self.get_all_deposits(&loan.borrower);
+            self.remove_isolated_debt(&deposits, &pool.lending_token, loan.amount);
+            return false;
+        }
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        self.record_loan(&mut pool, &loan, fee.into());
+        self.pools.replace(pool_id, &pool);
+        true
     }
 
     // Claim reward of lender
@@ -334,53 +793,25 @@ impl LendingContract {
             self.get_amount_claimable(pool_id, env::predecessor_account_id())
         );
         let pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
-        let amount_claimable = pool.amount_claimable(&env::predecessor_account_id());
-        ft_contract::ft_transfer(
-            ValidAccountId::try_from(env::predecessor_account_id()).unwrap(),
-            U128::from(amount_claimable),
-            None,
-            &pool.lending_token,
-            1,
-            10_000_000_000_000,
-        )
-        .then(self_contract::check_claim_success(
-            pool_id,
-            env::predecessor_account_id(),
-            &env::current_account_id(),
-            0,
-            10_000_000_000_000,
-        ))
+        self.assert_not_paused(&pool, ActionType::Claim);
+        self.transfer_claim(&pool, env::predecessor_account_id())
     }
 
     // Withdraw reward of lender, amount return = amount want to withdraw + reward
     #[payable]
     pub fn withdraw(&mut self, pool_id: u64, amount: U128) -> Promise {
         assert_one_yocto();
+        let pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(&pool, ActionType::Withdraw);
+        let amount =
+            U128::from(pool.withdraw_amount(&env::predecessor_account_id(), amount.into()));
         log!(
             "{} withdraw {} token with interest {}",
             env::predecessor_account_id(),
             Balance::from(amount),
             self.get_amount_claimable(pool_id, env::predecessor_account_id())
         );
-        let pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
-        let interest = pool.amount_claimable(&env::predecessor_account_id());
-        ft_contract::ft_transfer(
-            ValidAccountId::try_from(env::predecessor_account_id()).unwrap(),
-            U128::from(u128::from(amount) + interest),
-            None,
-            &pool.lending_token,
-            1,
-            10_000_000_000_000,
-        )
-        .then(self_contract::check_withdraw_success(
-            pool_id,
-            env::predecessor_account_id(),
-            U128::from(amount),
-            U128::from(interest),
-            &env::current_account_id(),
-            0,
-            10_000_000_000_000,
-        ))
+        self.transfer_withdrawal(&pool, env::predecessor_account_id(), amount.into())
     }
 
     // Update pool information after claim reward
@@ -422,6 +853,13 @@ impl FungibleTokenReceiver for LendingContract {
         msg: String,
     ) -> PromiseOrValue<U128> {
         let transfer_payload = serde_json::from_str::<TransferPayload>(&msg).expect("Wrong format");
+        // Only the transfers that open a position of the sender record its referrer
+        if matches!(
+            transfer_payload.transfer_type,
+            TransferType::Deposit | TransferType::Execute
+        ) {
+            self.internal_set_referrer(sender_id.as_ref(), transfer_payload.referrer.clone());
+        }
         match transfer_payload.transfer_type {
             //Transfer token to provide liqudity for pool
             TransferType::Deposit => {
@@ -438,10 +876,9 @@ impl FungibleTokenReceiver for LendingContract {
                     pool_id
                 );
                 let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
-                // update info of lender in pool
-                pool.deposit(sender_id.into(), amount.into());
+                let refund = self.internal_deposit(&mut pool, sender_id.into(), amount.into());
                 self.pools.replace(pool_id, &pool);
-                PromiseOrValue::Value(U128::from(0))
+                PromiseOrValue::Value(U128::from(refund))
             }
             // Borrower transfer token to pay the loan, amount require atleast greater than interest
             TransferType::Repay => {
@@ -450,36 +887,147 @@ impl FungibleTokenReceiver for LendingContract {
                     .get(&env::predecessor_account_id()) // Lending token
                     .expect(ERR_NO_POOL);
                 assert_eq!(pool_id, transfer_payload.pool_id, "pool id: not good");
+                // Anyone can repay the loan of another borrower
+                let borrower_id = transfer_payload
+                    .borrower_id
+                    .unwrap_or_else(|| sender_id.to_string());
                 log!(
-                    "{} repayed {} Yocto {} to pool {}",
+                    "{} repayed {} Yocto {} to pool {} for {}",
                     sender_id,
                     Balance::from(amount),
                     env::predecessor_account_id(),
-                    pool_id
+                    pool_id,
+                    borrower_id
                 );
                 let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                self.assert_not_paused(&pool, ActionType::Repay);
+                if transfer_payload.repay_max.unwrap_or(false) {
+                    assert!(
+                        Balance::from(amount) >= pool.get_repay_amount(&borrower_id),
+                        "{}",
+                        ERR_REPAY_MAX_NOT_ENOUGH
+                    );
+                }
                 // Update borrower info, if amount deposit > total amount neccesary, refund remain amount
-                let refund = pool.repay(sender_id.into(), amount.into());
+                let refund = self.internal_repay(&mut pool, borrower_id, amount.into());
                 self.pools.replace(pool_id, &pool);
                 PromiseOrValue::Value(U128::from(refund))
             }
-            // When price decreases to lower than liquidation threshold,
+            // When price decreases to lower than liquidation threshold, or a term loan is overdue,
             // other user can become liquidator to liquidate asset of borrower.
             // Transfer lending token to liquidate borrower's asset and get 5% more as Liquidator incentive
             TransferType::Liquidate => {
-                let borrower = transfer_payload.borrower_id.expect(ERR_NO_BORROWER);
+                let borrower_id = transfer_payload.borrower_id.expect(ERR_NO_BORROWER);
                 let pool_id = self
                     .pool_ids_by_lending_token
                     .get(&env::predecessor_account_id())
                     .expect(ERR_NO_POOL);
                 assert_eq!(pool_id, transfer_payload.pool_id, "pool id: not good");
                 let pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
-                // User ref finance as a source of pricing information,
-                // collateral token- wnear, lending token - wnear.
-                // Because Near have not had an oracle yet.
+                self.assert_not_paused(&pool, ActionType::Liquidate);
+                PromiseOrValue::Promise(
+                    self.internal_liquidate(Liquidation {
+                        liquidator: sender_id.into(),
+                        borrower_id,
+                        pool_id,
+                        collateral_pool_id: transfer_payload
+                            .collateral_pool_id
+                            .expect(ERR_NO_COLLATERAL),
+                        amount,
+                    }),
+                )
+            }
+            // Repay from the transferred tokens and deposit the rest, then run the other actions
+            TransferType::Execute => {
+                let pool_id = self
+                    .pool_ids_by_lending_token
+                    .get(&env::predecessor_account_id())
+                    .expect(ERR_NO_POOL);
+                assert_eq!(pool_id, transfer_payload.pool_id, "pool id: not good");
+                let sender_id: AccountId = sender_id.into();
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                let mut remaining = Balance::from(amount);
+                let mut repaid = false;
+                let mut actions = vec![];
+                for action in transfer_payload.actions.unwrap_or_default() {
+                    if let Action::Repay {
+                        pool_id: repay_pool_id,
+                        amount: repay_amount,
+                    } = action
+                    {
+                        assert_eq!(repay_pool_id, pool_id, "pool id: not good");
+                        self.assert_not_paused(&pool, ActionType::Repay);
+                        let repay_amount =
+                            repay_amount.map_or(remaining, |repay_amount| repay_amount.into());
+                        assert!(repay_amount <= remaining, "Not enough token to repay");
+                        log!(
+                            "{} repayed {} Yocto {} to pool {}",
+                            sender_id,
+                            repay_amount,
+                            pool.lending_token,
+                            pool_id
+                        );
+                        remaining -= repay_amount
+                            - self.internal_repay(&mut pool, sender_id.clone(), repay_amount);
+                        repaid = true;
+                    } else {
+                        actions.push(action);
+                    }
+                }
+                let mut refund = 0;
+                if remaining > 0 {
+                    refund = if repaid {
+                        self.deposit_remaining(&mut pool, sender_id.clone(), remaining)
+                    } else {
+                        self.log_deposit(&pool, &sender_id, remaining);
+                        self.internal_deposit(&mut pool, sender_id.clone(), remaining)
+                    };
+                }
+                self.pools.replace(pool_id, &pool);
+                if actions.is_empty() {
+                    return PromiseOrValue::Value(U128::from(refund));
+                }
+                // The token contract reads the refund from the last promise, which does not
+                // depend on the result of the actions
+                PromiseOrValue::Promise(self.internal_execute(sender_id, actions).then(
+                    self_contract::resolve_execute(
+                        U128::from(refund),
+                        &env::current_account_id(),
+                        0,
+                        GAS_FOR_RESOLVE_EXECUTE,
+                    ),
+                ))
+            }
+            // Flash loan receiver sends back the borrowed amount plus fee
+            TransferType::FlashLoanRepay => {
+                let pool_id = self
+                    .pool_ids_by_lending_token
+                    .get(&env::predecessor_account_id())
+                    .expect(ERR_NO_POOL);
+                assert_eq!(pool_id, transfer_payload.pool_id, "pool id: not good");
+                log!(
+                    "{} repayed {} Yocto {} of flash loan to pool {}",
+                    sender_id,
+                    Balance::from(amount),
+                    env::predecessor_account_id(),
+                    pool_id
+                );
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                let refund = pool.repay_flash_loan(amount.into());
+                self.pools.replace(pool_id, &pool);
+                PromiseOrValue::Value(U128::from(refund))
+            }
+            TransferType::FundIncentive => {
+                let reward_token = env::predecessor_account_id();
+                let funds = self.incentive_funds.get(&reward_token).unwrap_or(0) + amount.0;
+                self.incentive_funds.insert(&reward_token, &funds);
+                log!(
+                    "{} funded {} Yocto {} of incentives",
+                    sender_id,
+                    Balance::from(amount),
+                    reward_token
+                );
                 PromiseOrValue::Value(U128::from(0))
-                // If collater token is wnear, no need to use wnear as intermediate token
-                // Get pool to check and calculate amount liquidate
             }
         }
     }
@@ -506,6 +1054,43 @@ pub struct PoolMetadata {
     pub amount_borrowed: Balance,
     pub total_share: Share,
     pub reward_per_share: Balance,
+    pub paused_actions: Vec<ActionType>,
+    pub collateral_factor: u128,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub flash_loan_fee: u64,
+    pub supply_cap: Option<Balance>,
+    pub borrow_cap: Option<Balance>,
+    pub remaining_supply_capacity: Option<Balance>,
+    pub remaining_borrow_capacity: Option<Balance>,
+    pub min_borrow: Balance,
+    pub min_deposit: Balance,
+    pub dust_threshold: Balance,
+    pub stable_rate: u64,
+    pub stable_rate_premium: u64,
+    pub rebalance_utilization: u64,
+    pub utilization: u64,
+    pub variable_borrowed: Balance,
+    pub stable_borrowed: Balance,
+    pub grace_period: Timestamp,
+    pub early_repayment_penalty: u64,
+    pub debt_ceiling: Option<Balance>,
+    pub isolated_debt: Balance,
+    pub borrowable_in_isolation: bool,
+    pub emode_category: Option<u64>,
+    pub reserve_factor: u64,
+    pub reserve: Balance,
+    pub bad_debt: Balance,
+    pub total_staked: Balance,
+    pub safety_module_factor: u64,
+    pub max_slash_rate: u64,
+    pub stake_cooldown: Timestamp,
+    pub uncovered_bad_debt: Balance,
+    pub share_price: Balance,
+    pub referral_factor: u64,
+    pub origination_fee: u64,
+    pub liquidation_fee: u64,
+    pub treasury: Balance,
 }
 
 // #[cfg(all(test, not(target_arch = "wasm32")))]
@@ -533,3 +1118,271 @@ pub struct PoolMetadata {
 //         contract.create_new_lending_pool(ValidAccountId::try_from("tieubaoca.testnet").unwrap(), 200);
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        account, add_pool, context, new_contract, price, set_context, storage,
+    };
+    use crate::utils::ONE_DAY;
+    use near_sdk::testing_env;
+    use near_sdk::MockedBlockchain;
+
+    fn ft_metadata(spec: &str, decimals: u8) -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: spec.to_string(),
+            name: "USD Coin".to_string(),
+            symbol: "USDC".to_string(),
+            icon: Some("data:image/svg+xml,usdc".to_string()),
+            reference: None,
+            reference_hash: None,
+            decimals,
+        }
+    }
+
+    fn metadata_result(metadata: &FungibleTokenMetadata) -> PromiseResult {
+        PromiseResult::Successful(serde_json::to_vec(metadata).unwrap())
+    }
+
+    fn create_pool(metadata: PromiseResult, storage_deposit: PromiseResult) -> LendingContract {
+        let mut contract = new_contract();
+        set_context("lending", vec![metadata, storage_deposit]);
+        assert_eq!(
+            contract.create_lending_pool_callback("usdc".to_string(), 2_000),
+            0
+        );
+        contract
+    }
+
+    // The pool takes the decimals, symbol and icon of the token's metadata
+    #[test]
+    fn test_create_lending_pool_callback() {
+        let contract = create_pool(
+            metadata_result(&ft_metadata("ft-1.0.0", 6)),
+            PromiseResult::Successful(vec![]),
+        );
+        assert_eq!(contract.token_decimals.get(&"usdc".to_string()), Some(6));
+        let pool = contract.get_pool(0);
+        assert_eq!(pool.symbol, "USDC");
+        assert_eq!(pool.icon, Some("data:image/svg+xml,usdc".to_string()));
+        assert_eq!(
+            contract.pool_ids_by_lending_token.get(&"usdc".to_string()),
+            Some(0)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_TOKEN")]
+    fn test_ft_metadata_wrong_spec() {
+        create_pool(
+            metadata_result(&ft_metadata("nft-1.0.0", 6)),
+            PromiseResult::Successful(vec![]),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_TOKEN")]
+    fn test_ft_metadata_too_many_decimals() {
+        create_pool(
+            metadata_result(&ft_metadata("ft-1.0.0", MAX_DECIMALS + 1)),
+            PromiseResult::Successful(vec![]),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_TOKEN")]
+    fn test_ft_metadata_wrong_format() {
+        create_pool(
+            PromiseResult::Successful(b"{\"spec\": \"ft-1.0.0\"}".to_vec()),
+            PromiseResult::Successful(vec![]),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_TOKEN")]
+    fn test_ft_metadata_failed() {
+        create_pool(PromiseResult::Failed, PromiseResult::Successful(vec![]));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_STORAGE_DEPOSIT_FAILED")]
+    fn test_storage_deposit_failed() {
+        create_pool(
+            metadata_result(&ft_metadata("ft-1.0.0", 6)),
+            PromiseResult::Failed,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_STORAGE_DEPOSIT")]
+    fn test_create_pool_without_storage_deposit() {
+        let mut contract = new_contract();
+        testing_env!(context("owner").attached_deposit(0).build());
+        contract.create_new_lending_pool(account("usdc"), 2_000);
+    }
+
+    // Pool 0 lends usdc, lender deposited 1_000 USDC
+    fn new_pool_contract() -> LendingContract {
+        let mut contract = new_contract();
+        let mut pool = add_pool(&mut contract, "usdc", 6);
+        pool.deposit("lender".to_string(), 1_000_000_000);
+        contract.pools.replace(0, &pool);
+        contract
+    }
+
+    fn check_borrowable(contract: &mut LendingContract, storage_balance_of: PromiseResult) {
+        set_context("lending", vec![price("1"), storage_balance_of]);
+        let loan = contract
+            .pools
+            .get(0)
+            .unwrap()
+            .new_loan(&"alice".to_string(), 1_000_000);
+        contract.check_borrowable("alice".to_string(), 0, loan, vec![], vec![]);
+    }
+
+    // A loan is not sent to an account that is not registered on the lending token
+    #[test]
+    #[should_panic(expected = "ERR_NOT_REGISTERED")]
+    fn test_borrow_not_registered() {
+        let mut contract = new_pool_contract();
+        check_borrowable(&mut contract, storage(false));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PROMISE_NOT_SUCCESSFUL")]
+    fn test_borrow_storage_check_failed() {
+        let mut contract = new_pool_contract();
+        check_borrowable(&mut contract, PromiseResult::Failed);
+    }
+
+    // A withdrawal to an account that is not registered fails and the deposit is kept
+    #[test]
+    fn test_withdraw_not_registered() {
+        let mut contract = new_pool_contract();
+        let lender = "lender".to_string();
+        set_context("lending", vec![PromiseResult::Failed]);
+        contract.check_withdraw_success(0, lender.clone(), U128(400_000_000), U128(0));
+        let pool = contract.pools.get(0).unwrap();
+        assert_eq!(pool.lenders.get(&lender).unwrap().share, 1_000_000_000);
+
+        set_context("lending", vec![PromiseResult::Successful(vec![])]);
+        contract.check_withdraw_success(0, lender.clone(), U128(400_000_000), U128(0));
+        let pool = contract.pools.get(0).unwrap();
+        assert_eq!(pool.lenders.get(&lender).unwrap().share, 600_000_000);
+    }
+
+    // Alice borrowed 100 USDC from pool 0
+    fn new_loan_contract() -> LendingContract {
+        let mut contract = new_pool_contract();
+        let mut pool = contract.pools.get(0).unwrap();
+        pool.borrow(&"alice".to_string(), 100_000_000, None);
+        contract.pools.replace(0, &pool);
+        contract
+    }
+
+    // Another loan from the pool waits until the transfer of the last one is done
+    #[test]
+    #[should_panic(expected = "ERR_BORROW_PENDING")]
+    fn test_borrow_pending() {
+        let mut contract = new_pool_contract();
+        let alice = "alice".to_string();
+        let pool = contract.pools.get(0).unwrap();
+        let loan = pool.new_loan(&alice, 1_000_000);
+        set_context("lending", vec![]);
+        contract.transfer_loan(&pool, alice.clone(), loan.clone());
+        set_context("lending", vec![PromiseResult::Failed]);
+        contract.update_borrower(0, loan.clone(), U128(0), alice.clone());
+
+        set_context("lending", vec![]);
+        contract.transfer_loan(&pool, alice.clone(), loan.clone());
+        contract.transfer_loan(&pool, alice, loan);
+    }
+
+    // A loan whose mode was swapped while the transfer was pending is still recorded
+    #[test]
+    fn test_borrow_after_rate_swap() {
+        let mut contract = new_loan_contract();
+        let alice = "alice".to_string();
+        let mut pool = contract.pools.get(0).unwrap();
+        let loan = pool.new_loan(&alice, 1_000_000);
+        pool.reserve_loan(loan.amount);
+        pool.swap_rate_mode(&alice);
+        contract.pools.replace(0, &pool);
+
+        set_context("lending", vec![PromiseResult::Successful(vec![])]);
+        assert!(contract.update_borrower(0, loan, U128(0), alice.clone()));
+        let pool = contract.pools.get(0).unwrap();
+        let loan = pool.borrowers.get(&alice).unwrap();
+        assert_eq!(loan.rate_mode, RateMode::Stable);
+        assert_eq!(loan.amount, 101_000_000);
+        assert_eq!(pool.stable_borrowed, 101_000_000);
+        assert_eq!(pool.pool_supply, 899_000_000);
+    }
+
+    fn repay_transfer(
+        contract: &mut LendingContract,
+        amount: Balance,
+        repay_max: bool,
+    ) -> PromiseOrValue<U128> {
+        contract.ft_on_transfer(
+            account("bob"),
+            U128::from(amount),
+            format!(
+                "{{\"transfer_type\": \"Repay\", \"borrower_id\": \"alice\", \"token\": \"usdc\", \"pool_id\": 0, \"repay_max\": {}}}",
+                repay_max
+            ),
+        )
+    }
+
+    // Bob pays off part of the loan of alice
+    #[test]
+    fn test_repay_on_behalf() {
+        let mut contract = new_loan_contract();
+        set_context("usdc", vec![]);
+        let refund = repay_transfer(&mut contract, 40_000_000, false);
+        assert!(matches!(refund, PromiseOrValue::Value(U128(0))));
+        let pool = contract.pools.get(0).unwrap();
+        assert_eq!(pool.get_debt(&"alice".to_string()), 60_000_000);
+        assert!(pool.borrowers.get(&"bob".to_string()).is_none());
+    }
+
+    // Interest that is not paid is added to the loan
+    #[test]
+    fn test_repay_less_than_interest() {
+        let mut contract = new_loan_contract();
+        testing_env!(context("usdc").block_timestamp(ONE_DAY * 365).build());
+        assert_eq!(
+            contract
+                .pools
+                .get(0)
+                .unwrap()
+                .get_debt(&"alice".to_string()),
+            120_000_000
+        );
+        repay_transfer(&mut contract, 5_000_000, false);
+        let pool = contract.pools.get(0).unwrap();
+        assert_eq!(pool.get_debt(&"alice".to_string()), 115_000_000);
+        assert_eq!(pool.pool_supply, 905_000_000);
+    }
+
+    // Repay max closes the loan and refunds what is left over
+    #[test]
+    fn test_repay_max_refund() {
+        let mut contract = new_loan_contract();
+        set_context("usdc", vec![]);
+        let refund = repay_transfer(&mut contract, 150_000_000, true);
+        assert!(matches!(refund, PromiseOrValue::Value(U128(50_000_000))));
+        let pool = contract.pools.get(0).unwrap();
+        assert!(pool.borrowers.get(&"alice".to_string()).is_none());
+        assert_eq!(pool.amount_borrowed, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_REPAY_MAX_NOT_ENOUGH")]
+    fn test_repay_max_not_enough() {
+        let mut contract = new_loan_contract();
+        set_context("usdc", vec![]);
+        repay_transfer(&mut contract, 99_000_000, true);
+    }
+}
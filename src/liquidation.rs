@@ -0,0 +1,269 @@
+use crate::utils::{
+    ERR_HAS_COLLATERAL, ERR_NOT_LIQUIDATABLE, ERR_NO_COLLATERAL, ERR_SAME_POOL,
+    LIQUIDATE_THRESHOLD, LIQUIDATOR_INCENTIVE, MAX_LIQUIDATE_RATE,
+};
+use crate::*;
+
+// Liquidator repays up to amount of the loan in pool_id and seizes the deposit in collateral_pool_id
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Liquidation {
+    pub liquidator: AccountId,
+    pub borrower_id: AccountId,
+    pub pool_id: u64,
+    pub collateral_pool_id: u64,
+    pub amount: U128,
+}
+
+#[near_bindgen]
+impl LendingContract {
+    // Returns the part of the transferred tokens that was not used to repay
+    #[private]
+    pub fn liquidate_callback(
+        &mut self,
+        liquidation: Liquidation,
+        loans: Vec<Loan>,
+        deposits: Vec<LenderInfo>,
+    ) -> U128 {
+        let mut pool = self.pools.get(liquidation.pool_id).expect(ERR_NO_POOL);
+        let mut collateral_pool = self
+            .pools
+            .get(liquidation.collateral_pool_id)
+            .expect(ERR_NO_POOL);
+        let tokens = LendingContract::get_price_tokens(&loans, &deposits, &[]);
+        let prices = LendingContract::process_prices(&tokens);
+        let borrower_id = liquidation.borrower_id;
+        let loan = pool.borrowers.get(&borrower_id).expect(ERR_NO_BORROWER);
+        let (loan_value, liquidation_value) =
+            self.get_liquidation_value(&borrower_id, &loans, &deposits, &prices);
+        assert!(
+            loan_value > liquidation_value || pool.is_overdue(&loan),
+            "{}",
+            ERR_NOT_LIQUIDATABLE
+        );
+
+        // At most MAX_LIQUIDATE_RATE% of the debt, for collateral worth LIQUIDATOR_INCENTIVE% more
+        let amount = Balance::from(liquidation.amount);
+        let mut repay_amount =
+            amount.min(pool.get_debt(&borrower_id) * MAX_LIQUIDATE_RATE / BORROW_RATE_DIVISOR);
+        assert!(
+            collateral_pool
+                .lenders
+                .get(&borrower_id)
+                .is_some_and(|lender| lender.is_collateral),
+            "{}",
+            ERR_NO_COLLATERAL
+        );
+        let deposit = collateral_pool.get_deposit(&borrower_id);
+        let mut seized = self.convert_amount(
+            &pool.lending_token,
+            &collateral_pool.lending_token,
+            repay_amount,
+            &prices,
+        ) * (BORROW_RATE_DIVISOR + LIQUIDATOR_INCENTIVE)
+            / BORROW_RATE_DIVISOR;
+        if seized > deposit {
+            seized = deposit;
+            repay_amount = self.convert_amount(
+                &collateral_pool.lending_token,
+                &pool.lending_token,
+                seized * BORROW_RATE_DIVISOR / (BORROW_RATE_DIVISOR + LIQUIDATOR_INCENTIVE),
+                &prices,
+            );
+        }
+        let fee = collateral_pool.get_liquidation_fee(seized);
+        log!(
+            "{} liquidated {} Yocto {} of {} for {} Yocto {}, {} of it to the treasury",
+            liquidation.liquidator,
+            repay_amount,
+            pool.lending_token,
+            borrower_id,
+            seized,
+            collateral_pool.lending_token,
+            fee
+        );
+        collateral_pool.liquidate(&borrower_id, &liquidation.liquidator, seized, fee);
+        self.pools
+            .replace(liquidation.collateral_pool_id, &collateral_pool);
+        let refund = self.internal_repay(&mut pool, borrower_id.clone(), repay_amount);
+        self.pools.replace(liquidation.pool_id, &pool);
+        if !self.has_collateral(&borrower_id) {
+            self.write_off_bad_debt(&borrower_id, &deposits);
+        }
+        U128::from(amount - repay_amount + refund)
+    }
+
+    // Liquidations write off bad debt on their own, the owner writes off the loans of a borrower
+    // left without collateral in any other way
+    pub fn write_off(&mut self, borrower_id: ValidAccountId) {
+        self.assert_owner();
+        assert!(
+            !self.has_collateral(borrower_id.as_ref()),
+            "{}",
+            ERR_HAS_COLLATERAL
+        );
+        let deposits = self.get_all_deposits(borrower_id.as_ref());
+        self.write_off_bad_debt(borrower_id.as_ref(), &deposits);
+    }
+}
+
+impl LendingContract {
+    // Fetch the prices of the borrower's positions before liquidating
+    pub(crate) fn internal_liquidate(&mut self, liquidation: Liquidation) -> Promise {
+        assert_ne!(
+            liquidation.pool_id, liquidation.collateral_pool_id,
+            "{}",
+            ERR_SAME_POOL
+        );
+        let loans = self.get_all_loans(&liquidation.borrower_id);
+        let deposits = self.get_all_deposits(&liquidation.borrower_id);
+        let tokens = LendingContract::get_price_tokens(&loans, &deposits, &[]);
+        self.fetch_prices(&tokens)
+            .then(self_contract::liquidate_callback(
+                liquidation,
+                loans,
+                deposits,
+                &env::current_account_id(),
+                0,
+                100_000_000_000_000,
+            ))
+    }
+
+    // Value of the loans, and of the collateral weighted by the liquidation threshold
+    fn get_liquidation_value(
+        &self,
+        account_id: &AccountId,
+        loans: &[Loan],
+        deposits: &[LenderInfo],
+        prices: &HashMap<AccountId, Balance>,
+    ) -> (u128, u128) {
+        let loan_value: u128 = loans
+            .iter()
+            .map(|loan| self.get_value(&loan.lending_token, loan.amount, prices))
+            .sum();
+        let emode_category = self.get_emode_category(account_id, loans);
+        let liquidation_value: u128 = deposits
+            .iter()
+            .filter(|deposit| deposit.is_collateral)
+            .map(|deposit| {
+                let pool = self.get_pool_by_token(&deposit.lending_token);
+                let liquidation_threshold = match &emode_category {
+                    Some((category_id, category)) if pool.emode_category == Some(*category_id) => {
+                        category.liquidation_threshold
+                    }
+                    _ => LIQUIDATE_THRESHOLD,
+                };
+                self.get_value(&deposit.lending_token, deposit.share, prices)
+                    * liquidation_threshold
+                    / BORROW_RATE_DIVISOR
+            })
+            .sum();
+        (loan_value, liquidation_value)
+    }
+
+    fn has_collateral(&self, account_id: &AccountId) -> bool {
+        self.pools.iter().any(|pool| {
+            pool.lenders
+                .get(account_id)
+                .is_some_and(|lender| lender.is_collateral && lender.share > 0)
+        })
+    }
+
+    // deposits are the positions of the borrower before its collateral was seized, the principal
+    // written off frees room under the debt ceiling of an isolated collateral. The deposits left
+    // go to the reserves of their pools first
+    pub(crate) fn write_off_bad_debt(&mut self, borrower_id: &AccountId, deposits: &[LenderInfo]) {
+        let deposit_pool_ids: Vec<u64> = self
+            .pools
+            .iter()
+            .filter(|pool| pool.lenders.get(borrower_id).is_some())
+            .map(|pool| pool.pool_id)
+            .collect();
+        for pool_id in deposit_pool_ids {
+            let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+            let seized = pool.seize_deposit(borrower_id);
+            self.pools.replace(pool_id, &pool);
+            log!(
+                "Deposit of {} in pool {} seized to the reserve: {} Yocto {}",
+                borrower_id,
+                pool_id,
+                seized,
+                pool.lending_token
+            );
+        }
+        let pool_ids: Vec<u64> = self
+            .pools
+            .iter()
+            .filter(|pool| pool.borrowers.get(borrower_id).is_some())
+            .map(|pool| pool.pool_id)
+            .collect();
+        for pool_id in pool_ids {
+            let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+            let amount_borrowed = pool.amount_borrowed;
+            let (debt, covered) = pool.write_off(borrower_id);
+            self.pools.replace(pool.pool_id, &pool);
+            self.remove_isolated_debt(
+                deposits,
+                &pool.lending_token,
+                amount_borrowed - pool.amount_borrowed,
+            );
+            log!(
+                "Bad debt of {} written off in pool {}: {} Yocto {}, {} covered by the reserve, {} lost by lenders",
+                borrower_id,
+                pool.pool_id,
+                debt,
+                pool.lending_token,
+                covered,
+                debt - covered
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{account, add_pool, new_contract, set_context};
+
+    #[test]
+    fn test_write_off_bad_debt() {
+        let mut contract = new_contract();
+        let alice = "alice".to_string();
+        let mut usdc = add_pool(&mut contract, "usdc", 6);
+        usdc.borrowable_in_isolation = true;
+        usdc.deposit("lender".to_string(), 1_000_000_000);
+        usdc.borrow(&alice, 600_000_000, None);
+        contract.pools.replace(0, &usdc);
+        let mut meme = add_pool(&mut contract, "meme", 18);
+        meme.debt_ceiling = Some(100_000);
+        meme.deposit(alice.clone(), 10u128.pow(24));
+        contract.pools.replace(1, &meme);
+        let deposits = contract.get_all_deposits(&alice);
+        contract.add_isolated_debt(&deposits, &usdc.lending_token, 600_000_000);
+        // A deposit that is not collateral is seized to cover the loan
+        let mut usdc = contract.pools.get(0).unwrap();
+        usdc.deposit(alice.clone(), 100_000_000);
+        usdc.set_collateral(&alice, false);
+        contract.pools.replace(0, &usdc);
+
+        // Once the collateral is seized, the rest of the loan is lost by the lender and leaves the
+        // ceiling
+        let mut meme = contract.pools.get(1).unwrap();
+        meme.lenders.remove(&alice);
+        contract.pools.replace(1, &meme);
+        contract.write_off_bad_debt(&alice, &deposits);
+        assert_eq!(contract.pools.get(1).unwrap().isolated_debt, 0);
+        let usdc = contract.pools.get(0).unwrap();
+        assert!(usdc.borrowers.get(&alice).is_none());
+        assert!(usdc.lenders.get(&alice).is_none());
+        assert_eq!(usdc.reserve, 0);
+        assert_eq!(usdc.get_deposit(&"lender".to_string()), 500_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_OWNER")]
+    fn test_write_off_not_owner() {
+        let mut contract = new_contract();
+        set_context("alice", vec![]);
+        contract.write_off(account("bob"));
+    }
+}
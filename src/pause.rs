@@ -0,0 +1,220 @@
+use crate::utils::{ActionType, ERR_ACTION_PAUSED, ERR_NOT_GUARDIAN, ERR_NOT_OWNER};
+use crate::*;
+
+// Actions halted when the guardian pauses without listing them explicitly.
+// Repay and liquidate stay open so users can still reduce risk during an incident.
+const DEFAULT_PAUSED_ACTIONS: [ActionType; 6] = [
+    ActionType::Deposit,
+    ActionType::Borrow,
+    ActionType::Withdraw,
+    ActionType::Claim,
+    ActionType::FlashLoan,
+    ActionType::Stake,
+];
+
+const ALL_ACTIONS: [ActionType; 8] = [
+    ActionType::Deposit,
+    ActionType::Borrow,
+    ActionType::Withdraw,
+    ActionType::Repay,
+    ActionType::Claim,
+    ActionType::Liquidate,
+    ActionType::FlashLoan,
+    ActionType::Stake,
+];
+
+#[near_bindgen]
+impl LendingContract {
+    // Owner hands the pause role to another account
+    pub fn set_guardian(&mut self, guardian: ValidAccountId) {
+        self.assert_owner();
+        log!("Guardian changed from {} to {}", self.guardian, guardian);
+        self.guardian = guardian.into();
+    }
+
+    // Pause actions of one pool, or of every pool when pool_id is None
+    pub fn pause(&mut self, pool_id: Option<u64>, actions: Option<Vec<ActionType>>) {
+        self.assert_guardian();
+        let actions = actions.unwrap_or_else(|| DEFAULT_PAUSED_ACTIONS.to_vec());
+        self.update_paused_actions(pool_id, |paused| {
+            for action in actions.iter() {
+                if !paused.contains(action) {
+                    paused.push(*action);
+                }
+            }
+        });
+    }
+
+    // Resume actions of one pool, or of every pool when pool_id is None.
+    // Without explicit actions everything is resumed
+    pub fn unpause(&mut self, pool_id: Option<u64>, actions: Option<Vec<ActionType>>) {
+        self.assert_guardian();
+        let actions = actions.unwrap_or_else(|| ALL_ACTIONS.to_vec());
+        self.update_paused_actions(pool_id, |paused| {
+            paused.retain(|action| !actions.contains(action))
+        });
+    }
+
+    fn update_paused_actions<F: FnOnce(&mut Vec<ActionType>)>(
+        &mut self,
+        pool_id: Option<u64>,
+        update: F,
+    ) {
+        match pool_id {
+            Some(pool_id) => {
+                let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+                update(&mut pool.paused_actions);
+                log!(
+                    "Paused actions of pool {}: {:?}",
+                    pool_id,
+                    pool.paused_actions
+                );
+                self.pools.replace(pool_id, &pool);
+            }
+            None => {
+                update(&mut self.paused_actions);
+                log!("Paused actions of all pools: {:?}", self.paused_actions);
+            }
+        }
+    }
+}
+
+impl LendingContract {
+    pub(crate) fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner,
+            "{}",
+            ERR_NOT_OWNER
+        );
+    }
+
+    // Owner keeps the pause switch in case the guardian key is lost
+    pub(crate) fn assert_guardian(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.guardian || caller == self.owner,
+            "{}",
+            ERR_NOT_GUARDIAN
+        );
+    }
+
+    pub(crate) fn is_paused(&self, pool: &LendingPool, action: ActionType) -> bool {
+        self.paused_actions.contains(&action) || pool.paused_actions.contains(&action)
+    }
+
+    pub(crate) fn assert_not_paused(&self, pool: &LendingPool, action: ActionType) {
+        assert!(
+            !self.is_paused(pool, action),
+            "{}: {:?} in pool {}",
+            ERR_ACTION_PAUSED,
+            action,
+            pool.pool_id
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{account, add_pool, new_contract, set_context};
+
+    // alice owes 100 USDC of pool 0, pool 1 is another pool
+    fn new_paused_contract() -> LendingContract {
+        let mut contract = new_contract();
+        let mut usdc = add_pool(&mut contract, "usdc", 6);
+        usdc.deposit("lender".to_string(), 1_000_000_000);
+        usdc.borrow(&"alice".to_string(), 100_000_000, None);
+        contract.pools.replace(0, &usdc);
+        add_pool(&mut contract, "wnear", 24);
+        contract
+    }
+
+    fn is_paused(contract: &LendingContract, pool_id: u64, action: ActionType) -> bool {
+        contract.is_paused(&contract.pools.get(pool_id).unwrap(), action)
+    }
+
+    #[test]
+    fn test_pause_pool() {
+        let mut contract = new_paused_contract();
+        contract.pause(Some(0), None);
+        for action in DEFAULT_PAUSED_ACTIONS.iter() {
+            assert!(is_paused(&contract, 0, *action));
+            assert!(!is_paused(&contract, 1, *action));
+        }
+
+        // Only the listed actions resume
+        contract.unpause(Some(0), Some(vec![ActionType::Deposit]));
+        assert!(!is_paused(&contract, 0, ActionType::Deposit));
+        assert!(is_paused(&contract, 0, ActionType::Borrow));
+        contract.unpause(Some(0), None);
+        assert!(contract.pools.get(0).unwrap().paused_actions.is_empty());
+    }
+
+    #[test]
+    fn test_pause_all_pools() {
+        let mut contract = new_paused_contract();
+        contract.pause(None, Some(vec![ActionType::Borrow]));
+        assert!(is_paused(&contract, 0, ActionType::Borrow));
+        assert!(is_paused(&contract, 1, ActionType::Borrow));
+        assert!(!is_paused(&contract, 1, ActionType::Deposit));
+        contract.unpause(None, None);
+        assert!(!is_paused(&contract, 1, ActionType::Borrow));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ACTION_PAUSED: Deposit in pool 1")]
+    fn test_paused_action() {
+        let mut contract = new_paused_contract();
+        contract.pause(None, None);
+        contract.assert_not_paused(&contract.pools.get(1).unwrap(), ActionType::Deposit);
+    }
+
+    // Repay and liquidate stay open when pausing without explicit actions
+    #[test]
+    fn test_repay_while_paused() {
+        let mut contract = new_paused_contract();
+        contract.pause(None, None);
+        contract.pause(Some(0), None);
+        assert!(!is_paused(&contract, 0, ActionType::Liquidate));
+        set_context("usdc", vec![]);
+        let refund = contract.ft_on_transfer(
+            account("alice"),
+            U128::from(100_000_000),
+            "{\"transfer_type\": \"Repay\", \"token\": \"usdc\", \"pool_id\": 0}".to_string(),
+        );
+        assert!(matches!(refund, PromiseOrValue::Value(U128(0))));
+        let pool = contract.pools.get(0).unwrap();
+        assert!(pool.borrowers.get(&"alice".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_guardian() {
+        let mut contract = new_paused_contract();
+        contract.set_guardian(account("guardian"));
+        set_context("guardian", vec![]);
+        contract.pause(Some(1), None);
+        assert!(is_paused(&contract, 1, ActionType::Withdraw));
+
+        // The owner keeps the pause switch
+        set_context("owner", vec![]);
+        contract.unpause(Some(1), None);
+        assert!(!is_paused(&contract, 1, ActionType::Withdraw));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_GUARDIAN")]
+    fn test_pause_not_guardian() {
+        let mut contract = new_paused_contract();
+        set_context("alice", vec![]);
+        contract.pause(None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_OWNER")]
+    fn test_set_guardian_not_owner() {
+        let mut contract = new_paused_contract();
+        set_context("guardian", vec![]);
+        contract.set_guardian(account("guardian"));
+    }
+}
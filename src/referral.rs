@@ -0,0 +1,206 @@
+use crate::utils::ERR_NO_REFERRAL_REWARD;
+use crate::*;
+
+#[near_bindgen]
+impl LendingContract {
+    // Transfer the referral reward earned in the pool. It is removed before the transfer so
+    // that it cannot be claimed twice, and given back if the transfer fails
+    #[payable]
+    pub fn claim_referral_reward(&mut self, pool_id: u64) -> Promise {
+        assert_one_yocto();
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(&pool, ActionType::Claim);
+        let referrer_id = env::predecessor_account_id();
+        let amount = pool.get_referral_reward(&referrer_id);
+        assert!(amount > 0, "{}", ERR_NO_REFERRAL_REWARD);
+        pool.claim_referral_reward(&referrer_id, amount);
+        self.pools.replace(pool_id, &pool);
+        log!("{} claim {} token of referral reward", referrer_id, amount);
+        ft_contract::ft_transfer(
+            ValidAccountId::try_from(referrer_id.clone()).unwrap(),
+            U128::from(amount),
+            None,
+            &pool.lending_token,
+            1,
+            10_000_000_000_000,
+        )
+        .then(self_contract::check_claim_referral_success(
+            pool_id,
+            referrer_id,
+            U128::from(amount),
+            &env::current_account_id(),
+            0,
+            10_000_000_000_000,
+        ))
+    }
+
+    // Give back the referral reward when the transfer failed
+    #[private]
+    pub fn check_claim_referral_success(
+        &mut self,
+        pool_id: u64,
+        referrer_id: AccountId,
+        amount: U128,
+    ) {
+        if let PromiseResult::Successful(_) = env::promise_result(env::promise_results_count() - 1)
+        {
+            return;
+        }
+        log!(
+            "Claim of {} Yocto of referral reward failed for {}",
+            Balance::from(amount),
+            referrer_id
+        );
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        pool.restore_referral_reward(&referrer_id, amount.into());
+        self.pools.replace(pool_id, &pool);
+    }
+
+    pub fn get_referrer(&self, account_id: AccountId) -> Option<AccountId> {
+        self.referrers.get(&account_id)
+    }
+
+    pub fn get_referral_reward(&self, pool_id: u64, referrer_id: AccountId) -> Balance {
+        self.pools
+            .get(pool_id)
+            .expect(ERR_NO_POOL)
+            .get_referral_reward(&referrer_id)
+    }
+}
+
+impl LendingContract {
+    // The first referrer of an account is kept, accounts cannot refer themselves
+    pub(crate) fn internal_set_referrer(
+        &mut self,
+        account_id: &AccountId,
+        referrer_id: Option<AccountId>,
+    ) {
+        if let Some(referrer_id) = referrer_id {
+            if &referrer_id != account_id && self.referrers.get(account_id).is_none() {
+                self.referrers.insert(account_id, &referrer_id);
+                log!("{} was referred by {}", account_id, referrer_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lending_pool::tests::new_lending_pool;
+    use crate::test_utils::{self, account, add_pool, context, set_context_with};
+    use crate::utils::ONE_DAY;
+    use crate::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+    use near_sdk::MockedBlockchain;
+
+    #[test]
+    fn test_referral() {
+        let mut contract = test_utils::new_contract();
+        let (alice, bob) = ("alice".to_string(), "bob".to_string());
+        contract.internal_set_referrer(&alice, Some(alice.clone()));
+        assert_eq!(contract.referrers.get(&alice), None);
+        contract.internal_set_referrer(&alice, Some(bob.clone()));
+        contract.internal_set_referrer(&alice, Some("carol".to_string()));
+        assert_eq!(contract.referrers.get(&alice), Some(bob.clone()));
+
+        let mut pool = new_lending_pool(0);
+        pool.reserve_factor = 1_000;
+        pool.deposit("lender".to_string(), 1_000_000_000);
+        pool.borrow(&alice, 500_000_000, None);
+        pool.set_referrer(&alice, contract.referrers.get(&alice));
+
+        // 100_000_000 of interest: 10% to the reserve, then 10% of the rest to bob
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(ONE_DAY * 365)
+            .build());
+        assert_eq!(pool.get_referral_reward(&bob), 9_000_000);
+        assert_eq!(pool.amount_claimable(&"lender".to_string()), 81_000_000);
+        pool.repay(alice, 600_000_000);
+        pool.claim_referral_reward(&bob, 9_000_000);
+        assert_eq!(pool.get_referral_reward(&bob), 0);
+        assert_eq!(pool.reserve, 10_000_000);
+        assert_eq!(pool.pool_supply, 1_091_000_000);
+    }
+
+    // A year after the loan of new_contract
+    fn set_context(predecessor_account_id: &str, promise_results: Vec<PromiseResult>) {
+        set_context_with(
+            context(predecessor_account_id).block_timestamp(ONE_DAY * 365),
+            promise_results,
+        );
+    }
+
+    // Contract whose pool 0 owes bob 9_000_000 of referral reward
+    fn new_contract() -> LendingContract {
+        let mut contract = test_utils::new_contract();
+        let mut pool = add_pool(&mut contract, "usdc", 6);
+        pool.reserve_factor = 1_000;
+        pool.deposit("lender".to_string(), 1_000_000_000);
+        pool.borrow(&"alice".to_string(), 500_000_000, None);
+        pool.set_referrer(&"alice".to_string(), Some("bob".to_string()));
+        contract.pools.replace(0, &pool);
+        contract
+    }
+
+    // The reward is removed before the transfer and given back if it fails
+    #[test]
+    fn test_claim_referral_reward() {
+        let mut contract = new_contract();
+        let bob = "bob".to_string();
+        set_context("bob", vec![]);
+        contract.claim_referral_reward(0);
+        assert_eq!(contract.get_referral_reward(0, bob.clone()), 0);
+        let pool_supply = contract.pools.get(0).unwrap().pool_supply;
+
+        set_context("lending", vec![PromiseResult::Failed]);
+        contract.check_claim_referral_success(0, bob.clone(), U128::from(9_000_000));
+        assert_eq!(contract.get_referral_reward(0, bob.clone()), 9_000_000);
+        assert_eq!(
+            contract.pools.get(0).unwrap().pool_supply,
+            pool_supply + 9_000_000
+        );
+
+        set_context("bob", vec![]);
+        contract.claim_referral_reward(0);
+        set_context("lending", vec![PromiseResult::Successful(vec![])]);
+        contract.check_claim_referral_success(0, bob.clone(), U128::from(9_000_000));
+        assert_eq!(contract.get_referral_reward(0, bob), 0);
+        assert_eq!(contract.pools.get(0).unwrap().pool_supply, pool_supply);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_REFERRAL_REWARD")]
+    fn test_claim_referral_reward_twice() {
+        let mut contract = new_contract();
+        set_context("bob", vec![]);
+        contract.claim_referral_reward(0);
+        contract.claim_referral_reward(0);
+    }
+
+    // Only a Deposit or Execute transfer records the referrer of the sender
+    #[test]
+    fn test_transfer_referrer() {
+        let mut contract = new_contract();
+        set_context("reward", vec![]);
+        contract.ft_on_transfer(
+            account("carol"),
+            U128::from(1_000),
+            "{\"transfer_type\": \"FundIncentive\", \"token\": \"reward\", \"pool_id\": 0, \"referrer\": \"bob\"}"
+                .to_string(),
+        );
+        assert_eq!(contract.get_referrer("carol".to_string()), None);
+
+        set_context("usdc", vec![]);
+        contract.ft_on_transfer(
+            account("carol"),
+            U128::from(1_000_000),
+            "{\"transfer_type\": \"Deposit\", \"token\": \"usdc\", \"pool_id\": 0, \"referrer\": \"bob\"}"
+                .to_string(),
+        );
+        assert_eq!(
+            contract.get_referrer("carol".to_string()),
+            Some("bob".to_string())
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use crate::*;
+
+// Lenders stake part of a deposit to backstop the bad debt of its pool. Stakers earn
+// safety_module_factor of the reserve income, and governance can slash up to max_slash_rate
+// of the stake to make up what lenders lost to bad debt
+#[near_bindgen]
+impl LendingContract {
+    #[payable]
+    pub fn stake(&mut self, pool_id: u64, amount: U128) {
+        assert_one_yocto();
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(&pool, ActionType::Stake);
+        let staker_id = env::predecessor_account_id();
+        pool.stake(&staker_id, amount.into());
+        self.pools.replace(pool_id, &pool);
+        log!(
+            "{} staked {} Yocto {} in pool {}",
+            staker_id,
+            Balance::from(amount),
+            pool.lending_token,
+            pool_id
+        );
+    }
+
+    // Start the cooldown of amount of the stake, replacing the previous request
+    #[payable]
+    pub fn request_unstake(&mut self, pool_id: u64, amount: U128) -> Timestamp {
+        assert_one_yocto();
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        let staker_id = env::predecessor_account_id();
+        let cooldown_end = pool.request_unstake(&staker_id, amount.into());
+        self.pools.replace(pool_id, &pool);
+        log!(
+            "{} requested to unstake {} Yocto {} of pool {} after {}",
+            staker_id,
+            Balance::from(amount),
+            pool.lending_token,
+            pool_id,
+            cooldown_end
+        );
+        cooldown_end
+    }
+
+    // Return the stake that finished its cooldown to the deposit, which can then be withdrawn
+    #[payable]
+    pub fn unstake(&mut self, pool_id: u64) -> U128 {
+        assert_one_yocto();
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(&pool, ActionType::Stake);
+        let staker_id = env::predecessor_account_id();
+        let amount = pool.unstake(&staker_id);
+        self.pools.replace(pool_id, &pool);
+        log!(
+            "{} unstaked {} Yocto {} of pool {}",
+            staker_id,
+            amount,
+            pool.lending_token,
+            pool_id
+        );
+        U128::from(amount)
+    }
+
+    // The stake reward is added to the deposit of the staker
+    #[payable]
+    pub fn claim_stake_reward(&mut self, pool_id: u64) -> U128 {
+        assert_one_yocto();
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        self.assert_not_paused(&pool, ActionType::Stake);
+        let staker_id = env::predecessor_account_id();
+        let reward = pool.claim_stake_reward(&staker_id);
+        self.pools.replace(pool_id, &pool);
+        log!(
+            "{} claimed {} Yocto {} of stake reward",
+            staker_id,
+            reward,
+            pool.lending_token
+        );
+        U128::from(reward)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lending_pool::tests::new_lending_pool;
+    use crate::test_utils::{add_pool, new_contract, set_context};
+    use crate::utils::ONE_DAY;
+    use crate::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+    use near_sdk::MockedBlockchain;
+
+    fn new_pool() -> LendingPool {
+        testing_env!(VMContextBuilder::new().build());
+        let mut pool = new_lending_pool(0);
+        pool.reserve_factor = 1_000;
+        pool.deposit("lender".to_string(), 1_000_000_000);
+        pool.deposit("staker".to_string(), 1_000_000_000);
+        let mut staker = pool.lenders.get(&"staker".to_string()).unwrap();
+        staker.is_collateral = false;
+        pool.lenders.insert(&"staker".to_string(), &staker);
+        pool
+    }
+
+    #[test]
+    fn test_stake() {
+        let mut pool = new_pool();
+        let staker = "staker".to_string();
+        pool.stake(&staker, 1_000_000_000);
+        assert_eq!(pool.total_share, 1_000_000_000);
+        assert_eq!(pool.total_staked, 1_000_000_000);
+        pool.borrow(&"alice".to_string(), 500_000_000, None);
+
+        // 20% a year for a year, 10% of it goes to the reserve and half of that to stakers
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(ONE_DAY * 365)
+            .build());
+        assert_eq!(pool.get_stake_reward(&staker), 5_000_000);
+        assert_eq!(pool.claim_stake_reward(&staker), 5_000_000);
+        assert_eq!(pool.reserve, 5_000_000);
+        assert_eq!(pool.lenders.get(&staker).unwrap().share, 5_000_000);
+
+        pool.request_unstake(&staker, 400_000_000);
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(ONE_DAY * 375)
+            .build());
+        assert_eq!(pool.unstake(&staker), 400_000_000);
+        assert_eq!(pool.lenders.get(&staker).unwrap().share, 405_000_000);
+        assert_eq!(pool.total_staked, 600_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_COOLDOWN_NOT_FINISHED")]
+    fn test_unstake_before_cooldown() {
+        let mut pool = new_pool();
+        let staker = "staker".to_string();
+        pool.stake(&staker, 1_000_000_000);
+        pool.request_unstake(&staker, 1_000_000_000);
+        testing_env!(VMContextBuilder::new().block_timestamp(ONE_DAY).build());
+        pool.unstake(&staker);
+    }
+
+    #[test]
+    fn test_slash() {
+        let mut pool = new_pool();
+        let staker = "staker".to_string();
+        pool.stake(&staker, 1_000_000_000);
+        pool.borrow(&"alice".to_string(), 500_000_000, None);
+        pool.write_off(&"alice".to_string());
+        assert_eq!(pool.uncovered_bad_debt, 500_000_000);
+        assert_eq!(pool.get_deposit(&"lender".to_string()), 500_000_000);
+
+        // A slash takes at most 30% of the stake, a stake in cooldown is slashed as well
+        pool.request_unstake(&staker, 500_000_000);
+        assert_eq!(pool.slash(500_000_000), 300_000_000);
+        assert_eq!(pool.total_staked, 700_000_000);
+        assert_eq!(pool.get_stake_amount(&staker), 700_000_000);
+        assert_eq!(pool.stakers.get(&staker).unwrap().share, 1_000_000_000);
+        assert_eq!(pool.uncovered_bad_debt, 200_000_000);
+        pool.claim("lender".to_string());
+        assert_eq!(pool.pool_supply, 1_200_000_000);
+
+        // A new stake gets stake shares at the slashed value
+        pool.deposit("staker".to_string(), 140_000_000);
+        pool.stake(&staker, 140_000_000);
+        assert_eq!(pool.stakers.get(&staker).unwrap().share, 1_200_000_000);
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(ONE_DAY * 10)
+            .build());
+        assert_eq!(pool.unstake(&staker), 350_000_000);
+        assert_eq!(pool.get_stake_amount(&staker), 490_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ACTION_PAUSED")]
+    fn test_stake_paused() {
+        let mut contract = new_contract();
+        add_pool(&mut contract, "usdc", 6);
+        contract.pause(Some(0), None);
+        set_context("staker", vec![]);
+        contract.stake(0, U128::from(1_000_000));
+    }
+}
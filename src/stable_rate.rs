@@ -0,0 +1,35 @@
+use crate::*;
+
+#[near_bindgen]
+impl LendingContract {
+    // Switch the caller's loan between variable and stable rate,
+    // a stable loan is locked at the current stable rate of the pool
+    #[payable]
+    pub fn swap_rate_mode(&mut self, pool_id: u64) -> RateMode {
+        assert_one_yocto();
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        let rate_mode = pool.swap_rate_mode(&env::predecessor_account_id());
+        log!(
+            "{} swapped loan of pool {} to {:?} rate",
+            env::predecessor_account_id(),
+            pool_id,
+            rate_mode
+        );
+        self.pools.replace(pool_id, &pool);
+        rate_mode
+    }
+
+    // Anyone can move a stable loan up to the current stable rate once utilization is too high
+    pub fn rebalance_stable_rate(&mut self, pool_id: u64, borrower_id: ValidAccountId) -> u64 {
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        let stable_rate = pool.rebalance_stable_rate(borrower_id.as_ref());
+        log!(
+            "Stable rate of {} in pool {} rebalanced to {}",
+            borrower_id,
+            pool_id,
+            stable_rate
+        );
+        self.pools.replace(pool_id, &pool);
+        stable_rate
+    }
+}
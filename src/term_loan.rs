@@ -0,0 +1,20 @@
+use crate::*;
+
+#[near_bindgen]
+impl LendingContract {
+    // Borrow for term nanoseconds at the current stable rate, fixed until the loan is repaid.
+    // Repaying before maturity costs the early repayment penalty of the pool, and the loan can
+    // be liquidated whatever its health once the grace period after maturity has passed
+    #[payable]
+    pub fn borrow_term(&mut self, pool_id: u64, amount: U128, term: Timestamp) -> Promise {
+        assert_one_yocto();
+        self.internal_borrow(
+            env::predecessor_account_id(),
+            env::predecessor_account_id(),
+            pool_id,
+            amount,
+            Some(RateMode::Stable),
+            Some(term),
+        )
+    }
+}
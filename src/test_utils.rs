@@ -0,0 +1,87 @@
+// Fixtures shared by the unit tests of the contract modules
+use crate::lending_pool::tests::new_lending_pool;
+use crate::*;
+use near_sdk::test_utils::VMContextBuilder;
+use near_sdk::testing_env;
+use near_sdk::MockedBlockchain;
+
+pub(crate) fn account(account_id: &str) -> ValidAccountId {
+    ValidAccountId::try_from(account_id).unwrap()
+}
+
+// Call of predecessor_account_id to the lending contract with 1 yoctoNEAR attached
+pub(crate) fn context(predecessor_account_id: &str) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(account("lending"))
+        .predecessor_account_id(account(predecessor_account_id))
+        .attached_deposit(1);
+    builder
+}
+
+pub(crate) fn set_context(predecessor_account_id: &str, promise_results: Vec<PromiseResult>) {
+    set_context_with(&mut context(predecessor_account_id), promise_results);
+}
+
+// Context of a customized builder, e.g. context(..).block_timestamp(..)
+pub(crate) fn set_context_with(
+    builder: &mut VMContextBuilder,
+    promise_results: Vec<PromiseResult>,
+) {
+    testing_env!(
+        builder.build(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        promise_results
+    );
+}
+
+// Contract without pools, the context is left to the owner
+pub(crate) fn new_contract() -> LendingContract {
+    set_context("owner", vec![]);
+    LendingContract::new(account("owner"))
+}
+
+// Pool of lending_token at a 20% interest rate, as created by create_new_lending_pool
+pub(crate) fn add_pool(
+    contract: &mut LendingContract,
+    lending_token: &str,
+    decimals: u8,
+) -> LendingPool {
+    let mut pool = new_lending_pool(0);
+    pool.pool_id = contract.pool_count;
+    pool.lending_token = lending_token.to_string();
+    pool.borrowers = UnorderedMap::new(format!("{}borrowers", lending_token).as_bytes());
+    pool.lenders = UnorderedMap::new(format!("{}lenders", lending_token).as_bytes());
+    contract.pools.push(&pool);
+    contract
+        .pool_ids_by_lending_token
+        .insert(&pool.lending_token, &pool.pool_id);
+    contract
+        .token_decimals
+        .insert(&pool.lending_token, &decimals);
+    contract.pool_count += 1;
+    pool
+}
+
+// Oracle response of a price request
+pub(crate) fn price(result: &str) -> PromiseResult {
+    PromiseResult::Successful(
+        serde_json::to_vec(&Response {
+            result: result.to_string(),
+            timestamp: 0,
+        })
+        .unwrap(),
+    )
+}
+
+// storage_balance_of response of the lending token for an account
+pub(crate) fn storage(registered: bool) -> PromiseResult {
+    let storage_balance = Some(StorageBalance {
+        total: U128::from(0),
+        available: U128::from(0),
+    })
+    .filter(|_| registered);
+    PromiseResult::Successful(serde_json::to_vec(&storage_balance).unwrap())
+}
@@ -0,0 +1,166 @@
+use crate::utils::ERR_NOT_ENOUGH_TREASURY;
+use crate::*;
+
+#[near_bindgen]
+impl LendingContract {
+    // Owner withdraws the protocol fees of a pool, all of them without amount
+    #[payable]
+    pub fn withdraw_treasury(&mut self, pool_id: u64, amount: Option<U128>) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        let amount = amount.map_or(pool.treasury, Balance::from);
+        assert!(
+            amount > 0 && amount <= pool.treasury,
+            "{}",
+            ERR_NOT_ENOUGH_TREASURY
+        );
+        log!(
+            "{} withdraw {} Yocto {} from the treasury",
+            self.owner,
+            amount,
+            pool.lending_token
+        );
+        // Removed before the transfer so another withdrawal cannot take it twice
+        pool.treasury -= amount;
+        self.pools.replace(pool_id, &pool);
+        ft_contract::ft_transfer(
+            ValidAccountId::try_from(self.owner.clone()).unwrap(),
+            U128::from(amount),
+            None,
+            &pool.lending_token,
+            1,
+            10_000_000_000_000,
+        )
+        .then(self_contract::check_treasury_withdraw_success(
+            pool_id,
+            U128::from(amount),
+            &env::current_account_id(),
+            0,
+            10_000_000_000_000,
+        ))
+    }
+
+    // Give back the withdrawn amount to the treasury when the transfer failed
+    #[private]
+    pub fn check_treasury_withdraw_success(&mut self, pool_id: u64, amount: U128) {
+        if let PromiseResult::Successful(_) = env::promise_result(env::promise_results_count() - 1)
+        {
+            return;
+        }
+        log!(
+            "Withdrawal of {} Yocto from the treasury of pool {} failed",
+            Balance::from(amount),
+            pool_id
+        );
+        let mut pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        pool.treasury += Balance::from(amount);
+        self.pools.replace(pool_id, &pool);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{self, add_pool, set_context};
+    use crate::*;
+
+    // Contract whose pool 0 charges a 1% origination fee
+    fn new_contract() -> LendingContract {
+        let mut contract = test_utils::new_contract();
+        let mut pool = add_pool(&mut contract, "usdc", 6);
+        pool.origination_fee = 100;
+        pool.deposit("lender".to_string(), 1_000_000_000);
+        contract.pools.replace(0, &pool);
+        contract
+    }
+
+    // The borrower gets the loan less the fee, the fee goes to the treasury once the transfer
+    // succeeded
+    #[test]
+    fn test_origination_fee() {
+        let mut contract = new_contract();
+        let pool = contract.pools.get(0).unwrap();
+        let loan = pool.new_loan(&"alice".to_string(), 1_000_000);
+        let fee = pool.get_origination_fee(loan.amount);
+        assert_eq!(fee, 10_000);
+        let reserve_loan = |contract: &mut LendingContract| {
+            let mut pool = contract.pools.get(0).unwrap();
+            pool.reserve_loan(1_000_000);
+            contract.pools.replace(0, &pool);
+        };
+
+        reserve_loan(&mut contract);
+        set_context("lending", vec![PromiseResult::Failed]);
+        assert!(!contract.update_borrower(0, loan.clone(), U128::from(fee), "alice".to_string()));
+        let pool = contract.pools.get(0).unwrap();
+        assert_eq!(pool.treasury, 0);
+        assert_eq!(pool.get_debt(&"alice".to_string()), 0);
+        assert_eq!(pool.pool_supply, 1_000_000_000);
+
+        reserve_loan(&mut contract);
+        set_context("lending", vec![PromiseResult::Successful(vec![])]);
+        assert!(contract.update_borrower(0, loan, U128::from(fee), "alice".to_string()));
+        let pool = contract.pools.get(0).unwrap();
+        assert_eq!(pool.treasury, 10_000);
+        assert_eq!(pool.get_debt(&"alice".to_string()), 1_000_000);
+        assert_eq!(pool.pool_supply, 999_000_000);
+    }
+
+    #[test]
+    fn test_withdraw_treasury() {
+        let mut contract = new_contract();
+        let mut pool = contract.pools.get(0).unwrap();
+        pool.treasury = 10_000;
+        contract.pools.replace(0, &pool);
+
+        // The treasury goes down before the transfer and back up if it failed
+        set_context("owner", vec![]);
+        contract.withdraw_treasury(0, Some(U128::from(4_000)));
+        assert_eq!(contract.pools.get(0).unwrap().treasury, 6_000);
+        set_context("lending", vec![PromiseResult::Failed]);
+        contract.check_treasury_withdraw_success(0, U128::from(4_000));
+        assert_eq!(contract.pools.get(0).unwrap().treasury, 10_000);
+        set_context("owner", vec![]);
+        contract.withdraw_treasury(0, Some(U128::from(4_000)));
+        set_context("lending", vec![PromiseResult::Successful(vec![])]);
+        contract.check_treasury_withdraw_success(0, U128::from(4_000));
+        assert_eq!(contract.pools.get(0).unwrap().treasury, 6_000);
+
+        // All of it without amount
+        set_context("owner", vec![]);
+        contract.withdraw_treasury(0, None);
+        assert_eq!(contract.pools.get(0).unwrap().treasury, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_TREASURY")]
+    fn test_withdraw_treasury_exceeds() {
+        let mut contract = new_contract();
+        let mut pool = contract.pools.get(0).unwrap();
+        pool.treasury = 10_000;
+        contract.pools.replace(0, &pool);
+        set_context("owner", vec![]);
+        contract.withdraw_treasury(0, Some(U128::from(10_001)));
+    }
+
+    // A pending withdrawal is no longer in the treasury
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_TREASURY")]
+    fn test_withdraw_treasury_pending() {
+        let mut contract = new_contract();
+        let mut pool = contract.pools.get(0).unwrap();
+        pool.treasury = 10_000;
+        contract.pools.replace(0, &pool);
+        set_context("owner", vec![]);
+        contract.withdraw_treasury(0, Some(U128::from(6_000)));
+        contract.withdraw_treasury(0, Some(U128::from(6_000)));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_OWNER")]
+    fn test_withdraw_treasury_not_owner() {
+        let mut contract = new_contract();
+        set_context("alice", vec![]);
+        contract.withdraw_treasury(0, None);
+    }
+}
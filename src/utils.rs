@@ -3,16 +3,102 @@ pub const INTEREST_DIVISOR: u128 = 10_000;
 pub const PRICE_DIVISOR: f64 = 10_000f64;
 pub const SHARE_DIVISOR: Balance = 1_000_000_000_000;
 pub const ONE_DAY: Timestamp = 86_400_000_000_000;
+pub const ONE_SECOND: Timestamp = 1_000_000_000;
 pub const ORACLE_DATA_EXPIRATION: Timestamp = 600_000_000_000;
 pub const MAX_BORROW_RATE: u128 = 50;
 pub const BORROW_RATE_DIVISOR: Balance = 100;
 pub const LIQUIDATE_THRESHOLD: u128 = 65;
 pub const LIQUIDATOR_INCENTIVE: u128 = 5;
 pub const MAX_LIQUIDATE_RATE: u128 = 50;
+pub const MAX_DECIMALS: u8 = 38;
+pub const FLASH_LOAN_FEE: u64 = 9;
+pub const GAS_FOR_FLASH_LOAN_CALLBACK: Gas = 20_000_000_000_000;
+// Attached to each oracle request of fetch_prices
+pub const GAS_FOR_PRICE: Gas = 5_000_000_000_000;
+pub const GAS_FOR_RESOLVE_EXECUTE: Gas = 5_000_000_000_000;
+pub const TIMELOCK_DELAY: Timestamp = 172_800_000_000_000;
+pub const MIN_TIMELOCK_DELAY: Timestamp = 86_400_000_000_000;
+pub const STABLE_RATE_PREMIUM: u64 = 200;
+pub const REBALANCE_UTILIZATION: u64 = 9_500;
+pub const GRACE_PERIOD: Timestamp = 604_800_000_000_000;
+pub const EARLY_REPAYMENT_PENALTY: u64 = 100;
+pub const DEBT_CEILING_DECIMALS: u32 = 2;
+pub const RESERVE_FACTOR: u64 = 1_000;
+pub const SAFETY_MODULE_FACTOR: u64 = 5_000;
+pub const MAX_SLASH_RATE: u64 = 3_000;
+pub const STAKE_COOLDOWN: Timestamp = 864_000_000_000_000;
+pub const REFERRAL_FACTOR: u64 = 1_000;
+pub const ORIGINATION_FEE: u64 = 0;
+pub const LIQUIDATION_FEE: u64 = 0;
+pub const MAX_SWAP_SLIPPAGE: u128 = 200;
+pub const SLIPPAGE_DIVISOR: u128 = 10_000;
+pub const LEVERAGE_DIVISOR: u128 = 100;
+pub const GAS_FOR_DEX_DEPOSIT: Gas = 35_000_000_000_000;
+pub const GAS_FOR_DEX_SWAP: Gas = 20_000_000_000_000;
+pub const GAS_FOR_DEX_WITHDRAW: Gas = 30_000_000_000_000;
+pub const GAS_FOR_SWAP_CALLBACK: Gas = 25_000_000_000_000;
 pub const ERR_ORACLE_DATA_EXPIRED: &str = "ERR_ORACLE_DATA_EXPIRED";
 pub const ERR_NO_POOL: &str = "ERR_NO_POOL";
 pub const ERR_NO_BORROWER: &str = "ERR_NO_BORROWER";
 pub const ERR_BORROW_VALUE_LIMITED: &str = "ERR_BORROW_VALUE_LIMITED";
+pub const ERR_NOT_OWNER: &str = "ERR_NOT_OWNER";
+pub const ERR_NOT_GUARDIAN: &str = "ERR_NOT_GUARDIAN";
+pub const ERR_ACTION_PAUSED: &str = "ERR_ACTION_PAUSED";
+pub const ERR_NO_PROPOSAL: &str = "ERR_NO_PROPOSAL";
+pub const ERR_TIMELOCK_NOT_EXPIRED: &str = "ERR_TIMELOCK_NOT_EXPIRED";
+pub const ERR_TIMELOCK_DELAY_TOO_SHORT: &str = "ERR_TIMELOCK_DELAY_TOO_SHORT";
+pub const ERR_INVALID_COLLATERAL_FACTOR: &str = "ERR_INVALID_COLLATERAL_FACTOR";
+pub const ERR_SUPPLY_CAP_EXCEEDED: &str = "ERR_SUPPLY_CAP_EXCEEDED";
+pub const ERR_BORROW_CAP_EXCEEDED: &str = "ERR_BORROW_CAP_EXCEEDED";
+pub const ERR_BELOW_MIN_BORROW: &str = "ERR_BELOW_MIN_BORROW";
+pub const ERR_BELOW_MIN_DEPOSIT: &str = "ERR_BELOW_MIN_DEPOSIT";
+pub const ERR_DUST_ABOVE_MIN_BORROW: &str = "ERR_DUST_ABOVE_MIN_BORROW";
+pub const ERR_INVALID_TOKEN: &str = "ERR_INVALID_TOKEN";
+pub const ERR_POOL_EXISTS: &str = "ERR_POOL_EXISTS";
+pub const ERR_NO_STORAGE_DEPOSIT: &str = "ERR_NO_STORAGE_DEPOSIT";
+pub const ERR_STORAGE_DEPOSIT_FAILED: &str = "ERR_STORAGE_DEPOSIT_FAILED";
+pub const ERR_REPAY_MAX_NOT_ENOUGH: &str = "ERR_REPAY_MAX_NOT_ENOUGH";
+pub const ERR_FLASH_LOAN_IN_PROGRESS: &str = "ERR_FLASH_LOAN_IN_PROGRESS";
+pub const ERR_NO_FLASH_LOAN: &str = "ERR_NO_FLASH_LOAN";
+pub const ERR_NO_ACTIONS: &str = "ERR_NO_ACTIONS";
+pub const ERR_DUPLICATE_ACTION: &str =
+    "ERR_DUPLICATE_ACTION: one borrow and one withdraw or claim per pool";
+pub const ERR_REPAY_NEEDS_TRANSFER: &str = "ERR_REPAY_NEEDS_TRANSFER: repay in an Execute transfer";
+pub const ERR_NOT_REGISTERED: &str = "ERR_NOT_REGISTERED: call storage_deposit on the token first";
+pub const ERR_NO_DEX: &str = "ERR_NO_DEX";
+pub const ERR_INVALID_LEVERAGE: &str = "ERR_INVALID_LEVERAGE";
+pub const ERR_NO_COLLATERAL: &str = "ERR_NO_COLLATERAL";
+pub const ERR_SAME_POOL: &str = "ERR_SAME_POOL";
+pub const ERR_NO_DEX_POOL: &str = "ERR_NO_DEX_POOL";
+pub const ERR_NO_DEX_WITHDRAWAL: &str = "ERR_NO_DEX_WITHDRAWAL";
+pub const ERR_DELEGATION_EXCEEDED: &str = "ERR_DELEGATION_EXCEEDED";
+pub const ERR_RATE_MODE_MISMATCH: &str =
+    "ERR_RATE_MODE_MISMATCH: swap the rate mode of the loan first";
+pub const ERR_REBALANCE_NOT_ALLOWED: &str = "ERR_REBALANCE_NOT_ALLOWED";
+pub const ERR_TERM_LOAN: &str = "ERR_TERM_LOAN: term loans cannot be extended or change rate";
+pub const ERR_INVALID_TERM: &str = "ERR_INVALID_TERM";
+pub const ERR_BORROW_PENDING: &str = "ERR_BORROW_PENDING: wait for the transfer of the last loan";
+pub const ERR_ISOLATION_MODE: &str =
+    "ERR_ISOLATION_MODE: isolated collateral cannot be used with other collateral";
+pub const ERR_NOT_BORROWABLE_IN_ISOLATION: &str = "ERR_NOT_BORROWABLE_IN_ISOLATION";
+pub const ERR_DEBT_CEILING_EXCEEDED: &str = "ERR_DEBT_CEILING_EXCEEDED";
+pub const ERR_NO_EMODE_CATEGORY: &str = "ERR_NO_EMODE_CATEGORY";
+pub const ERR_INVALID_EMODE_CATEGORY: &str = "ERR_INVALID_EMODE_CATEGORY";
+pub const ERR_NOT_LIQUIDATABLE: &str = "ERR_NOT_LIQUIDATABLE";
+pub const ERR_HAS_COLLATERAL: &str = "ERR_HAS_COLLATERAL";
+pub const ERR_STAKE_COLLATERAL: &str = "ERR_STAKE_COLLATERAL: turn off collateral before staking";
+pub const ERR_NOT_ENOUGH_SHARE: &str = "ERR_NOT_ENOUGH_SHARE";
+pub const ERR_NO_STAKE: &str = "ERR_NO_STAKE";
+pub const ERR_COOLDOWN_NOT_FINISHED: &str = "ERR_COOLDOWN_NOT_FINISHED";
+pub const ERR_INVALID_INCENTIVE: &str = "ERR_INVALID_INCENTIVE";
+pub const ERR_NO_INCENTIVE_REWARD: &str = "ERR_NO_INCENTIVE_REWARD";
+pub const ERR_INCENTIVE_NOT_FUNDED: &str =
+    "ERR_INCENTIVE_NOT_FUNDED: fund the contract with the reward tokens of the schedule first";
+pub const ERR_NO_REFERRAL_REWARD: &str = "ERR_NO_REFERRAL_REWARD";
+pub const ERR_NOT_ENOUGH_TREASURY: &str = "ERR_NOT_ENOUGH_TREASURY";
+pub const ERR_WRONG_SWAP_PURPOSE: &str = "ERR_WRONG_SWAP_PURPOSE";
+pub const ERR_POSITION_WORSE: &str =
+    "ERR_POSITION_WORSE: the loan must end within the borrow limit or closer to it";
 pub const WRONG_FORMAT_PROMISE_RESULT: &'static [u8] = b"ERR_WRONG_VAL_RECEIVED";
 pub const PROMISE_NOT_SUCCESSFUL: &'static [u8] = b"ERR_PROMISE_NOT_SUCCESSFUL";
 use uint::construct_uint;
@@ -34,6 +120,21 @@ trait TFT {
         memo: Option<String>,
         msg: String,
     ) -> PromiseOrValue<U128>;
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<ValidAccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance;
+    fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance>;
+}
+
+// Ref Finance exchange: tokens are deposited with ft_transfer_call, swapped inside
+// the exchange and withdrawn back to the caller
+#[ext_contract(dex_contract)]
+trait TDex {
+    fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<ValidAccountId>) -> U128;
+    fn withdraw(&mut self, token_id: ValidAccountId, amount: U128, unregister: Option<bool>);
 }
 
 #[ext_contract(oracle_contract)]
@@ -44,6 +145,15 @@ trait TOracle {
 #[ext_contract(self_contract)]
 pub trait TSelf {
     fn check_claim_success(&mut self, pool_id: u64, lender: AccountId);
+    fn check_treasury_withdraw_success(&mut self, pool_id: u64, amount: U128);
+    fn check_claim_referral_success(&mut self, pool_id: u64, referrer_id: AccountId, amount: U128);
+    fn check_claim_incentive_success(
+        &mut self,
+        pool_id: u64,
+        incentive_id: u64,
+        account_id: AccountId,
+        amount: U128,
+    );
     fn check_withdraw_success(
         &mut self,
         pool_id: u64,
@@ -51,30 +161,70 @@ pub trait TSelf {
         amount: U128,
         interest: U128,
     );
-    fn update_borrower(&mut self, pool_id: u64, borrower: AccountId, amount: U128);
-    fn check_borrowable(
+    fn create_lending_pool_callback(&mut self, lending_token: AccountId, interest_rate: u64);
+    fn flash_loan_callback(
         &mut self,
-        borrower_id: AccountId,
         pool_id: u64,
-        amount: U128,
+        flash_loan: FlashLoan,
+        msg: String,
+        loans: Vec<Loan>,
+        deposits: Vec<LenderInfo>,
+    );
+    fn check_flash_loan(&mut self, pool_id: u64);
+    fn execute_callback(
+        &mut self,
+        account_id: AccountId,
+        actions: Vec<Action>,
         loans: Vec<Loan>,
         deposits: Vec<LenderInfo>,
     );
-    fn liquidate(
+    fn resolve_execute(&mut self, refund: U128) -> U128;
+    fn update_borrower(&mut self, pool_id: u64, loan: Loan, fee: U128, receiver_id: AccountId);
+    fn leverage_callback(
+        &mut self,
+        account_id: AccountId,
+        leverage: u64,
+        dex_pool_id: u64,
+        args: SwapArgs,
+    );
+    fn swap_collateral_callback(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        min_amount_out: Option<U128>,
+        args: SwapArgs,
+    );
+    fn repay_with_collateral_callback(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        min_amount_out: U128,
+        args: SwapArgs,
+    );
+    fn on_dex_deposit(&mut self, account_id: AccountId, action: SwapAction, purpose: SwapPurpose);
+    fn on_dex_swap(&mut self, account_id: AccountId, action: SwapAction, purpose: SwapPurpose);
+    fn on_dex_withdraw(&mut self, withdrawal: DexWithdrawal, withdrawal_id: Option<u64>);
+    fn check_borrowable(
         &mut self,
-        liquidator: AccountId,
+        receiver_id: AccountId,
         pool_id: u64,
-        amount: Balance,
-        borrower_id: AccountId,
-    ) -> PromiseOrValue<U128>;
+        loan: Loan,
+        loans: Vec<Loan>,
+        deposits: Vec<LenderInfo>,
+    );
+    fn set_emode_callback(
+        &mut self,
+        account_id: AccountId,
+        category_id: Option<u64>,
+        loans: Vec<Loan>,
+        deposits: Vec<LenderInfo>,
+    );
     fn liquidate_callback(
         &mut self,
-        pool_id: u64,
-        amount_deposit: Balance,
-        remain_amount: Balance,
-        amount_collateral_out: Balance,
-        borrower_id: AccountId,
-    ) -> PromiseOrValue<U128>;
+        liquidation: Liquidation,
+        loans: Vec<Loan>,
+        deposits: Vec<LenderInfo>,
+    ) -> U128;
 }
 
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault, Deserialize, Serialize)]
@@ -84,14 +234,42 @@ pub struct Response {
     pub timestamp: Timestamp,
 }
 
+// Swap action of a Ref Finance pool, amount_in defaults to the output of the previous action
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+// NEP-145 storage balance returned by the token contracts
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 #[cfg_attr(not(target_arch = "wasm32"), derive(Debug, PartialEq))]
 pub struct TransferPayload {
     pub transfer_type: TransferType, //"Deposit", "Repay", "Mortgate", "Liquidate"
-    pub borrower_id: Option<AccountId>, // Require once deposit to liquidate asset of borrower
+    // Require once deposit to liquidate asset of borrower, or to repay the loan of another borrower
+    pub borrower_id: Option<AccountId>,
+    // Pool of the deposit seized by a liquidation
+    pub collateral_pool_id: Option<u64>,
     pub token: AccountId,
     pub pool_id: u64,
+    // Repay the whole debt and refund the surplus, fails if amount is not enough
+    pub repay_max: Option<bool>,
+    // Actions run after an Execute transfer, repays are paid from the transfer and the rest is deposited
+    pub actions: Option<Vec<Action>>,
+    // Recorded as the referrer of the sender if it has none, on a Deposit or Execute transfer
+    pub referrer: Option<AccountId>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -101,4 +279,24 @@ pub enum TransferType {
     Deposit,
     Repay,
     Liquidate,
+    FlashLoanRepay,
+    Execute,
+    // Reward tokens for the incentives, sent by the reward token
+    FundIncentive,
+}
+
+// Actions that can be halted independently by the guardian
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum ActionType {
+    Deposit,
+    Borrow,
+    Withdraw,
+    Repay,
+    Claim,
+    Liquidate,
+    FlashLoan,
+    // Staking, unstaking and stake reward claims of the safety module
+    Stake,
 }
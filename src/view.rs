@@ -1,3 +1,4 @@
+use crate::utils::{ActionType, ERR_NO_PROPOSAL};
 use crate::*;
 
 #[near_bindgen]
@@ -18,29 +19,13 @@ impl LendingContract {
             .iter()
             .skip(from_index)
             .take(limit)
-            .map(|pool| PoolMetadata {
-                pool_id: pool.pool_id,
-                lending_token: pool.lending_token,
-                interest_rate: pool.interest_rate,
-                pool_supply: pool.pool_supply,
-                amount_borrowed: pool.amount_borrowed,
-                total_share: pool.total_share,
-                reward_per_share: pool.reward_per_share,
-            })
+            .map(PoolMetadata::from)
             .collect()
     }
 
     pub fn get_pool(&self, pool_id: u64) -> PoolMetadata {
         let pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
-        PoolMetadata {
-            pool_id: pool.pool_id,
-            lending_token: pool.lending_token,
-            interest_rate: pool.interest_rate,
-            pool_supply: pool.pool_supply,
-            amount_borrowed: pool.amount_borrowed,
-            total_share: pool.total_share,
-            reward_per_share: pool.reward_per_share,
-        }
+        PoolMetadata::from(pool)
     }
 
     pub fn get_loan(&self, pool_id: u64, borrower_id: AccountId) -> Loan {
@@ -59,6 +44,58 @@ impl LendingContract {
             .get_interest(&self.get_loan(pool_id, borrower_id))
     }
 
+    // Amount that closes the loan now, with the early repayment penalty of a term loan
+    pub fn get_repay_amount(&self, pool_id: u64, borrower_id: AccountId) -> Balance {
+        self.pools
+            .get(pool_id)
+            .expect(ERR_NO_POOL)
+            .get_repay_amount(&borrower_id)
+    }
+
+    // Term loan past its maturity and grace period
+    pub fn is_loan_overdue(&self, pool_id: u64, borrower_id: AccountId) -> bool {
+        let pool = self.pools.get(pool_id).expect(ERR_NO_POOL);
+        pool.borrowers
+            .get(&borrower_id)
+            .is_some_and(|loan| pool.is_overdue(&loan))
+    }
+
+    pub fn get_incentives(&self, pool_id: u64) -> Vec<Incentive> {
+        self.pools.get(pool_id).expect(ERR_NO_POOL).incentives
+    }
+
+    // Unclaimed rewards of an account, by incentive id
+    pub fn get_incentive_rewards(&self, pool_id: u64, account_id: AccountId) -> Vec<Balance> {
+        self.pools
+            .get(pool_id)
+            .expect(ERR_NO_POOL)
+            .get_incentive_rewards(&account_id)
+    }
+
+    pub fn get_stake(&self, pool_id: u64, staker_id: AccountId) -> Option<StakeInfo> {
+        self.pools
+            .get(pool_id)
+            .expect(ERR_NO_POOL)
+            .stakers
+            .get(&staker_id)
+    }
+
+    // Lending tokens of a staker in the safety module, after slashing
+    pub fn get_staked_amount(&self, pool_id: u64, staker_id: AccountId) -> Balance {
+        self.pools
+            .get(pool_id)
+            .expect(ERR_NO_POOL)
+            .get_stake_amount(&staker_id)
+    }
+
+    // Reserve income earned by a staker in the safety module
+    pub fn get_stake_reward(&self, pool_id: u64, staker_id: AccountId) -> Balance {
+        self.pools
+            .get(pool_id)
+            .expect(ERR_NO_POOL)
+            .get_stake_reward(&staker_id)
+    }
+
     pub fn get_lender(&self, pool_id: u64, lender_id: AccountId) -> LenderInfo {
         self.pools
             .get(pool_id)
@@ -67,4 +104,147 @@ impl LendingContract {
             .get(&lender_id)
             .expect("ERR_NO_LENDER")
     }
+
+    // Page of the lenders of a pool, to pass to compound
+    pub fn get_lender_ids(&self, pool_id: u64, from_index: usize, limit: usize) -> Vec<AccountId> {
+        self.pools
+            .get(pool_id)
+            .expect(ERR_NO_POOL)
+            .lenders
+            .keys()
+            .skip(from_index)
+            .take(limit)
+            .collect()
+    }
+
+    // Lending tokens the share of a lender is worth, without its interest
+    pub fn get_deposit(&self, pool_id: u64, lender_id: AccountId) -> Balance {
+        self.pools
+            .get(pool_id)
+            .expect(ERR_NO_POOL)
+            .get_deposit(&lender_id)
+    }
+
+    pub fn get_guardian(&self) -> AccountId {
+        self.guardian.clone()
+    }
+
+    // Actions paused globally when pool_id is None, otherwise paused for the pool only
+    pub fn get_paused_actions(&self, pool_id: Option<u64>) -> Vec<ActionType> {
+        match pool_id {
+            Some(pool_id) => self.pools.get(pool_id).expect(ERR_NO_POOL).paused_actions,
+            None => self.paused_actions.clone(),
+        }
+    }
+
+    pub fn get_oracle(&self) -> AccountId {
+        self.oracle.clone()
+    }
+
+    pub fn get_dex(&self) -> Option<AccountId> {
+        self.dex.clone()
+    }
+
+    pub fn get_dex_pool(&self, token_a: ValidAccountId, token_b: ValidAccountId) -> Option<u64> {
+        self.dex_pool_ids.get(&LendingContract::dex_pool_key(
+            token_a.as_ref(),
+            token_b.as_ref(),
+        ))
+    }
+
+    pub fn get_dex_withdrawal(&self, withdrawal_id: u64) -> Option<DexWithdrawal> {
+        self.dex_withdrawals.get(&withdrawal_id)
+    }
+
+    pub fn get_delegation(
+        &self,
+        delegator: ValidAccountId,
+        delegatee: ValidAccountId,
+        pool_id: u64,
+    ) -> U128 {
+        U128::from(
+            self.delegations
+                .get(&LendingContract::delegation_key(
+                    delegator.as_ref(),
+                    delegatee.as_ref(),
+                    pool_id,
+                ))
+                .unwrap_or(0),
+        )
+    }
+
+    pub fn get_emode_categories(&self) -> Vec<(u64, EModeCategory)> {
+        self.emode_categories.to_vec()
+    }
+
+    pub fn get_account_emode(&self, account_id: ValidAccountId) -> Option<u64> {
+        self.account_emodes.get(account_id.as_ref())
+    }
+
+    pub fn get_timelock_delay(&self) -> U64 {
+        U64::from(self.timelock_delay)
+    }
+
+    pub fn get_proposal(&self, proposal_id: u64) -> Proposal {
+        self.proposals.get(&proposal_id).expect(ERR_NO_PROPOSAL)
+    }
+
+    pub fn get_proposals(&self, from_index: usize, limit: usize) -> Vec<Proposal> {
+        self.proposals
+            .values()
+            .skip(from_index)
+            .take(limit)
+            .collect()
+    }
+}
+
+impl From<LendingPool> for PoolMetadata {
+    fn from(pool: LendingPool) -> Self {
+        Self {
+            remaining_supply_capacity: pool.remaining_supply_capacity(),
+            remaining_borrow_capacity: pool.remaining_borrow_capacity(),
+            stable_rate: pool.get_stable_rate(),
+            utilization: pool.utilization(),
+            variable_borrowed: pool.amount_borrowed - pool.stable_borrowed,
+            pool_id: pool.pool_id,
+            lending_token: pool.lending_token,
+            interest_rate: pool.interest_rate,
+            pool_supply: pool.pool_supply,
+            amount_borrowed: pool.amount_borrowed,
+            total_share: pool.total_share,
+            reward_per_share: pool.reward_per_share,
+            paused_actions: pool.paused_actions,
+            collateral_factor: pool.collateral_factor,
+            symbol: pool.symbol,
+            icon: pool.icon,
+            flash_loan_fee: pool.flash_loan_fee,
+            supply_cap: pool.supply_cap,
+            borrow_cap: pool.borrow_cap,
+            min_borrow: pool.min_borrow,
+            min_deposit: pool.min_deposit,
+            dust_threshold: pool.dust_threshold,
+            stable_rate_premium: pool.stable_rate_premium,
+            rebalance_utilization: pool.rebalance_utilization,
+            stable_borrowed: pool.stable_borrowed,
+            grace_period: pool.grace_period,
+            early_repayment_penalty: pool.early_repayment_penalty,
+            debt_ceiling: pool.debt_ceiling,
+            isolated_debt: pool.isolated_debt,
+            borrowable_in_isolation: pool.borrowable_in_isolation,
+            emode_category: pool.emode_category,
+            reserve_factor: pool.reserve_factor,
+            reserve: pool.reserve,
+            bad_debt: pool.bad_debt,
+            total_staked: pool.total_staked,
+            safety_module_factor: pool.safety_module_factor,
+            max_slash_rate: pool.max_slash_rate,
+            stake_cooldown: pool.stake_cooldown,
+            uncovered_bad_debt: pool.uncovered_bad_debt,
+            share_price: pool.share_price,
+            referral_factor: pool.referral_factor,
+            origination_fee: pool.origination_fee,
+            liquidation_fee: pool.liquidation_fee,
+            treasury: pool.treasury,
+        }
+    }
 }